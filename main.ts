@@ -1,5 +1,7 @@
 import { Editor, MarkdownView, Menu, Notice, Plugin } from 'obsidian';
 import { semanticSearchSettings, SemanticSearchSettingTab } from 'src/settings/settings';
+import { describeError } from 'src/ui/errors';
+import { ProgressNotice } from 'src/ui/progress';
 import { GenerateEmbeddingsModal } from 'src/ui/generateEmbeddingsModal';
 import { LinkSuggest } from 'src/ui/linkSuggest';
 import { LinkSuggestQueryModal, QueryModal } from 'src/ui/queryModal';
@@ -9,19 +11,26 @@ import * as wasmbin from './pkg/obsidian_rust_plugin_bg.wasm';
 
 export default class SemanticSearch extends Plugin {
 	settings: semanticSearchSettings;
+	index: plugin.EmbeddingIndex;
+	liveIndexer: plugin.LiveIndexer | undefined;
 
 	async onload() {
 		await this.loadSettings();
 
+		// here's the Rust bit
+		await plugin.default(Promise.resolve(wasmbin.default));
+		plugin.onload(this);
+		this.index = new plugin.EmbeddingIndex(this.app, this.settings);
+
 		this.addRibbonIcon('file-search-2', 'Semantic Search', (_: MouseEvent) => {
-      new QueryModal(this.app, this.settings).open();
+      new QueryModal(this.app, this.settings, this.index).open();
 		});
 
 		this.addCommand({
 			id: 'open-query-modal',
 			name: 'Open query modal',
 			callback: () => {
-				new QueryModal(this.app, this.settings).open();
+				new QueryModal(this.app, this.settings, this.index).open();
 			}
 		});
 
@@ -29,34 +38,87 @@ export default class SemanticSearch extends Plugin {
 			id: 'open-link-suggest-query-modal',
 			name: 'Recommend links using current selection',
 			editorCallback: (editor: Editor, view: MarkdownView) => {
-				new LinkSuggestQueryModal(this.app, this.settings, editor).open();
+				new LinkSuggestQueryModal(this.app, this.settings, this.index, editor).open();
 			}
 		});
 
 		this.addCommand({
-			id: 'generate-input',
-			name: 'Generate Input',
-			callback: () => {
+			id: 'export-input',
+			name: 'Export Input (debug)',
+			callback: async () => {
+        const progress = new ProgressNotice("Exporting input");
         try {
-			
-          new plugin.GenerateInputCommand(this.app, this.settings).callback();
+          await new plugin.ExportInputCommand(this.app, this.settings).callback(progress.update, progress.signal);
         } catch (error) {
-          new Notice("Failed to generate input");
+          new Notice(`Failed to export input. Error: ${describeError(error)}`);
           console.error(error);
+        } finally {
+          progress.hide();
         }
 			}
 		});
 
+		this.addCommand({
+			id: 'show-embedding-cache-stats',
+			name: 'Show embedding cache statistics',
+			callback: async () => {
+				try {
+					const { entries, megabytes, hits, misses, hit_rate } = await new plugin.EmbeddingCacheCommand(this.app, this.settings).stats();
+					new Notice(`${entries} cached embeddings (${megabytes.toFixed(1)} MB), ${Math.round(hit_rate * 100)}% of ${hits + misses} lookups hit the cache`);
+				} catch (error) {
+					new Notice(`Failed to read the embedding cache. Error: ${describeError(error)}`);
+					console.error(error);
+				}
+			}
+		});
+
+		this.addCommand({
+			id: 'clear-embedding-cache',
+			name: 'Clear embedding cache',
+			callback: async () => {
+				try {
+					await new plugin.EmbeddingCacheCommand(this.app, this.settings).clear();
+					new Notice("Cleared the embedding cache");
+				} catch (error) {
+					new Notice(`Failed to clear the embedding cache. Error: ${describeError(error)}`);
+					console.error(error);
+				}
+			}
+		});
+
+		this.addCommand({
+			id: 'toggle-live-indexing-pause',
+			name: 'Pause or resume live indexing',
+			checkCallback: (checking: boolean) => {
+				if (!this.liveIndexer) {
+					return false;
+				}
+				if (!checking) {
+					if (this.liveIndexer.is_paused()) {
+						this.liveIndexer.resume();
+						new Notice(`Resumed live indexing, ${this.liveIndexer.queue_length()} note(s) queued`);
+					} else {
+						this.liveIndexer.pause();
+						new Notice("Paused live indexing, changes will be queued until resumed");
+					}
+				}
+				return true;
+			}
+		});
+
+		// Vault create events fire for every file while the vault loads
+		this.app.workspace.onLayoutReady(() => this.setLiveIndexing(this.settings.liveIndexing));
+
 		this.addCommand({
 			id: 'generate-embeddings-modal',
 			name: 'Generate Embeddings',
 			callback: () => {
-				new GenerateEmbeddingsModal(this.app, this.settings).open();
+				new GenerateEmbeddingsModal(this.app, this.settings, this.index).open();
 			}
 		});
 
     if (this.settings.enableLinkRecommendationSuggestor) {
-      const linksSuggest = new LinkSuggest(this.app, this.settings);
+      const linksSuggest = new LinkSuggest(this.app, this.settings, this.index);
       this.registerEditorSuggest(linksSuggest);
     }
 
@@ -74,14 +136,20 @@ export default class SemanticSearch extends Plugin {
     );
 
 		this.addSettingTab(new SemanticSearchSettingTab(this.app, this));
-
-		// here's the Rust bit
-		await plugin.default(Promise.resolve(wasmbin.default));
-		plugin.onload(this);
 	}
 
 	onunload() {
+		this.setLiveIndexing(false);
+		this.index?.free();
+	}
 
+	setLiveIndexing(enabled: boolean) {
+		if (enabled && !this.liveIndexer) {
+			this.liveIndexer = new plugin.LiveIndexer(this.app, this.settings);
+		} else if (!enabled && this.liveIndexer) {
+			this.liveIndexer.free();
+			this.liveIndexer = undefined;
+		}
 	}
 
 	async loadSettings() {
@@ -92,9 +160,27 @@ export default class SemanticSearch extends Plugin {
       debugMode: false,
       ignoredFolders: "",
       apiResponseType: 'Ollama',
+      customRequestTemplate: '{"model": "{{model}}", "input": "{{input}}"}',
+      customResponsePath: 'embeddings',
+      customBatchSize: 96,
+      teiTruncate: true,
+      teiNormalize: true,
+      azureApiVersion: '2024-02-01',
+      embeddingDimensions: 0,
+      maxRetries: 5,
+      requestsPerMinute: 0,
+      tokensPerMinute: 0,
+      concurrentRequests: 1,
+      embeddingCacheSize: 100,
+      documentPrefix: '',
+      queryPrefix: '',
       sectionDelimeterRegex: '.',
-      numBatches: 1,
       maxTokenLength: 8191,
+      quantization: 'None',
+      quantizationRescore: false,
+      hnswEfSearch: 64,
+      liveIndexing: false,
+      liveIndexingDelay: 2000,
       enableLinkRecommendationSuggestor: false
     }
 
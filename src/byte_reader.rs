@@ -0,0 +1,103 @@
+use std::convert::TryInto;
+
+use anyhow::{anyhow, Result};
+
+/// Reads the little-endian binary files the plugin writes, failing instead of
+/// panicking when one is truncated
+pub struct ByteReader<'a> {
+    bytes: &'a [u8],
+    offset: usize,
+    /// The file being read, for error messages, e.g. `Embedding index`
+    what: &'static str,
+}
+
+impl<'a> ByteReader<'a> {
+    pub fn new(bytes: &'a [u8], what: &'static str) -> Self {
+        Self {
+            bytes,
+            offset: 0,
+            what,
+        }
+    }
+
+    pub fn take(&mut self, n: usize) -> Result<&'a [u8]> {
+        let end = self
+            .offset
+            .checked_add(n)
+            .filter(|end| *end <= self.bytes.len())
+            .ok_or_else(|| anyhow!("{} is truncated at byte {}", self.what, self.offset))?;
+        let slice = &self.bytes[self.offset..end];
+        self.offset = end;
+        Ok(slice)
+    }
+
+    pub fn read_u32(&mut self) -> Result<u32> {
+        let bytes = self.take(4)?;
+        Ok(u32::from_le_bytes(
+            bytes.try_into().expect("slice is 4 bytes"),
+        ))
+    }
+
+    pub fn read_u64(&mut self) -> Result<u64> {
+        let bytes = self.take(8)?;
+        Ok(u64::from_le_bytes(
+            bytes.try_into().expect("slice is 8 bytes"),
+        ))
+    }
+
+    /// A block prefixed with its length as a u32
+    pub fn read_block(&mut self) -> Result<&'a [u8]> {
+        let len = self.read_u32()? as usize;
+        self.take(len)
+    }
+
+    /// Fails unless every byte has been read
+    pub fn finish(&self) -> Result<()> {
+        if self.offset != self.bytes.len() {
+            return Err(anyhow!(
+                "{} has {} trailing bytes",
+                self.what,
+                self.bytes.len() - self.offset
+            ));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_values_and_blocks() {
+        let mut bytes = 7u32.to_le_bytes().to_vec();
+        bytes.extend_from_slice(&2u32.to_le_bytes());
+        bytes.extend_from_slice(b"ok");
+        bytes.extend_from_slice(&u64::MAX.to_le_bytes());
+        let mut reader = ByteReader::new(&bytes, "Test file");
+
+        assert_eq!(reader.read_u32().unwrap(), 7);
+        assert_eq!(reader.read_block().unwrap(), b"ok");
+        assert_eq!(reader.read_u64().unwrap(), u64::MAX);
+        assert!(reader.finish().is_ok());
+    }
+
+    #[test]
+    fn rejects_truncated_and_trailing_bytes() {
+        let bytes = [1, 0, 0];
+        let mut reader = ByteReader::new(&bytes, "Test file");
+
+        assert_eq!(
+            reader.read_u32().unwrap_err().to_string(),
+            "Test file is truncated at byte 0"
+        );
+        assert_eq!(
+            reader.take(usize::MAX).unwrap_err().to_string(),
+            "Test file is truncated at byte 0"
+        );
+        assert_eq!(
+            reader.finish().unwrap_err().to_string(),
+            "Test file has 3 trailing bytes"
+        );
+    }
+}
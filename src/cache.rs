@@ -0,0 +1,416 @@
+use std::collections::BTreeSet;
+use std::collections::HashMap;
+use std::convert::TryInto;
+
+use anyhow::{anyhow, Context, Result};
+use serde::Deserialize;
+use serde::Serialize;
+use wasm_bindgen::prelude::*;
+
+use crate::byte_reader::ByteReader;
+use crate::error::SemanticSearchError;
+use crate::file_processor::FileProcessor;
+use crate::obsidian::semanticSearchSettings;
+use crate::obsidian::App;
+
+/// Identifies an embedding cache file written by this plugin
+const MAGIC: &[u8; 4] = b"SSEC";
+const FORMAT_VERSION: u32 = 1;
+const BYTES_PER_MEGABYTE: usize = 1024 * 1024;
+
+/// Embeddings of previously embedded text, so that text embedded again, for
+/// example after the sections of the vault were split differently, does not
+/// have to be sent to the API. Keys identify the text along with everything
+/// that changes its embedding, see `Client::cache_key`. Once the cache holds
+/// more than `max_bytes` the least recently used embeddings are evicted.
+///
+/// On disk the layout is:
+///
+/// | bytes | content                                      |
+/// |-------|----------------------------------------------|
+/// | 4     | magic `SSEC`                                 |
+/// | 4     | format version (u32 LE)                      |
+/// | 4 + n | metadata length (u32 LE), JSON metadata      |
+/// | 4 * k | f32 LE embeddings, in the order of metadata  |
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct EmbeddingCache {
+    entries: HashMap<String, CacheEntry>,
+    /// `(last_used, key)` of every entry, least recently used first
+    recency: BTreeSet<(u64, String)>,
+    /// 0 disables the cache
+    max_bytes: usize,
+    bytes: usize,
+    /// Incremented on every use, orders entries from least to most recently
+    /// used
+    clock: u64,
+    hits: u64,
+    misses: u64,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct CacheEntry {
+    embedding: Vec<f32>,
+    last_used: u64,
+}
+
+#[derive(Serialize, Deserialize)]
+struct CacheMetadata {
+    clock: u64,
+    hits: u64,
+    misses: u64,
+    entries: Vec<EntryMetadata>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct EntryMetadata {
+    key: String,
+    dimension: usize,
+    last_used: u64,
+}
+
+/// Memory an entry is accounted for
+fn entry_bytes(key: &str, embedding: &[f32]) -> usize {
+    key.len() + embedding.len() * 4
+}
+
+impl EmbeddingCache {
+    pub fn new(max_megabytes: u32) -> Self {
+        Self {
+            max_bytes: max_megabytes as usize * BYTES_PER_MEGABYTE,
+            ..Default::default()
+        }
+    }
+
+    pub fn max_megabytes(&self) -> u32 {
+        (self.max_bytes / BYTES_PER_MEGABYTE) as u32
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.max_bytes > 0
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn bytes(&self) -> usize {
+        self.bytes
+    }
+
+    pub fn hits(&self) -> u64 {
+        self.hits
+    }
+
+    pub fn misses(&self) -> u64 {
+        self.misses
+    }
+
+    /// Share of lookups that found an embedding, 0 before the first lookup
+    pub fn hit_rate(&self) -> f64 {
+        match self.hits + self.misses {
+            0 => 0.0,
+            lookups => self.hits as f64 / lookups as f64,
+        }
+    }
+
+    /// The embedding cached under `key`, counting the lookup towards the hit
+    /// rate
+    pub fn get(&mut self, key: &str) -> Option<Vec<f32>> {
+        if !self.is_enabled() {
+            return None;
+        }
+        self.clock += 1;
+        match self.entries.get_mut(key) {
+            Some(entry) => {
+                self.recency.remove(&(entry.last_used, key.to_string()));
+                self.recency.insert((self.clock, key.to_string()));
+                entry.last_used = self.clock;
+                self.hits += 1;
+                Some(entry.embedding.clone())
+            }
+            None => {
+                self.misses += 1;
+                None
+            }
+        }
+    }
+
+    pub fn insert(&mut self, key: String, embedding: Vec<f32>) {
+        if !self.is_enabled() {
+            return;
+        }
+        self.clock += 1;
+        self.bytes += entry_bytes(&key, &embedding);
+        let entry = CacheEntry {
+            embedding,
+            last_used: self.clock,
+        };
+        if let Some(previous) = self.entries.insert(key.clone(), entry) {
+            self.bytes -= entry_bytes(&key, &previous.embedding);
+            self.recency.remove(&(previous.last_used, key.clone()));
+        }
+        self.recency.insert((self.clock, key));
+        self.evict();
+    }
+
+    /// Removes every embedding and resets the hit rate
+    pub fn clear(&mut self) {
+        *self = Self {
+            max_bytes: self.max_bytes,
+            ..Default::default()
+        };
+    }
+
+    /// Evicts the least recently used entries until the cache fits in
+    /// `max_bytes`
+    fn evict(&mut self) {
+        while self.bytes > self.max_bytes {
+            let (_, key) = match self.recency.pop_first() {
+                Some(oldest) => oldest,
+                None => break,
+            };
+            if let Some(entry) = self.entries.remove(&key) {
+                self.bytes -= entry_bytes(&key, &entry.embedding);
+            }
+        }
+    }
+
+    pub fn to_bytes(&self) -> Result<Vec<u8>> {
+        let entries: Vec<(&String, &CacheEntry)> = self
+            .recency
+            .iter()
+            .filter_map(|(_, key)| self.entries.get_key_value(key))
+            .collect();
+        let metadata = CacheMetadata {
+            clock: self.clock,
+            hits: self.hits,
+            misses: self.misses,
+            entries: entries
+                .iter()
+                .map(|(key, entry)| EntryMetadata {
+                    key: key.to_string(),
+                    dimension: entry.embedding.len(),
+                    last_used: entry.last_used,
+                })
+                .collect(),
+        };
+        let metadata =
+            serde_json::to_vec(&metadata).context("Failed to serialize embedding cache")?;
+        let len: u32 = metadata
+            .len()
+            .try_into()
+            .context("Embedding cache metadata is too large")?;
+
+        let mut bytes = Vec::with_capacity(12 + metadata.len() + self.bytes);
+        bytes.extend_from_slice(MAGIC);
+        bytes.extend_from_slice(&FORMAT_VERSION.to_le_bytes());
+        bytes.extend_from_slice(&len.to_le_bytes());
+        bytes.extend_from_slice(&metadata);
+        for (_, entry) in entries {
+            entry
+                .embedding
+                .iter()
+                .for_each(|f| bytes.extend_from_slice(&f.to_le_bytes()));
+        }
+        Ok(bytes)
+    }
+
+    /// Reads a cache written by `to_bytes`, evicting entries over a
+    /// `max_megabytes` limit lowered since
+    pub fn from_bytes(bytes: &[u8], max_megabytes: u32) -> Result<Self> {
+        let mut reader = ByteReader::new(bytes, "Embedding cache");
+        if reader.take(MAGIC.len())? != MAGIC {
+            return Err(anyhow!("Not an embedding cache file"));
+        }
+        let version = reader.read_u32()?;
+        if version != FORMAT_VERSION {
+            return Err(anyhow!(
+                "Unsupported embedding cache format version {}",
+                version
+            ));
+        }
+        let metadata: CacheMetadata = serde_json::from_slice(reader.read_block()?)
+            .context("Failed to deserialize embedding cache")?;
+
+        let mut cache = Self::new(max_megabytes);
+        cache.clock = metadata.clock;
+        cache.hits = metadata.hits;
+        cache.misses = metadata.misses;
+        for entry in metadata.entries {
+            let data = reader.take(entry.dimension.saturating_mul(4))?;
+            let embedding: Vec<f32> = data
+                .chunks_exact(4)
+                .map(|b| f32::from_le_bytes(b.try_into().expect("chunk is 4 bytes")))
+                .collect();
+            cache.bytes += entry_bytes(&entry.key, &embedding);
+            let loaded = CacheEntry {
+                embedding,
+                last_used: entry.last_used,
+            };
+            // only a corrupt file repeats a key
+            if let Some(previous) = cache.entries.insert(entry.key.clone(), loaded) {
+                cache.bytes -= entry_bytes(&entry.key, &previous.embedding);
+                cache.recency.remove(&(previous.last_used, entry.key.clone()));
+            }
+            cache.recency.insert((entry.last_used, entry.key));
+        }
+        reader.finish()?;
+        cache.evict();
+        Ok(cache)
+    }
+}
+
+#[wasm_bindgen]
+pub struct EmbeddingCacheCommand {
+    file_processor: FileProcessor,
+    max_megabytes: u32,
+}
+
+#[wasm_bindgen]
+pub struct CacheStatsResponse {
+    pub entries: u32,
+    pub megabytes: f64,
+    pub hits: f64,
+    pub misses: f64,
+    /// Share of lookups since the cache was last cleared that found an
+    /// embedding
+    pub hit_rate: f64,
+}
+
+#[wasm_bindgen]
+impl EmbeddingCacheCommand {
+    #[wasm_bindgen(constructor)]
+    pub fn new(app: App, settings: &semanticSearchSettings) -> EmbeddingCacheCommand {
+        EmbeddingCacheCommand {
+            file_processor: FileProcessor::new(app.vault()),
+            max_megabytes: settings.embeddingCacheSize(),
+        }
+    }
+
+    pub async fn stats(&self) -> Result<CacheStatsResponse, SemanticSearchError> {
+        let cache = self.file_processor.read_cache(self.max_megabytes).await?;
+        Ok(CacheStatsResponse {
+            entries: cache.len().try_into().expect("Too many cached embeddings"),
+            megabytes: cache.bytes() as f64 / BYTES_PER_MEGABYTE as f64,
+            hits: cache.hits() as f64,
+            misses: cache.misses() as f64,
+            hit_rate: cache.hit_rate(),
+        })
+    }
+
+    pub async fn clear(&self) -> Result<(), SemanticSearchError> {
+        self.file_processor.delete_cache().await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A cache holding `n` three dimensional embeddings under two character
+    /// keys
+    fn cache_for(n: usize) -> EmbeddingCache {
+        EmbeddingCache {
+            max_bytes: n * entry_bytes("k0", &[0.0; 3]),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn counts_hits_and_misses() {
+        let mut cache = cache_for(10);
+        cache.insert("k0".to_string(), vec![1.0, 2.0, 3.0]);
+
+        assert_eq!(cache.get("k0"), Some(vec![1.0, 2.0, 3.0]));
+        assert_eq!(cache.get("k1"), None);
+        assert_eq!(cache.get("k0"), Some(vec![1.0, 2.0, 3.0]));
+
+        assert_eq!((cache.hits(), cache.misses()), (2, 1));
+        assert!((cache.hit_rate() - 2.0 / 3.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn evicts_least_recently_used() {
+        let mut cache = cache_for(2);
+        cache.insert("k0".to_string(), vec![0.0; 3]);
+        cache.insert("k1".to_string(), vec![1.0; 3]);
+        cache.get("k0");
+        cache.insert("k2".to_string(), vec![2.0; 3]);
+
+        assert_eq!(cache.len(), 2);
+        assert!(cache.get("k1").is_none());
+        assert!(cache.get("k0").is_some());
+        assert!(cache.get("k2").is_some());
+        assert!(cache.bytes() <= cache.max_bytes);
+    }
+
+    #[test]
+    fn keeps_recency_in_step_with_entries() {
+        let mut cache = cache_for(3);
+        for i in 0..100 {
+            cache.insert(format!("k{}", i % 7), vec![i as f32; 3]);
+            cache.get(&format!("k{}", i % 5));
+        }
+
+        assert_eq!(cache.len(), 3);
+        assert_eq!(cache.recency.len(), cache.len());
+        assert!(cache
+            .recency
+            .iter()
+            .all(|(last_used, key)| cache.entries[key].last_used == *last_used));
+    }
+
+    #[test]
+    fn replaces_entry() {
+        let mut cache = cache_for(2);
+        cache.insert("k0".to_string(), vec![0.0; 3]);
+        cache.insert("k0".to_string(), vec![1.0; 3]);
+
+        assert_eq!(cache.len(), 1);
+        assert_eq!(cache.bytes(), entry_bytes("k0", &[0.0; 3]));
+        assert_eq!(cache.get("k0"), Some(vec![1.0; 3]));
+    }
+
+    #[test]
+    fn disabled_cache_keeps_nothing() {
+        let mut cache = EmbeddingCache::new(0);
+        cache.insert("k0".to_string(), vec![1.0]);
+
+        assert_eq!(cache.get("k0"), None);
+        assert_eq!((cache.len(), cache.hits(), cache.misses()), (0, 0, 0));
+    }
+
+    #[test]
+    fn roundtrip() {
+        let mut cache = EmbeddingCache::new(1);
+        cache.insert("a".to_string(), vec![0.5, -1.25]);
+        cache.insert("b".to_string(), vec![3.0, 0.0, 1.0]);
+        cache.get("a");
+        cache.get("c");
+
+        let decoded = EmbeddingCache::from_bytes(&cache.to_bytes().unwrap(), 1).unwrap();
+
+        assert_eq!(decoded, cache);
+    }
+
+    #[test]
+    fn evicts_to_a_lowered_limit_on_load() {
+        let mut cache = EmbeddingCache::new(1);
+        cache.insert("a".to_string(), vec![0.0; 10]);
+        cache.insert("b".to_string(), vec![0.0; 10]);
+
+        let decoded = EmbeddingCache::from_bytes(&cache.to_bytes().unwrap(), 0).unwrap();
+
+        assert_eq!(decoded.len(), 0);
+    }
+
+    #[test]
+    fn rejects_truncated_file() {
+        let mut cache = EmbeddingCache::new(1);
+        cache.insert("a".to_string(), vec![0.5, -1.25]);
+        let bytes = cache.to_bytes().unwrap();
+
+        assert!(EmbeddingCache::from_bytes(&bytes[..bytes.len() - 1], 1).is_err());
+    }
+}
@@ -0,0 +1,160 @@
+use std::iter::Enumerate;
+use std::vec::IntoIter;
+
+use futures_util::future::LocalBoxFuture;
+use futures_util::stream::FuturesUnordered;
+use futures_util::StreamExt;
+
+/// Runs fallible tasks with at most `limit` of them in flight, yielding each
+/// result with the index of its task as it completes. Once a task has failed,
+/// or `stop` was called, no further tasks are started, but those already in
+/// flight are still yielded so that their work is not lost.
+pub struct BoundedTasks<'a, T, E> {
+    pending: Enumerate<IntoIter<LocalBoxFuture<'a, Result<T, E>>>>,
+    in_flight: FuturesUnordered<LocalBoxFuture<'a, (usize, Result<T, E>)>>,
+    limit: usize,
+    stopped: bool,
+}
+
+impl<'a, T: 'a, E: 'a> BoundedTasks<'a, T, E> {
+    /// Tasks are only polled, and so started, once there is room for them
+    pub fn new(tasks: Vec<LocalBoxFuture<'a, Result<T, E>>>, limit: usize) -> Self {
+        Self {
+            pending: tasks.into_iter().enumerate(),
+            in_flight: FuturesUnordered::new(),
+            limit: limit.max(1),
+            stopped: false,
+        }
+    }
+
+    /// Starts no further tasks
+    pub fn stop(&mut self) {
+        self.stopped = true;
+    }
+
+    /// The next result to complete, or `None` once no task is left to run
+    pub async fn next(&mut self) -> Option<(usize, Result<T, E>)> {
+        while !self.stopped && self.in_flight.len() < self.limit {
+            match self.pending.next() {
+                Some((i, task)) => self
+                    .in_flight
+                    .push(Box::pin(async move { (i, task.await) })),
+                None => break,
+            }
+        }
+        let (i, result) = self.in_flight.next().await?;
+        self.stopped |= result.is_err();
+        Some((i, result))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+
+    use futures_util::FutureExt;
+
+    use super::*;
+
+    /// A task that stays in flight for `yields` polls, tracking how many tasks
+    /// run at once
+    async fn task(
+        i: usize,
+        yields: usize,
+        running: &Cell<usize>,
+        max_running: &Cell<usize>,
+        started: &Cell<usize>,
+    ) -> Result<usize, String> {
+        started.set(started.get() + 1);
+        running.set(running.get() + 1);
+        max_running.set(max_running.get().max(running.get()));
+        for _ in 0..yields {
+            tokio::task::yield_now().await;
+        }
+        running.set(running.get() - 1);
+        if i == 3 {
+            Err(format!("task {} failed", i))
+        } else {
+            Ok(i)
+        }
+    }
+
+    #[tokio::test]
+    async fn bounds_tasks_in_flight() {
+        let (running, max_running, started) = (Cell::new(0), Cell::new(0), Cell::new(0));
+        let tasks = [5, 1, 1]
+            .iter()
+            .enumerate()
+            .map(|(i, yields)| task(i, *yields, &running, &max_running, &started).boxed_local())
+            .collect();
+        let mut bounded = BoundedTasks::new(tasks, 2);
+
+        let mut completed = Vec::new();
+        while let Some((i, result)) = bounded.next().await {
+            assert_eq!(result.unwrap(), i);
+            completed.push(i);
+        }
+
+        assert_eq!(max_running.get(), 2);
+        assert_eq!(completed, vec![1, 2, 0]);
+    }
+
+    #[tokio::test]
+    async fn stops_starting_tasks_after_failure() {
+        let (running, max_running, started) = (Cell::new(0), Cell::new(0), Cell::new(0));
+        let tasks = (0..8)
+            .map(|i| {
+                let yields = if i == 3 { 0 } else { 2 };
+                task(i, yields, &running, &max_running, &started).boxed_local()
+            })
+            .collect();
+        let mut bounded = BoundedTasks::new(tasks, 2);
+
+        let mut results = Vec::new();
+        while let Some((i, result)) = bounded.next().await {
+            results.push((i, result.is_ok()));
+        }
+
+        assert!(results.contains(&(3, false)));
+        assert_eq!(started.get(), results.len());
+        assert!(started.get() < 8);
+        // the task in flight alongside the failed one still completes
+        assert!(results.iter().filter(|(_, ok)| *ok).count() >= 3);
+    }
+
+    #[tokio::test]
+    async fn runs_sequentially_with_limit_of_one() {
+        let (running, max_running, started) = (Cell::new(0), Cell::new(0), Cell::new(0));
+        let tasks = (0..3)
+            .map(|i| task(i, 3 - i, &running, &max_running, &started).boxed_local())
+            .collect();
+        let mut bounded = BoundedTasks::new(tasks, 0);
+
+        let mut completed = Vec::new();
+        while let Some((i, _)) = bounded.next().await {
+            completed.push(i);
+        }
+
+        assert_eq!(max_running.get(), 1);
+        assert_eq!(completed, vec![0, 1, 2]);
+    }
+
+    #[tokio::test]
+    async fn finishes_tasks_in_flight_when_stopped() {
+        let (running, max_running, started) = (Cell::new(0), Cell::new(0), Cell::new(0));
+        let tasks = (0..6)
+            .map(|i| task(i, 1, &running, &max_running, &started).boxed_local())
+            .collect();
+        let mut bounded = BoundedTasks::new(tasks, 2);
+
+        let mut completed = vec![bounded.next().await.unwrap().0];
+        bounded.stop();
+        while let Some((i, _)) = bounded.next().await {
+            completed.push(i);
+        }
+        completed.sort_unstable();
+
+        assert_eq!(completed, vec![0, 1]);
+        assert_eq!(started.get(), 2);
+    }
+}
@@ -1,5 +1,5 @@
 use derive_builder::Builder;
-use serde::{Deserialize, Serialize};
+use serde::Serialize;
 
 use crate::file_processor::InputRow;
 
@@ -35,19 +35,70 @@ impl From<&[String]> for EmbeddingInput {
 
 impl From<&[InputRow]> for EmbeddingInput {
     fn from(value: &[InputRow]) -> Self {
-        EmbeddingInput::StringArray(value.to_vec().into_iter().map(|row| row.body).collect())
+        EmbeddingInput::StringArray(value.iter().map(|row| row.body.to_string()).collect())
     }
 }
 
 impl From<&Vec<InputRow>> for EmbeddingInput {
     fn from(value: &Vec<InputRow>) -> Self {
-        EmbeddingInput::StringArray(value.into_iter().map(|row| row.body.to_string()).collect())
+        EmbeddingInput::StringArray(value.iter().map(|row| row.body.to_string()).collect())
     }
 }
 
 impl From<&mut Vec<InputRow>> for EmbeddingInput {
     fn from(value: &mut Vec<InputRow>) -> Self {
-        EmbeddingInput::StringArray(value.into_iter().map(|row| row.body.to_string()).collect())
+        EmbeddingInput::StringArray(value.iter().map(|row| row.body.to_string()).collect())
+    }
+}
+
+/// What embeddings are used for. Asymmetric models embed the documents being
+/// searched and the queries searching them differently.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EmbeddingPurpose {
+    Document,
+    Query,
+}
+
+/// Replaced by the text in a prefix template
+const TEXT_PLACEHOLDER: &str = "{{text}}";
+
+/// Instructions some models expect in front of the text, such as
+/// `search_document: ` and `search_query: ` for Nomic or `passage: ` and
+/// `query: ` for E5. A template containing `{{text}}` has the text
+/// substituted in, any other template is prepended to it.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PrefixTemplates {
+    pub document: String,
+    pub query: String,
+}
+
+impl PrefixTemplates {
+    pub fn template(&self, purpose: EmbeddingPurpose) -> &str {
+        match purpose {
+            EmbeddingPurpose::Document => &self.document,
+            EmbeddingPurpose::Query => &self.query,
+        }
+    }
+
+    pub fn apply(&self, input: EmbeddingInput, purpose: EmbeddingPurpose) -> EmbeddingInput {
+        let template = self.template(purpose);
+        if template.is_empty() {
+            return input;
+        }
+        let EmbeddingInput::StringArray(texts) = input;
+        texts
+            .iter()
+            .map(|text| apply_template(template, text))
+            .collect::<Vec<String>>()
+            .into()
+    }
+}
+
+fn apply_template(template: &str, text: &str) -> String {
+    if template.contains(TEXT_PLACEHOLDER) {
+        template.replace(TEXT_PLACEHOLDER, text)
+    } else {
+        format!("{}{}", template, text)
     }
 }
 
@@ -63,48 +114,49 @@ pub struct EmbeddingRequest {
     pub input: EmbeddingInput,
 }
 
-#[derive(Debug, Clone)]
-pub enum SupportedAPIs {
-    Ollama,
-    OpenAI,
-}
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-impl From<std::string::String> for SupportedAPIs {
-    fn from(value: std::string::String) -> Self {
-        match value.as_str() {
-            "Ollama" => Self::Ollama,
-            "OpenAI" => Self::OpenAI,
-            _ => todo!(),
+    fn texts(input: EmbeddingInput) -> Vec<String> {
+        let EmbeddingInput::StringArray(texts) = input;
+        texts
+    }
+
+    fn prefixes() -> PrefixTemplates {
+        PrefixTemplates {
+            document: "search_document: ".to_string(),
+            query: "Instruct: Find related notes\nQuery: {{text}}".to_string(),
         }
     }
-}
 
-#[derive(Debug, Deserialize, Clone)]
-pub struct OpenAIEmbeddingResponse {
-    pub data: Vec<Embedding>,
-}
+    #[test]
+    fn prepends_prefix() {
+        let input = vec!["a".to_string(), "b".to_string()].into();
 
-#[derive(Debug, Deserialize, Clone)]
-pub struct OllamaEmbeddingResponse {
-    pub embeddings: Vec<Vec<f32>>,
-}
+        assert_eq!(
+            texts(prefixes().apply(input, EmbeddingPurpose::Document)),
+            ["search_document: a", "search_document: b"]
+        );
+    }
 
-#[derive(Debug, Deserialize, Clone)]
-pub struct Embedding {
-    pub embedding: Vec<f32>,
-}
+    #[test]
+    fn substitutes_text() {
+        let input = "cats".to_string().into();
 
-impl From<&mut OpenAIEmbeddingResponse> for Vec<Vec<f32>> {
-    fn from(value: &mut OpenAIEmbeddingResponse) -> Self {
-        std::mem::take(&mut value.data)
-            .iter_mut()
-            .map(|x| std::mem::take(&mut x.embedding))
-            .collect()
+        assert_eq!(
+            texts(prefixes().apply(input, EmbeddingPurpose::Query)),
+            ["Instruct: Find related notes\nQuery: cats"]
+        );
     }
-}
 
-impl From<&mut OllamaEmbeddingResponse> for Vec<Vec<f32>> {
-    fn from(value: &mut OllamaEmbeddingResponse) -> Self {
-        std::mem::take(&mut value.embeddings)
+    #[test]
+    fn empty_template_keeps_text() {
+        let input = "cats".to_string().into();
+
+        assert_eq!(
+            texts(PrefixTemplates::default().apply(input, EmbeddingPurpose::Query)),
+            ["cats"]
+        );
     }
 }
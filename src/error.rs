@@ -10,54 +10,362 @@ pub(crate) struct WrappedError {
 
 impl std::fmt::Display for WrappedError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{:?}", self.error)
+        write!(f, "{}", self.error)
     }
 }
 
-/// OpenAI API returns error object on failure
+/// Error object of the OpenAI API, also returned by many compatible APIs
 #[derive(Debug, Deserialize)]
 pub struct ApiError {
     pub message: String,
+    #[serde(default)]
     pub r#type: String,
     pub param: Option<serde_json::Value>,
     pub code: Option<serde_json::Value>,
 }
 
+/// The message, followed by whichever of type, code and parameter are set,
+/// e.g. `Incorrect API key provided (type: invalid_request_error, code:
+/// invalid_api_key)`
 impl std::fmt::Display for ApiError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let describe = |value: &serde_json::Value| match value {
+            serde_json::Value::String(s) => s.to_string(),
+            other => other.to_string(),
+        };
+        let details: Vec<String> = [
+            Some(self.r#type.to_string())
+                .filter(|t| !t.is_empty())
+                .map(|t| format!("type: {}", t)),
+            self.code
+                .as_ref()
+                .filter(|c| !c.is_null())
+                .map(|c| format!("code: {}", describe(c))),
+            self.param
+                .as_ref()
+                .filter(|p| !p.is_null())
+                .map(|p| format!("param: {}", describe(p))),
+        ]
+        .iter()
+        .flatten()
+        .cloned()
+        .collect();
+        if details.is_empty() {
+            write!(f, "{}", self.message)
+        } else {
+            write!(f, "{} ({})", self.message, details.join(", "))
+        }
+    }
+}
+
+/// A setting holds a value the plugin cannot work with
+#[derive(Debug)]
+pub struct ConfigurationError {
+    pub setting: &'static str,
+    pub message: String,
+}
+
+impl ConfigurationError {
+    pub fn new(setting: &'static str, message: String) -> Self {
+        Self { setting, message }
+    }
+}
+
+impl std::error::Error for ConfigurationError {}
+
+impl std::fmt::Display for ConfigurationError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Invalid setting '{}': {}", self.setting, self.message)
+    }
+}
+
+/// An embedding request answered with an error status
+#[derive(Debug)]
+pub struct HttpError {
+    pub status: u16,
+    /// How long the API asked to wait before retrying, if it did
+    pub retry_after_ms: Option<f64>,
+    /// The error decoded from the response body
+    pub source: anyhow::Error,
+}
+
+impl std::error::Error for HttpError {}
+
+impl std::fmt::Display for HttpError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.source)
+    }
+}
+
+/// An embedding request kept failing transiently until the retry budget was
+/// spent
+#[derive(Debug)]
+pub struct RetriesExhausted {
+    pub attempts: u32,
+    /// The error of the last attempt
+    pub source: anyhow::Error,
+}
+
+impl std::error::Error for RetriesExhausted {}
+
+impl std::fmt::Display for RetriesExhausted {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(
             f,
-            "Call to OpenAI failed with code: {:?}, message: {}, type: {}, param: {:?}",
-            self.code, self.message, self.r#type, self.param
+            "Gave up after {} attempts: {}",
+            self.attempts, self.source
         )
     }
 }
 
+/// What went wrong, as far as the user is concerned. Each kind has a stable
+/// code the UI can tell errors apart by, and most suggest how to fix them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorKind {
+    /// A setting holds a value the plugin cannot work with
+    Configuration,
+    /// The API rejected the API key
+    Auth,
+    /// The API kept rate limiting requests
+    RateLimited,
+    /// The API answered with an error or a response that could not be read
+    Provider,
+    /// The API could not be reached
+    Network,
+    /// input.csv has not been exported
+    InputMissing,
+    /// No embeddings have been generated
+    IndexMissing,
+    /// An index file could not be read
+    IndexCorrupt,
+    /// The index was embedded with other settings than the current ones
+    IndexMismatch,
+    /// Embeddings of different lengths were combined
+    DimensionMismatch,
+    /// Reading or writing a file in the vault failed
+    VaultIo,
+    /// The user stopped the command
+    Aborted,
+    Internal,
+}
+
+impl ErrorKind {
+    pub fn code(self) -> &'static str {
+        match self {
+            ErrorKind::Configuration => "configuration",
+            ErrorKind::Auth => "auth",
+            ErrorKind::RateLimited => "rate_limited",
+            ErrorKind::Provider => "provider",
+            ErrorKind::Network => "network",
+            ErrorKind::InputMissing => "input_missing",
+            ErrorKind::IndexMissing => "index_missing",
+            ErrorKind::IndexCorrupt => "index_corrupt",
+            ErrorKind::IndexMismatch => "index_mismatch",
+            ErrorKind::DimensionMismatch => "dimension_mismatch",
+            ErrorKind::VaultIo => "vault_io",
+            ErrorKind::Aborted => "aborted",
+            ErrorKind::Internal => "internal",
+        }
+    }
+
+    pub fn hint(self) -> Option<&'static str> {
+        match self {
+            ErrorKind::Configuration => Some("Correct the setting in the Semantic Search settings"),
+            ErrorKind::Auth => Some("Check that the API key is valid and has access to the model"),
+            ErrorKind::RateLimited => Some("Try again later, or lower 'Requests per minute', 'Tokens per minute' or 'Concurrent requests'"),
+            ErrorKind::Provider => Some("Check that the API URL, model and API response type match your API"),
+            ErrorKind::Network => Some("Check your connection and the API URL"),
+            ErrorKind::InputMissing => Some("Run 'Export Input' first"),
+            ErrorKind::IndexMissing => Some("Run 'Generate Embeddings' first"),
+            ErrorKind::IndexCorrupt => Some("Run 'Generate Embeddings' to rebuild the index"),
+            ErrorKind::IndexMismatch | ErrorKind::DimensionMismatch => {
+                Some("Run 'Generate Embeddings' to re-index with the current settings")
+            }
+            ErrorKind::Aborted => {
+                Some("Run 'Generate Embeddings' again to continue from the completed batches")
+            }
+            ErrorKind::VaultIo | ErrorKind::Internal => None,
+        }
+    }
+
+    /// The kind of the first cause in the chain of `error` that has one,
+    /// `Internal` if none does
+    pub(crate) fn of(error: &anyhow::Error) -> Self {
+        for cause in error.chain() {
+            if let Some(error) = cause.downcast_ref::<SemanticSearchError>() {
+                return error.kind;
+            }
+            if cause.is::<ConfigurationError>() {
+                return ErrorKind::Configuration;
+            }
+            if let Some(error) = cause.downcast_ref::<HttpError>() {
+                return match error.status {
+                    401 | 403 => ErrorKind::Auth,
+                    429 => ErrorKind::RateLimited,
+                    _ => ErrorKind::Provider,
+                };
+            }
+            if let Some(error) = cause.downcast_ref::<RetriesExhausted>() {
+                return Self::of(&error.source);
+            }
+            if cause.is::<reqwest::Error>() {
+                return ErrorKind::Network;
+            }
+        }
+        ErrorKind::Internal
+    }
+}
+
+/// An error returned to the UI. Wraps the `anyhow` chain describing it, and
+/// inside such a chain marks the kind of the errors it wraps.
 #[derive(Debug)]
-pub struct SemanticSearchError(pub anyhow::Error);
+pub struct SemanticSearchError {
+    pub kind: ErrorKind,
+    source: anyhow::Error,
+}
 
-impl std::error::Error for SemanticSearchError {}
+impl SemanticSearchError {
+    pub fn new(kind: ErrorKind, source: impl Into<anyhow::Error>) -> Self {
+        Self {
+            kind,
+            source: source.into(),
+        }
+    }
+
+    /// The message with all its causes, e.g. `Failed to read input.csv:
+    /// input.csv does not exist`
+    pub fn message(&self) -> String {
+        format!("{:#}", self.source)
+    }
+
+    /// The message followed by the hint, for notices shown from Rust
+    pub fn describe(&self) -> String {
+        match self.kind.hint() {
+            Some(hint) => format!("{}. {}.", self.message(), hint),
+            None => self.message(),
+        }
+    }
+}
+
+impl std::error::Error for SemanticSearchError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        self.source.chain().nth(1)
+    }
+}
 
 impl std::fmt::Display for SemanticSearchError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{:?}", self)
+        write!(f, "{}", self.source)
     }
 }
 
 impl From<anyhow::Error> for SemanticSearchError {
     fn from(value: anyhow::Error) -> Self {
-        SemanticSearchError(value)
+        Self::new(ErrorKind::of(&value), value)
     }
 }
 
+impl From<ConfigurationError> for SemanticSearchError {
+    fn from(value: ConfigurationError) -> Self {
+        Self::new(ErrorKind::Configuration, value)
+    }
+}
+
+/// Errors thrown by the Obsidian API, which the plugin only calls to access
+/// the vault
 impl From<wasm_bindgen::JsValue> for SemanticSearchError {
     fn from(value: wasm_bindgen::JsValue) -> Self {
-        SemanticSearchError(anyhow!("{:?}", value))
+        Self::new(ErrorKind::VaultIo, anyhow!("{:?}", value))
+    }
+}
+
+/// Thrown as an `Error` with the message, a `code` from `ErrorKind::code` and
+/// a `hint` if there is one
+impl From<SemanticSearchError> for wasm_bindgen::JsValue {
+    fn from(value: SemanticSearchError) -> Self {
+        let error = js_sys::Error::new(&value.message());
+        let set = |key: &str, field: JsValue| {
+            // setting a property of a plain Error cannot fail
+            let _ = js_sys::Reflect::set(&error, &JsValue::from_str(key), &field);
+        };
+        set("code", JsValue::from_str(value.kind.code()));
+        if let Some(hint) = value.kind.hint() {
+            set("hint", JsValue::from_str(hint));
+        }
+        error.into()
     }
 }
 
-impl Into<wasm_bindgen::JsValue> for SemanticSearchError {
-    fn into(self) -> wasm_bindgen::JsValue {
-        JsValue::from_str(&format!("{:?}", self))
+#[cfg(test)]
+mod tests {
+    use anyhow::Context;
+
+    use super::*;
+
+    fn http_error(status: u16) -> anyhow::Error {
+        HttpError {
+            status,
+            retry_after_ms: None,
+            source: anyhow!("status {}", status),
+        }
+        .into()
+    }
+
+    #[test]
+    fn classifies_http_errors() {
+        assert_eq!(ErrorKind::of(&http_error(401)), ErrorKind::Auth);
+        assert_eq!(ErrorKind::of(&http_error(403)), ErrorKind::Auth);
+        assert_eq!(ErrorKind::of(&http_error(429)), ErrorKind::RateLimited);
+        assert_eq!(ErrorKind::of(&http_error(400)), ErrorKind::Provider);
+        assert_eq!(ErrorKind::of(&anyhow!("unexpected")), ErrorKind::Internal);
+    }
+
+    #[test]
+    fn classifies_exhausted_retries_by_last_error() {
+        let error: anyhow::Error = RetriesExhausted {
+            attempts: 3,
+            source: http_error(429),
+        }
+        .into();
+
+        let error = SemanticSearchError::from(error);
+
+        assert_eq!(error.kind, ErrorKind::RateLimited);
+        assert_eq!(error.message(), "Gave up after 3 attempts: status 429");
+    }
+
+    #[test]
+    fn keeps_kind_and_context_through_chains() {
+        let missing =
+            SemanticSearchError::new(ErrorKind::InputMissing, anyhow!("input.csv does not exist"));
+        let error = Err::<(), _>(missing)
+            .context("Failed to read input.csv")
+            .unwrap_err();
+
+        let error = SemanticSearchError::from(error);
+
+        assert_eq!(error.kind, ErrorKind::InputMissing);
+        assert_eq!(error.kind.code(), "input_missing");
+        assert_eq!(error.kind.hint(), Some("Run 'Export Input' first"));
+        assert_eq!(
+            error.message(),
+            "Failed to read input.csv: input.csv does not exist"
+        );
+        assert_eq!(
+            error.describe(),
+            "Failed to read input.csv: input.csv does not exist. Run 'Export Input' first."
+        );
+    }
+
+    #[test]
+    fn classifies_configuration_errors() {
+        let error: SemanticSearchError =
+            ConfigurationError::new("Model", "must not be empty".to_string()).into();
+
+        assert_eq!(error.kind, ErrorKind::Configuration);
+        assert_eq!(
+            error.message(),
+            "Invalid setting 'Model': must not be empty"
+        );
     }
 }
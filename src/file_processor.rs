@@ -1,22 +1,41 @@
 use std::collections::HashMap;
-use std::convert::TryInto;
-use anyhow::{Context, Result};
+use std::collections::HashSet;
+use anyhow::{anyhow, Context, Result};
 
 use csv::ReaderBuilder;
+use js_sys::Uint8Array;
 use log::debug;
 use log::info;
+use log::warn;
 use serde::Deserialize;
 use serde::Serialize;
 use wasm_bindgen::JsCast;
 use wasm_bindgen::prelude::*;
 
+use crate::cache::EmbeddingCache;
+use crate::error::ErrorKind;
 use crate::error::SemanticSearchError;
 use crate::obsidian::TFile;
 use crate::obsidian::TFolder;
 use crate::obsidian::Vault;
+use crate::hnsw::Hnsw;
+use crate::hnsw::Matrix;
+use crate::quantization::QuantizedVector;
+use crate::store::EmbeddingStore;
+use crate::store::IndexHeader;
 
 pub const INPUT_FILE_PATH: &str = "input.csv";
-pub const EMBEDDING_FILE_PATH: &str = "embedding.csv";
+pub const EMBEDDING_FILE_PATH: &str = "embedding.bin";
+/// Comma-separated text format used before the binary index was introduced
+pub const LEGACY_EMBEDDING_FILE_PATH: &str = "embedding.csv";
+pub const HNSW_FILE_PATH: &str = "embedding.hnsw";
+/// Full precision embeddings completed by a run that has not yet been written
+/// to the index, one file per batch, kept so a failed run can resume
+pub const STAGING_FOLDER_PATH: &str = "embedding.staging";
+/// Staging file of earlier versions, which rewrote it after every batch
+const LEGACY_STAGING_FILE_PATH: &str = "embedding.staging.bin";
+/// Embeddings kept across index rebuilds, see `EmbeddingCache`
+pub const CACHE_FILE_PATH: &str = "embedding.cache.bin";
 
 #[wasm_bindgen]
 pub struct FileProcessor {
@@ -26,33 +45,78 @@ pub struct FileProcessor {
 #[derive(Serialize)]
 pub(crate) struct WrittenInputRow<'a> {
 	pub name: &'a str,
+	pub path: &'a str,
 	pub mtime: &'a str,
+	pub section_id: &'a str,
 	pub section: &'a str,
+	pub hash: &'a str,
 	pub body: &'a str
 }
 
 #[derive(Debug, Clone, Deserialize)]
 pub struct InputRow {
 	pub name: String,
+	/// Full vault path of the note, unique unlike `name`
+	pub path: String,
 	pub mtime: String,
+	/// Identifies the section within its note, see `generate_input::extract_sections`
+	pub section_id: String,
 	pub section: String,
+	/// `hash::content_hash` of `body`
+	pub hash: String,
 	pub body: String
 }
 
-#[derive(Serialize)]
-struct WrittenEmbeddingRow<'a> {
-	name: &'a str,
-	mtime: &'a str,
-	header: &'a str,
-	embedding: &'a str
+#[derive(Debug, Deserialize)]
+struct LegacyEmbeddingRow {
+	name: String,
+	mtime: String,
+	header: String,
+	embedding: String
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Clone)]
 pub struct EmbeddingRow {
 	pub name: String,
+	pub path: String,
 	pub mtime: String,
+	pub section_id: String,
 	pub header: String,
-	pub embedding: String
+	pub hash: String,
+	pub embedding: Vec<f32>,
+	/// The codes of an embedding read from an index that only stores
+	/// quantized vectors, in which case `embedding` is their reconstruction
+	pub quantized: Option<QuantizedVector>
+}
+
+/// Number of sections affected by each kind of change since the last index
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct IndexChanges {
+	pub added: usize,
+	pub updated: usize,
+	pub removed: usize,
+	/// Unchanged sections whose note was renamed or moved
+	pub moved: usize,
+}
+
+impl IndexChanges {
+	pub fn is_empty(&self) -> bool {
+		*self == Self::default()
+	}
+}
+
+/// Input sections split into those that need embedding and those whose
+/// previous embedding can be carried over
+#[derive(Debug)]
+pub struct ModifiedInput {
+	pub input: Vec<InputRow>,
+	/// Previous embeddings updated with the current metadata of their section
+	pub reusable: Vec<EmbeddingRow>,
+	pub changes: IndexChanges,
+	/// Whether a previous index existed
+	pub indexed: bool,
+	/// Content hashes of the sections embedded by an earlier, unfinished run
+	pub staged_hashes: HashSet<String>,
 }
 
 impl FileProcessor {
@@ -61,47 +125,220 @@ impl FileProcessor {
     }
 
 	pub async fn read_input_csv(&self) -> Result<Vec<InputRow>> {
+		if !self.check_file_exists_at_path(INPUT_FILE_PATH).await {
+			return Err(SemanticSearchError::new(ErrorKind::InputMissing, anyhow!("{} does not exist", INPUT_FILE_PATH)).into());
+		}
 		let input = self.read_from_path(INPUT_FILE_PATH).await.context(format!("Failed to read {}", INPUT_FILE_PATH))?;
 		let mut reader = ReaderBuilder::new().trim(csv::Trim::All).flexible(false)
 			.from_reader(input.as_bytes());
-		let records = reader.deserialize().collect::<Result<Vec<InputRow>, csv::Error>>().context(format!("Failed to deserialize input.csv"))?;
+		let records = reader.deserialize().collect::<Result<Vec<InputRow>, csv::Error>>().context("Failed to deserialize input.csv")?;
 		Ok(records)
 	}
 
-	pub async fn read_embedding_csv(&self) -> Result<Vec<EmbeddingRow>> {
-		let input = self.read_from_path(EMBEDDING_FILE_PATH).await.context(format!("Failed to read {}", EMBEDDING_FILE_PATH))?;
+	async fn read_legacy_embedding_csv(&self) -> Result<Vec<EmbeddingRow>> {
+		let input = self.read_from_path(LEGACY_EMBEDDING_FILE_PATH).await.context(format!("Failed to read {}", LEGACY_EMBEDDING_FILE_PATH))?;
 		let mut reader = ReaderBuilder::new().trim(csv::Trim::All).flexible(false)
 			.from_reader(input.as_bytes());
-		let records = reader.deserialize().collect::<Result<Vec<EmbeddingRow>, csv::Error>>().context("Failed to deserialize embedding.csv")?;
-		Ok(records)
+		let records = reader.deserialize().collect::<Result<Vec<LegacyEmbeddingRow>, csv::Error>>().context("Failed to deserialize embedding.csv")?;
+		records.into_iter().map(|row| {
+			let embedding = row.embedding.split(',')
+				.map(|s| s.parse::<f32>())
+				.collect::<Result<Vec<f32>, _>>()
+				.with_context(|| format!("Failed to deserialize embedding for file: {} and section: {}", &row.name, &row.header))?;
+			// The CSV format kept neither paths nor hashes, so migrated rows
+			// never match the input and are re-embedded on the next run
+			Ok(EmbeddingRow { name: row.name, path: String::new(), mtime: row.mtime, section_id: String::new(), header: row.header, hash: String::new(), embedding, quantized: None })
+		}).collect()
 	}
 
-	// TODO: return a struct instead
-	pub async fn read_modified_input(&self) -> Result<(i64, Vec<InputRow>, Vec<EmbeddingRow>)> {
-        let mut input = self.read_input_csv().await.context("Failed to read input.csv. Try running 'Generate Input' first")?;
+	/// Reads the binary embedding index, migrating a legacy embedding.csv to it
+	/// the first time it is encountered. `template` supplies the header recorded
+	/// for migrated embeddings, since the CSV format did not store one.
+	/// Returns `None` when neither file exists.
+	pub async fn read_index(&self, template: &IndexHeader) -> Result<Option<EmbeddingStore>> {
+		if self.check_file_exists_at_path(EMBEDDING_FILE_PATH).await {
+			let bytes = self.read_binary_from_path(EMBEDDING_FILE_PATH).await.context(format!("Failed to read {}", EMBEDDING_FILE_PATH))?;
+			let store = EmbeddingStore::from_bytes(&bytes).map_err(|e| SemanticSearchError::new(ErrorKind::IndexCorrupt, e)).context(format!("Failed to deserialize {}", EMBEDDING_FILE_PATH))?;
+			return Ok(Some(store));
+		}
+		if !self.check_file_exists_at_path(LEGACY_EMBEDDING_FILE_PATH).await {
+			return Ok(None);
+		}
+
+		info!("Migrating {} to {}", LEGACY_EMBEDDING_FILE_PATH, EMBEDDING_FILE_PATH);
+		let rows = self.read_legacy_embedding_csv().await?;
+		let store = EmbeddingStore::from_rows(template.clone(), rows)?;
+		self.write_index(&store).await?;
+		self.delete_file_at_path(LEGACY_EMBEDDING_FILE_PATH).await.context(format!("Failed to delete {}", LEGACY_EMBEDDING_FILE_PATH))?;
+		info!("Migrated {} embeddings", store.len());
+		Ok(Some(store))
+	}
+
+	pub async fn write_index(&self, store: &EmbeddingStore) -> Result<()> {
+		let bytes = store.to_bytes()?;
+		self.write_binary_to_path(EMBEDDING_FILE_PATH, &bytes).await.context(format!("Failed to write to {}", EMBEDDING_FILE_PATH))?;
+		Ok(())
+	}
 
-		if !self.check_file_exists_at_path(EMBEDDING_FILE_PATH).await {
-			return Ok((-1, input, Vec::new()));
+	/// Writes the store along with its HNSW graph, updated in place from the
+	/// previous one. The graph is written before the store itself so that an
+	/// index reloaded after the store changes finds a matching graph.
+	pub async fn save_index(&self, store: &EmbeddingStore) -> Result<()> {
+		let mut graph = match self.read_hnsw().await {
+			Ok(graph) => graph.unwrap_or_default(),
+			Err(e) => {
+				warn!("Failed to read HNSW graph, rebuilding it: {:?}", e);
+				Hnsw::default()
+			}
+		};
+		let vectors = store.normalized_vectors();
+		graph.update(store.keys(), Matrix::new(&vectors, store.header.dimension));
+		self.write_hnsw(&graph).await?;
+		info!("Saved HNSW graph over {} embeddings to {}", graph.len(), HNSW_FILE_PATH);
+		self.write_index(store).await
+	}
+
+	pub async fn read_hnsw(&self) -> Result<Option<Hnsw>> {
+		if !self.check_file_exists_at_path(HNSW_FILE_PATH).await {
+			return Ok(None);
 		}
+		let bytes = self.read_binary_from_path(HNSW_FILE_PATH).await.context(format!("Failed to read {}", HNSW_FILE_PATH))?;
+		let graph = Hnsw::from_bytes(&bytes).context(format!("Failed to deserialize {}", HNSW_FILE_PATH))?;
+		Ok(Some(graph))
+	}
 
-		let prev_embeddings = self.read_embedding_csv().await.context("Failed to obtain previous embeddings")?;
-		let mut name_to_modified: HashMap<String, (String, String)> = HashMap::new();
-		prev_embeddings.into_iter().for_each(|e| {
-			name_to_modified.insert(e.name, (e.mtime, e.embedding));
+	pub async fn write_hnsw(&self, graph: &Hnsw) -> Result<()> {
+		let bytes = graph.to_bytes()?;
+		self.write_binary_to_path(HNSW_FILE_PATH, &bytes).await.context(format!("Failed to write to {}", HNSW_FILE_PATH))?;
+		Ok(())
+	}
+
+	/// Splits the sections of the vault's notes into those that need
+	/// embedding and those that can reuse an embedding from the index or from
+	/// the staging file of an unfinished run
+	pub async fn modified_input(&self, input: Vec<InputRow>, template: &IndexHeader) -> Result<ModifiedInput> {
+		let staged = self.read_staging(template).await;
+		let indexed = match self.read_index(template).await {
+			Ok(indexed) => indexed,
+			// Rebuilding is the way out of a corrupt index, so it must not fail
+			Err(e) if ErrorKind::of(&e) == ErrorKind::IndexCorrupt => {
+				warn!("Re-embedding all sections, {} is corrupt: {:?}", EMBEDDING_FILE_PATH, e);
+				None
+			}
+			Err(e) => return Err(e.context("Failed to obtain previous embeddings")),
+		};
+		let indexed = indexed.map(|store| {
+			// Embeddings from another model or with other prefixes cannot be
+			// compared with new ones, so every section is embedded again
+			match store.header.mismatch(template).or_else(|| store.header.storage_mismatch(template)) {
+				Some(mismatch) => {
+					info!("Re-embedding all sections, {} was embedded with {}", EMBEDDING_FILE_PATH, mismatch);
+					Vec::new()
+				}
+				None => store.into_rows(),
+			}
 		});
+		if indexed.is_none() && staged.is_empty() {
+			let changes = IndexChanges { added: input.len(), ..Default::default() };
+			return Ok(ModifiedInput { input, reusable: Vec::new(), changes, indexed: false, staged_hashes: HashSet::new() });
+		}
 
-		let mut embedding_rows: Vec<EmbeddingRow> = Vec::new();
+		let has_index = indexed.is_some();
+		let staged_hashes = staged.iter().map(|row| row.hash.to_string()).collect();
+		// Staged rows come last so they replace the indexed version of a section
+		let mut prev_embeddings = indexed.unwrap_or_default();
+		prev_embeddings.extend(staged);
+		let mut modified = diff_sections(input, prev_embeddings);
+		modified.indexed = has_index;
+		modified.staged_hashes = staged_hashes;
+		Ok(modified)
+	}
 
-		input.retain(|r| {
-			if let Some((prev_mtime, prev_embedding)) = name_to_modified.get(&r.name) {
-				if prev_mtime == &r.mtime {
-					embedding_rows.push(EmbeddingRow { name: r.name.to_string(), mtime: r.mtime.to_string(), header: r.section.to_string(), embedding: prev_embedding.to_string() });
-					return false;
+	/// Reads the embeddings staged by an unfinished run. Staged embeddings from
+	/// a different model, provider or prefixes than `template`, or in a file
+	/// that cannot be read, are discarded.
+	async fn read_staging(&self, template: &IndexHeader) -> Vec<EmbeddingRow> {
+		let mut paths = Vec::new();
+		if self.check_file_exists_at_path(LEGACY_STAGING_FILE_PATH).await {
+			paths.push(LEGACY_STAGING_FILE_PATH.to_string());
+		}
+		if let Some(folder) = self.staging_folder() {
+			let mut batches: Vec<String> = folder.children().iter().map(|file| file.path()).filter(|path| path.ends_with(".bin")).collect();
+			batches.sort_unstable();
+			paths.extend(batches);
+		}
+
+		let mut rows = Vec::new();
+		for path in &paths {
+			let store = match self.read_binary_from_path(path).await.map_err(anyhow::Error::from).and_then(|bytes| EmbeddingStore::from_bytes(&bytes)) {
+				Ok(store) => store,
+				Err(e) => {
+					warn!("Ignoring unreadable {}: {:?}", path, e);
+					continue;
 				}
+			};
+			if let Some(mismatch) = store.header.mismatch(template) {
+				info!("Discarding {} embedded with {}", path, mismatch);
+				continue;
 			}
-			true
-		});
-		Ok((input.len().try_into().expect("Too many files"), input, embedding_rows))
+			rows.extend(store.into_rows());
+		}
+		if !rows.is_empty() {
+			info!("Resuming from {} staged embeddings", rows.len());
+		}
+		rows
+	}
+
+	fn staging_folder(&self) -> Option<TFolder> {
+		let folder = self.vault.getAbstractFileByPath(STAGING_FOLDER_PATH.to_string());
+		if folder.is_null() {
+			return None;
+		}
+		folder.dyn_into::<TFolder>().ok()
+	}
+
+	/// Stages the embeddings of batch `batch` of the run started at `run`, in
+	/// a file of its own so that earlier batches are not written again
+	pub async fn write_staging_batch(&self, run: u64, batch: usize, store: &EmbeddingStore) -> Result<()> {
+		if self.staging_folder().is_none() {
+			self.vault.createFolder(STAGING_FOLDER_PATH.to_string()).await.map_err(SemanticSearchError::from).context(format!("Failed to create {}", STAGING_FOLDER_PATH))?;
+		}
+		let path = format!("{}/{}-{:05}.bin", STAGING_FOLDER_PATH, run, batch);
+		let bytes = store.to_bytes()?;
+		self.write_binary_to_path(&path, &bytes).await.context(format!("Failed to write to {}", path))?;
+		Ok(())
+	}
+
+	/// Deletes the staged embeddings once they have been merged into the index
+	pub async fn delete_staging(&self) -> Result<()> {
+		if self.check_file_exists_at_path(LEGACY_STAGING_FILE_PATH).await {
+			self.delete_file_at_path(LEGACY_STAGING_FILE_PATH).await.context(format!("Failed to delete {}", LEGACY_STAGING_FILE_PATH))?;
+		}
+		if let Some(folder) = self.staging_folder() {
+			self.vault.deleteFolder(folder, true).await.map_err(SemanticSearchError::from).context(format!("Failed to delete {}", STAGING_FOLDER_PATH))?;
+		}
+		Ok(())
+	}
+
+	pub async fn read_cache(&self, max_megabytes: u32) -> Result<EmbeddingCache> {
+		if !self.check_file_exists_at_path(CACHE_FILE_PATH).await {
+			return Ok(EmbeddingCache::new(max_megabytes));
+		}
+		let bytes = self.read_binary_from_path(CACHE_FILE_PATH).await.context(format!("Failed to read {}", CACHE_FILE_PATH))?;
+		EmbeddingCache::from_bytes(&bytes, max_megabytes).context(format!("Failed to deserialize {}", CACHE_FILE_PATH))
+	}
+
+	pub async fn write_cache(&self, cache: &EmbeddingCache) -> Result<()> {
+		let bytes = cache.to_bytes()?;
+		self.write_binary_to_path(CACHE_FILE_PATH, &bytes).await.context(format!("Failed to write to {}", CACHE_FILE_PATH))?;
+		Ok(())
+	}
+
+	pub async fn delete_cache(&self) -> Result<()> {
+		if self.check_file_exists_at_path(CACHE_FILE_PATH).await {
+			self.delete_file_at_path(CACHE_FILE_PATH).await.context(format!("Failed to delete {}", CACHE_FILE_PATH))?;
+		}
+		Ok(())
 	}
 
 	pub async fn write_input_csv(&self, embeddings: Vec<InputRow>) -> Result<()> {
@@ -109,8 +346,11 @@ impl FileProcessor {
 		for row in embeddings {
 			wtr.serialize(WrittenInputRow {
 				name: &row.name,
+				path: &row.path,
 				mtime: &row.mtime,
+				section_id: &row.section_id,
 				section: &row.section,
+				hash: &row.hash,
 				body: &row.body
 			})?;
 		}
@@ -119,27 +359,18 @@ impl FileProcessor {
 		Ok(())
 	}
 
-	pub async fn write_embedding_csv(&self, embeddings: Vec<EmbeddingRow>, with_header: bool) -> Result<()> {
-		let mut wtr = csv::WriterBuilder::new().has_headers(with_header).from_writer(vec![]);
-		for row in embeddings {
-			wtr.serialize(WrittenEmbeddingRow {
-				name: &row.name,
-				mtime: &row.mtime,
-				header: &row.header,
-				embedding: &row.embedding,
-			}).context("Failed to serialize embedding row")?;
-		}
-		let data = String::from_utf8(wtr.into_inner()?)?;
-		self.write_to_path(EMBEDDING_FILE_PATH, &data).await.context(format!("Failed to write to {}", EMBEDDING_FILE_PATH))?;
-		Ok(())
-	}
-
     async fn read_from_path(&self, path: &str) -> Result<String, SemanticSearchError> {
         let file: TFile = self.vault.getAbstractFileByPath(path.to_string()).unchecked_into();
         let input = self.vault.cachedRead(file).await?.as_string().expect("file contents is not a string");
         Ok(input)
     }
 
+    async fn read_binary_from_path(&self, path: &str) -> Result<Vec<u8>, SemanticSearchError> {
+        let file: TFile = self.vault.getAbstractFileByPath(path.to_string()).unchecked_into();
+        let buffer = self.vault.readBinary(file).await?;
+        Ok(Uint8Array::new(&buffer).to_vec())
+    }
+
     pub async fn read_from_file(&self, file: TFile) -> Result<String, SemanticSearchError> {
         let input = self.vault.cachedRead(file).await?.as_string().expect("file contents is not a string");
         Ok(input)
@@ -156,22 +387,56 @@ impl FileProcessor {
         Ok(())
     }
 
+    /// Replaces the contents of the file at `path`, creating it if needed
+    async fn write_binary_to_path(&self, path: &str, data: &[u8]) -> Result<(), SemanticSearchError> {
+        let buffer = Uint8Array::from(data).buffer();
+        let file: TFile = self.vault.getAbstractFileByPath(path.to_string()).unchecked_into();
+        if file.is_null() {
+            debug!("File: {} does not exist. Creating it now.", path);
+            self.vault.createBinary(path.to_string(), buffer).await?;
+            return Ok(());
+        }
+        self.vault.modifyBinary(file, buffer).await?;
+        Ok(())
+    }
+
 	pub async fn delete_input(&self) -> Result<()> {
 		self.delete_file_at_path(INPUT_FILE_PATH).await.context(format!("Failed to delete {}", INPUT_FILE_PATH))?;
 		Ok(())
 	}
 
-	pub async fn delete_embeddings(&self) -> Result<()> {
-		self.delete_file_at_path(EMBEDDING_FILE_PATH).await.context(format!("Failed to delete {}", EMBEDDING_FILE_PATH))?;
-		Ok(())
-	}
-
     async fn delete_file_at_path(&self, path: &str) -> Result<(), SemanticSearchError> {
         let file: TFile = self.vault.getAbstractFileByPath(path.to_string()).unchecked_into();
+        if file.is_null() {
+            return Err(SemanticSearchError::new(ErrorKind::VaultIo, anyhow!("{} does not exist", path)));
+        }
         self.vault.delete(file).await?;
         Ok(())
     }
 
+    /// Last modification time of the file at `path`, or `None` if it does not exist
+    pub fn get_file_mtime(&self, path: &str) -> Option<f64> {
+        let file = self.vault.getAbstractFileByPath(path.to_string());
+        if file.is_null() {
+            return None;
+        }
+        let file: TFile = file.unchecked_into();
+        Some(file.stat().mtime())
+    }
+
+    /// The markdown file at `path`, if it exists and is not ignored
+    pub fn get_markdown_file(&self, path: &str, ignored_folders_setting: &str) -> Option<TFile> {
+        let file = self.vault.getAbstractFileByPath(path.to_string());
+        if file.is_null() || !file.has_type::<TFile>() || is_ignored(path, ignored_folders_setting) {
+            return None;
+        }
+        let file: TFile = file.unchecked_into();
+        if file.extension() != "md" {
+            return None;
+        }
+        Some(file)
+    }
+
     pub async fn check_file_exists_at_path(&self, path: &str) -> bool {
         let file = self.vault.getAbstractFileByPath(path.to_string());
         if file.is_null() {
@@ -185,7 +450,7 @@ impl FileProcessor {
         let ignored_folders: Vec<String> = ignored_folders_setting.split("\n").map(|x| x.to_string()).collect();
         info!("Ignored folders: {:?}", &ignored_folders);
     
-        return self.search_for_markdown_files(root, &ignored_folders);
+        self.search_for_markdown_files(root, &ignored_folders)
     }
 
     fn search_for_markdown_files(&self, root: TFolder, ignored_folders: &Vec<String>) -> Vec<TFile> {
@@ -197,7 +462,7 @@ impl FileProcessor {
                 if ignored_folders.contains(&folder.path()) {
                     continue;
                 }
-                markdown_files.extend(self.search_for_markdown_files(folder, &ignored_folders));
+                markdown_files.extend(self.search_for_markdown_files(folder, ignored_folders));
             } else {
                 let file = child.dyn_into::<TFile>().expect("File should have TFile type");
                 if file.extension() == "md" {
@@ -206,6 +471,200 @@ impl FileProcessor {
             }
         }
 
-        return markdown_files;
+        markdown_files
     }
 }
+
+/// Matches input sections against the previous embeddings. A section keeps
+/// its embedding when its path, section id and content hash are unchanged, or
+/// when its note was renamed or moved and a previous section that no longer
+/// exists has the same content hash. Previous embeddings left unmatched belong
+/// to deleted or edited sections and are dropped.
+pub(crate) fn diff_sections(input: Vec<InputRow>, prev_embeddings: Vec<EmbeddingRow>) -> ModifiedInput {
+	let mut section_to_embedding: HashMap<(String, String), EmbeddingRow> = HashMap::new();
+	prev_embeddings.into_iter().for_each(|e| {
+		section_to_embedding.insert((e.path.to_string(), e.section_id.to_string()), e);
+	});
+
+	let mut changes = IndexChanges::default();
+	let mut modified: Vec<InputRow> = Vec::new();
+	let mut unmatched: Vec<InputRow> = Vec::new();
+	let mut reusable: Vec<EmbeddingRow> = Vec::new();
+	for r in input {
+		match section_to_embedding.remove(&(r.path.to_string(), r.section_id.to_string())) {
+			Some(prev) if prev.hash == r.hash => reusable.push(reuse_embedding(&r, prev)),
+			Some(_) => {
+				changes.updated += 1;
+				modified.push(r);
+			}
+			None => unmatched.push(r),
+		}
+	}
+
+	let mut hash_to_embeddings: HashMap<String, Vec<EmbeddingRow>> = HashMap::new();
+	section_to_embedding.into_values().for_each(|e| {
+		hash_to_embeddings.entry(e.hash.to_string()).or_default().push(e);
+	});
+	for r in unmatched {
+		match hash_to_embeddings.get_mut(&r.hash).and_then(Vec::pop) {
+			Some(prev) => {
+				changes.moved += 1;
+				reusable.push(reuse_embedding(&r, prev));
+			}
+			None => {
+				changes.added += 1;
+				modified.push(r);
+			}
+		}
+	}
+	changes.removed = hash_to_embeddings.values().map(Vec::len).sum();
+
+	ModifiedInput { input: modified, reusable, changes, indexed: true, staged_hashes: HashSet::new() }
+}
+
+/// Whether `path` lies in one of the newline separated ignored folders
+pub(crate) fn is_ignored(path: &str, ignored_folders_setting: &str) -> bool {
+	ignored_folders_setting.split('\n')
+		.filter(|folder| !folder.is_empty())
+		.any(|folder| path.strip_prefix(folder).is_some_and(|rest| rest.starts_with('/')))
+}
+
+fn reuse_embedding(r: &InputRow, prev: EmbeddingRow) -> EmbeddingRow {
+	EmbeddingRow { name: r.name.to_string(), path: r.path.to_string(), mtime: r.mtime.to_string(), section_id: r.section_id.to_string(), header: r.section.to_string(), hash: r.hash.to_string(), embedding: prev.embedding, quantized: prev.quantized }
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::hash::content_hash;
+
+	fn input(path: &str, section_id: &str, body: &str) -> InputRow {
+		InputRow {
+			name: path.rsplit('/').next().unwrap().to_string(),
+			path: path.to_string(),
+			mtime: "2".to_string(),
+			section_id: section_id.to_string(),
+			section: section_id.to_string(),
+			hash: content_hash(body),
+			body: body.to_string(),
+		}
+	}
+
+	fn embedding(path: &str, section_id: &str, body: &str, embedding: Vec<f32>) -> EmbeddingRow {
+		EmbeddingRow {
+			name: path.rsplit('/').next().unwrap().to_string(),
+			path: path.to_string(),
+			mtime: "1".to_string(),
+			section_id: section_id.to_string(),
+			header: section_id.to_string(),
+			hash: content_hash(body),
+			embedding,
+			quantized: None,
+		}
+	}
+
+	#[test]
+	fn reuses_each_unchanged_section() {
+		let rows = vec![input("a.md", "one", "first"), input("a.md", "two", "second")];
+		let prev = vec![embedding("a.md", "one", "first", vec![1.0]), embedding("a.md", "two", "second", vec![2.0])];
+
+		let modified = diff_sections(rows, prev);
+
+		assert!(modified.input.is_empty());
+		assert!(modified.changes.is_empty());
+		assert_eq!(modified.reusable.len(), 2);
+		assert_eq!(modified.reusable[0].embedding, vec![1.0]);
+		assert_eq!(modified.reusable[1].embedding, vec![2.0]);
+		assert_eq!(modified.reusable[0].mtime, "2");
+	}
+
+	#[test]
+	fn re_embeds_only_edited_sections() {
+		let rows = vec![input("a.md", "one", "first"), input("a.md", "two", "second, edited")];
+		let prev = vec![embedding("a.md", "one", "first", vec![1.0]), embedding("a.md", "two", "second", vec![2.0])];
+
+		let modified = diff_sections(rows, prev);
+
+		assert_eq!(modified.reusable.len(), 1);
+		assert_eq!(modified.reusable[0].section_id, "one");
+		assert_eq!(modified.input.len(), 1);
+		assert_eq!(modified.input[0].section_id, "two");
+		assert_eq!(modified.changes, IndexChanges { updated: 1, ..Default::default() });
+	}
+
+	#[test]
+	fn keeps_same_named_files_apart() {
+		let rows = vec![input("x/note.md", "one", "body"), input("y/note.md", "one", "other body")];
+		let prev = vec![embedding("x/note.md", "one", "body", vec![1.0])];
+
+		let modified = diff_sections(rows, prev);
+
+		assert_eq!(modified.reusable.len(), 1);
+		assert_eq!(modified.reusable[0].path, "x/note.md");
+		assert_eq!(modified.input.len(), 1);
+		assert_eq!(modified.input[0].path, "y/note.md");
+		assert_eq!(modified.changes, IndexChanges { added: 1, ..Default::default() });
+	}
+
+	#[test]
+	fn drops_deleted_notes() {
+		let rows = vec![input("a.md", "one", "first")];
+		let prev = vec![embedding("a.md", "one", "first", vec![1.0]), embedding("b.md", "one", "gone", vec![2.0])];
+
+		let modified = diff_sections(rows, prev);
+
+		assert_eq!(modified.reusable.len(), 1);
+		assert_eq!(modified.reusable[0].path, "a.md");
+		assert_eq!(modified.changes, IndexChanges { removed: 1, ..Default::default() });
+	}
+
+	#[test]
+	fn carries_embeddings_over_renames() {
+		let rows = vec![input("archive/renamed.md", "one", "first"), input("archive/renamed.md", "two", "new")];
+		let prev = vec![embedding("a.md", "one", "first", vec![1.0]), embedding("a.md", "two", "second", vec![2.0])];
+
+		let modified = diff_sections(rows, prev);
+
+		assert_eq!(modified.reusable.len(), 1);
+		assert_eq!(modified.reusable[0].path, "archive/renamed.md");
+		assert_eq!(modified.reusable[0].name, "renamed.md");
+		assert_eq!(modified.reusable[0].embedding, vec![1.0]);
+		assert_eq!(modified.input.len(), 1);
+		assert_eq!(modified.changes, IndexChanges { added: 1, removed: 1, moved: 1, ..Default::default() });
+	}
+
+	#[test]
+	fn re_embeds_migrated_rows() {
+		let rows = vec![input("a.md", "one", "first")];
+		let mut prev = embedding("", "", "first", vec![1.0]);
+		prev.hash = String::new();
+
+		let modified = diff_sections(rows, vec![prev]);
+
+		assert_eq!(modified.input.len(), 1);
+		assert_eq!(modified.changes, IndexChanges { added: 1, removed: 1, ..Default::default() });
+	}
+
+	#[test]
+	fn ignores_nested_paths() {
+		let ignored = "templates\narchive/old";
+
+		assert!(is_ignored("templates/daily.md", ignored));
+		assert!(is_ignored("archive/old/2020/note.md", ignored));
+		assert!(!is_ignored("archive/note.md", ignored));
+		assert!(!is_ignored("templates-old/note.md", ignored));
+		assert!(!is_ignored("note.md", ""));
+	}
+
+	#[test]
+	fn staged_embeddings_replace_indexed_ones() {
+		let rows = vec![input("a.md", "one", "edited")];
+		let prev = vec![embedding("a.md", "one", "first", vec![1.0]), embedding("a.md", "one", "edited", vec![2.0])];
+
+		let modified = diff_sections(rows, prev);
+
+		assert!(modified.input.is_empty());
+		assert_eq!(modified.reusable[0].embedding, vec![2.0]);
+		assert!(modified.changes.is_empty());
+	}
+}
@@ -1,8 +1,12 @@
+use std::collections::HashMap;
+
 use log::debug;
 use log::info;
 use regex::Regex;
 use log::error;
+use js_sys::Function;
 use wasm_bindgen::prelude::*;
+use web_sys::AbortSignal;
 use lazy_static::lazy_static;
 use anyhow::{Context, Result};
 
@@ -10,83 +14,165 @@ use crate::FileProcessor;
 use crate::SemanticSearchError;
 use crate::Notice;
 use crate::file_processor::InputRow;
+use crate::file_processor::INPUT_FILE_PATH;
+use crate::hash::content_hash;
 use crate::obsidian;
 use crate::obsidian::App;
 use crate::obsidian::semanticSearchSettings;
+use crate::progress::Phase;
+use crate::progress::Progress;
+use crate::progress::ProgressReporter;
+use crate::provider;
+
+/// How notes are split into sections, from the settings
+#[derive(Debug, Clone)]
+pub(crate) struct SectionReader {
+	ignored_folders: String,
+	section_delimeter_regex: String,
+	max_token_length: u32,
+}
+
+impl SectionReader {
+	pub fn new(settings: &semanticSearchSettings) -> SectionReader {
+		SectionReader {
+			ignored_folders: settings.ignoredFolders(),
+			section_delimeter_regex: settings.sectionDelimeterRegex(),
+			max_token_length: provider::max_token_length(settings),
+		}
+	}
+
+	/// Sections of every note in the vault, or `None` if aborted before all
+	/// were read. A note that cannot be read is logged and skipped.
+	pub async fn read_vault(&self, file_processor: &FileProcessor, reporter: &ProgressReporter) -> Result<Option<Vec<InputRow>>, SemanticSearchError> {
+		let files = file_processor.get_vault_markdown_files(self.ignored_folders.clone());
+		info!("Found {} files", files.len());
+		info!("Processing files with regex: {}, max token length: {}",
+			&self.section_delimeter_regex, &self.max_token_length);
+		let mut progress = Progress::new(Phase::ReadingNotes);
+		progress.files_total = files.len();
+		reporter.report(&progress);
+		let mut folded_input: Vec<InputRow> = Vec::new();
+		for file in files {
+			if reporter.is_aborted() {
+				return Ok(None);
+			}
+			match process_file(file_processor, file, &self.section_delimeter_regex, self.max_token_length).await {
+				Ok(mut extracted) => {
+					folded_input.append(&mut extracted);
+				},
+				Err(e) => error!("{:?}", e),
+			}
+			progress.files_processed += 1;
+			reporter.report(&progress);
+		}
+		Ok(Some(folded_input))
+	}
+}
 
+/// Writes the sections 'Generate Embeddings' would embed to input.csv, to
+/// inspect how notes are split. Embedding does not need it.
 #[wasm_bindgen]
-pub struct GenerateInputCommand {
+pub struct ExportInputCommand {
     file_processor: FileProcessor,
-    ignored_folders: String,
-    section_delimeter_regex: String,
-    max_token_length: u32,
+    sections: SectionReader,
 }
 
 #[wasm_bindgen]
-impl GenerateInputCommand {
+impl ExportInputCommand {
     #[wasm_bindgen(constructor)]
-    pub fn new(app: App, settings: semanticSearchSettings) -> GenerateInputCommand {
+    pub fn new(app: App, settings: semanticSearchSettings) -> ExportInputCommand {
         let file_processor = FileProcessor::new(app.vault());
-        let ignored_folders = settings.ignoredFolders();
-        let section_delimeter_regex = settings.sectionDelimeterRegex();
-        let max_token_length = settings.maxTokenLength();
+        let sections = SectionReader::new(&settings);
 
-        GenerateInputCommand { file_processor, ignored_folders, section_delimeter_regex, max_token_length}
+        ExportInputCommand { file_processor, sections }
     }
 
-    pub async fn callback(&self) {
-		let data: Vec<InputRow>;
-        match self.generate_input().await {
-			Ok(input) => data = input,
+    /// Regenerates input.csv. `on_progress` is called with a `Progress` as
+    /// notes are read, and once `signal` is aborted input.csv is left as it was.
+    pub async fn callback(&self, on_progress: Option<Function>, signal: Option<AbortSignal>) {
+		let reporter = ProgressReporter::new(on_progress, signal);
+		let data: Vec<InputRow> = match self.sections.read_vault(&self.file_processor, &reporter).await {
+			Ok(Some(input)) => input,
+			Ok(None) => {
+				info!("Stopped exporting input");
+				Notice::new("Stopped exporting input, input.csv was left unchanged");
+				return;
+			}
 			Err(e) => {
-				Notice::new(&format!("An error occurred generating inputs: {}", e));
+				Notice::new(&format!("An error occurred exporting inputs: {}", e.describe()));
 				error!("{:?}", e);
 				return;
 			}
+		};
+		let mut progress = Progress::new(Phase::WritingInput);
+		reporter.report(&progress);
+		if self.file_processor.check_file_exists_at_path(INPUT_FILE_PATH).await {
+			info!("Deleting input.csv");
+			match self.file_processor.delete_input().await {
+				Ok(()) => (),
+				Err(e) => error!("{:?}", e),
+			}
 		}
-		info!("Deleting input.csv");
-        match self.file_processor.delete_input().await {
-            Ok(()) => (),
-            Err(e) => error!("{:?}", e),
-        }
 		info!("Writing input.csv");
-        match self.file_processor.write_input_csv(data).await {
-            Ok(()) => (),
-            Err(e) => error!("{:?}", e),
-        }
+		if let Err(e) = self.file_processor.write_input_csv(data).await {
+			let e = SemanticSearchError::from(e);
+			Notice::new(&format!("An error occurred writing input.csv: {}", e.describe()));
+			error!("{:?}", e);
+			return;
+		}
 
-        Notice::new("Successfully created input.csv");
+		progress.phase = Phase::Done;
+		reporter.report(&progress);
+        Notice::new("Successfully exported input.csv");
     }
+}
 
-    async fn generate_input(&self) -> Result<Vec<InputRow>, SemanticSearchError> {
-        let files = self.file_processor.get_vault_markdown_files(self.ignored_folders.clone());
-		info!("Found {} files", files.len());
-		info!("Processing files with regex: {}, max token length: {}",
-			&self.section_delimeter_regex, &self.max_token_length);
-		let mut folded_input: Vec<InputRow> = Vec::new();
-        for file in files {
-            match self.process_file(file).await {
-				Ok(mut extracted) => {
-					folded_input.append(&mut extracted);
-				},
-				Err(e) => error!("{:?}", e),
-			}
-        }
-        Ok(folded_input)
-    }
+/// Reads a note and splits it into sections
+pub(crate) async fn process_file(file_processor: &FileProcessor, file: obsidian::TFile, delimeter: &str, max_token_length: u32) -> Result<Vec<InputRow>, SemanticSearchError> {
+	let path = file.path();
+	debug!("processing {}", path);
+	let mtime = file.stat().mtime();
+	let text = file_processor.read_from_file(file).await.context(format!("Failed to read {}", path))?;
+	let sections = extract_sections(&path, &mtime.to_string(), &text, delimeter, max_token_length)?;
+	Ok(sections)
+}
+
+/// Collects the sections of a single file, giving each one an id that stays
+/// the same as long as its heading and position among equally named headings
+/// do, and a hash of its body to detect edits.
+struct FileSections<'a> {
+	path: &'a str,
+	name: &'a str,
+	mtime: &'a str,
+	seen_ids: HashMap<String, usize>,
+	rows: Vec<InputRow>,
+}
 
-    async fn process_file(&self, file: obsidian::TFile) -> Result<Vec<InputRow>, SemanticSearchError> {
-        let name = file.name();
-		debug!("processing {}", name);
-		let mtime = file.stat().mtime();
-        let text = self.file_processor.read_from_file(file).await.context(format!("Failed to read {}", name))?;
-		let sections = extract_sections(&name, &mtime.to_string(), &text, &self.section_delimeter_regex, self.max_token_length)?;
-		Ok(sections)
+impl<'a> FileSections<'a> {
+	fn new(path: &'a str, mtime: &'a str) -> Self {
+		let name = path.rsplit('/').next().unwrap_or(path);
+		FileSections { path, name, mtime, seen_ids: HashMap::new(), rows: Vec::new() }
+	}
+
+	fn push(&mut self, section: String, body: String) {
+		let slug = section.to_lowercase().split_whitespace().collect::<Vec<&str>>().join("-");
+		let occurrence = self.seen_ids.entry(slug.clone()).or_insert(0);
+		let section_id = if *occurrence == 0 { slug } else { format!("{}-{}", slug, occurrence) };
+		*occurrence += 1;
+		self.rows.push(InputRow {
+			name: self.name.to_string(),
+			path: self.path.to_string(),
+			mtime: self.mtime.to_string(),
+			section_id,
+			section,
+			hash: content_hash(&body),
+			body,
+		});
 	}
 }
 
-fn extract_sections(name: &str, mtime: &str, text: &str, delimeter: &str, max_token_length: u32) -> Result<Vec<InputRow>, SemanticSearchError> {
-    let mut output: Vec<InputRow> = Vec::new();
+fn extract_sections(path: &str, mtime: &str, text: &str, delimeter: &str, max_token_length: u32) -> Result<Vec<InputRow>, SemanticSearchError> {
+    let mut output = FileSections::new(path, mtime);
     let mut lines = text.lines().peekable();
     let re = match Regex::new(delimeter) {
         Ok(r) => r,
@@ -98,12 +184,12 @@ fn extract_sections(name: &str, mtime: &str, text: &str, delimeter: &str, max_to
     let mut section_header = "".to_string();
     let mut body = String::new();
     while let Some(line) = lines.next() {
-        if re.is_match(&line) {
+        if re.is_match(line) {
             if !(section_header.trim().is_empty() && body.trim().is_empty()) {
 				let section_text = clean_text(&section_header, max_token_length);
 				let body_text = clean_text(&body, max_token_length);
 				if !(section_text.is_empty() && body_text.is_empty()) {
-					output.push(InputRow { name: name.to_string(), mtime: mtime.to_string(), section: section_text, body: body_text});
+					output.push(section_text, body_text);
 				}
 			}
 			section_header = line.to_string();
@@ -114,7 +200,7 @@ fn extract_sections(name: &str, mtime: &str, text: &str, delimeter: &str, max_to
 			}
 			let cleaned_line = clean_text(line, max_token_length);
 			if !cleaned_line.is_empty() {
-				body.push_str(&" ");
+				body.push(' ');
 				body.push_str(&cleaned_line);
 			}
 		}
@@ -122,11 +208,11 @@ fn extract_sections(name: &str, mtime: &str, text: &str, delimeter: &str, max_to
 			let section_text = clean_text(&section_header, max_token_length);
 			let body_text = clean_text(&body, max_token_length);
 			if !(section_text.is_empty() && body_text.is_empty()) {
-				output.push(InputRow { name: name.to_string(), mtime: mtime.to_string(), section: section_text, body: body_text});
+				output.push(section_text, body_text);
 			}
 		}
     }
-    Ok(output)
+    Ok(output.rows)
 }
 
 fn clean_text(text: &str, max_token_length: u32) -> String {
@@ -367,4 +453,22 @@ Guarantees reliability only if sender is correct
         assert_eq!(res.get(1).unwrap().section, "Test2");
         assert_eq!(res.get(1).unwrap().body, "Test2");
     }
+
+    #[test]
+    fn section_ids_and_hashes() {
+        let text = "## Intro\nHello\n## Intro\nHello\n## Other Part\nWorld";
+        let section_delimeter = "^## ";
+
+        let res = extract_sections("folder/note.md", " ", text, section_delimeter, DEFAULT_MAX_TOKEN).unwrap();
+
+        assert_eq!(res.len(), 3);
+        assert_eq!(res[0].name, "note.md");
+        assert_eq!(res[0].path, "folder/note.md");
+        assert_eq!(res[0].section_id, "intro");
+        assert_eq!(res[1].section_id, "intro-1");
+        assert_eq!(res[2].section_id, "other-part");
+        assert_eq!(res[0].hash, res[1].hash);
+        assert_ne!(res[0].hash, res[2].hash);
+        assert_eq!(res[2].hash, content_hash(&res[2].body));
+    }
 }
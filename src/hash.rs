@@ -0,0 +1,27 @@
+const FNV_OFFSET_BASIS: u64 = 0xcbf2_9ce4_8422_2325;
+const FNV_PRIME: u64 = 0x0000_0100_0000_01b3;
+
+/// 64-bit FNV-1a hash of `text` as 16 hex digits. Stable across platforms and
+/// releases, unlike `std::hash`, so it can be persisted in the vault.
+pub fn content_hash(text: &str) -> String {
+    let hash = text.bytes().fold(FNV_OFFSET_BASIS, |hash, byte| {
+        (hash ^ byte as u64).wrapping_mul(FNV_PRIME)
+    });
+    format!("{:016x}", hash)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn known_values() {
+        assert_eq!(content_hash(""), "cbf29ce484222325");
+        assert_eq!(content_hash("a"), "af63dc4c8601ec8c");
+    }
+
+    #[test]
+    fn differs_on_content() {
+        assert_ne!(content_hash("Test body"), content_hash("Test body."));
+    }
+}
@@ -0,0 +1,632 @@
+use std::cmp::{Ordering, Reverse};
+use std::collections::{BinaryHeap, HashMap, HashSet};
+use std::convert::TryInto;
+
+use anyhow::{anyhow, Context, Result};
+use log::debug;
+
+use crate::byte_reader::ByteReader;
+
+/// Identifies an HNSW graph file written by this plugin
+const MAGIC: &[u8; 4] = b"SSHN";
+const FORMAT_VERSION: u32 = 1;
+const NO_ENTRY_POINT: u32 = u32::MAX;
+pub const DEFAULT_M: usize = 16;
+pub const DEFAULT_EF_CONSTRUCTION: usize = 64;
+/// Upper bound on node levels, far above what `DEFAULT_M` produces in practice
+const MAX_LEVEL: usize = 16;
+/// Rebuilding is cheaper than repairing once this fraction of nodes is removed
+const REBUILD_FRACTION: f64 = 0.5;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Candidate {
+    similarity: f32,
+    node: u32,
+}
+
+impl Eq for Candidate {}
+
+impl PartialOrd for Candidate {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Candidate {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.similarity
+            .total_cmp(&other.similarity)
+            .then(self.node.cmp(&other.node))
+    }
+}
+
+/// Row-major matrix of L2-normalized vectors, so similarity is a dot product
+#[derive(Clone, Copy)]
+pub struct Matrix<'a> {
+    data: &'a [f32],
+    dimension: usize,
+}
+
+impl<'a> Matrix<'a> {
+    pub fn new(data: &'a [f32], dimension: usize) -> Self {
+        Self { data, dimension }
+    }
+
+    fn len(&self) -> usize {
+        self.data.len().checked_div(self.dimension).unwrap_or(0)
+    }
+
+    fn row(&self, i: usize) -> &'a [f32] {
+        &self.data[i * self.dimension..(i + 1) * self.dimension]
+    }
+
+    fn similarity(&self, a: usize, b: usize) -> f32 {
+        self.row(a).iter().zip(self.row(b)).map(|(x, y)| x * y).sum()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Node {
+    /// Neighbour lists for layers `0..=level`
+    neighbours: Vec<Vec<u32>>,
+}
+
+impl Node {
+    fn with_level(level: usize) -> Self {
+        Self {
+            neighbours: vec![Vec::new(); level + 1],
+        }
+    }
+
+    fn level(&self) -> usize {
+        self.neighbours.len() - 1
+    }
+}
+
+/// Hierarchical navigable small world graph over the sections of an index.
+/// Node `i` corresponds to row `i` of the embedding store it was last updated
+/// with, and `keys` records which section each node holds so that the graph
+/// can be matched against, and incrementally updated to, a newer store.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Hnsw {
+    m: usize,
+    ef_construction: usize,
+    keys: Vec<String>,
+    nodes: Vec<Node>,
+    entry_point: Option<u32>,
+    rng: u64,
+}
+
+impl Default for Hnsw {
+    fn default() -> Self {
+        Self::new(DEFAULT_M, DEFAULT_EF_CONSTRUCTION)
+    }
+}
+
+impl Hnsw {
+    pub fn new(m: usize, ef_construction: usize) -> Self {
+        Self {
+            m: m.max(2),
+            ef_construction: ef_construction.max(1),
+            keys: Vec::new(),
+            nodes: Vec::new(),
+            entry_point: None,
+            rng: 0x2545_f491_4f6c_dd1d,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.nodes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.nodes.is_empty()
+    }
+
+    pub fn keys(&self) -> &[String] {
+        &self.keys
+    }
+
+    /// Brings the graph in line with a new set of rows. Nodes whose key is
+    /// still present are kept and renumbered to their new row, nodes whose key
+    /// disappeared are unlinked and their neighbours reconnected, and rows
+    /// without a node are inserted.
+    pub fn update(&mut self, keys: Vec<String>, matrix: Matrix) {
+        let mut rows_by_key: HashMap<&str, Vec<usize>> = HashMap::new();
+        keys.iter()
+            .enumerate()
+            .rev()
+            .for_each(|(row, key)| rows_by_key.entry(key.as_str()).or_default().push(row));
+        let old_to_new: Vec<Option<usize>> = self
+            .keys
+            .iter()
+            .map(|key| rows_by_key.get_mut(key.as_str()).and_then(|rows| rows.pop()))
+            .collect();
+        let removed = old_to_new.iter().filter(|row| row.is_none()).count();
+
+        if self.nodes.is_empty() || removed as f64 > REBUILD_FRACTION * self.nodes.len() as f64 {
+            debug!("Rebuilding HNSW graph over {} rows", keys.len());
+            self.nodes.clear();
+            self.entry_point = None;
+            self.insert_all(0..keys.len(), matrix);
+            self.keys = keys;
+            return;
+        }
+
+        if removed > 0 {
+            self.repair(&old_to_new, matrix);
+        }
+
+        let mut nodes = vec![Node::with_level(0); keys.len()];
+        let mut present = vec![false; keys.len()];
+        for (old, node) in std::mem::take(&mut self.nodes).into_iter().enumerate() {
+            if let Some(new) = old_to_new[old] {
+                nodes[new] = Node {
+                    neighbours: node
+                        .neighbours
+                        .into_iter()
+                        .map(|layer| {
+                            layer
+                                .into_iter()
+                                .filter_map(|n| old_to_new[n as usize].map(|row| row as u32))
+                                .collect()
+                        })
+                        .collect(),
+                };
+                present[new] = true;
+            }
+        }
+        self.nodes = nodes;
+        self.entry_point = self
+            .entry_point
+            .and_then(|entry| old_to_new[entry as usize])
+            .or_else(|| {
+                (0..self.nodes.len())
+                    .filter(|i| present[*i])
+                    .max_by_key(|i| self.nodes[*i].level())
+            })
+            .map(|entry| entry as u32);
+
+        let missing: Vec<usize> = (0..keys.len()).filter(|i| !present[*i]).collect();
+        debug!(
+            "Updating HNSW graph: {} removed, {} inserted",
+            removed,
+            missing.len()
+        );
+        self.insert_all(missing.into_iter(), matrix);
+        self.keys = keys;
+    }
+
+    /// Returns up to `k` nodes with the highest `score`, best first. `ef`
+    /// bounds the candidate list and trades speed for recall.
+    pub fn search(&self, score: impl Fn(usize) -> f32, k: usize, ef: usize) -> Vec<(usize, f32)> {
+        let entry = match self.entry_point {
+            Some(entry) => entry,
+            None => return Vec::new(),
+        };
+        let score = |n: u32| score(n as usize);
+        let mut entry_points = vec![Candidate {
+            similarity: score(entry),
+            node: entry,
+        }];
+        for layer in (1..=self.nodes[entry as usize].level()).rev() {
+            entry_points = self.search_layer(&score, &entry_points, 1, layer);
+        }
+        let mut found = self.search_layer(&score, &entry_points, ef.max(k), 0);
+        found.truncate(k);
+        found
+            .into_iter()
+            .map(|c| (c.node as usize, c.similarity))
+            .collect()
+    }
+
+    fn max_neighbours(&self, layer: usize) -> usize {
+        if layer == 0 {
+            2 * self.m
+        } else {
+            self.m
+        }
+    }
+
+    fn random_level(&mut self) -> usize {
+        // xorshift64*
+        self.rng ^= self.rng >> 12;
+        self.rng ^= self.rng << 25;
+        self.rng ^= self.rng >> 27;
+        let bits = self.rng.wrapping_mul(0x2545_f491_4f6c_dd1d) >> 11;
+        let uniform = (bits as f64 + 1.0) / (1u64 << 53) as f64;
+        let level = (-uniform.ln() / (self.m as f64).ln()).floor() as usize;
+        level.min(MAX_LEVEL)
+    }
+
+    fn insert_all(&mut self, rows: impl Iterator<Item = usize>, matrix: Matrix) {
+        if self.nodes.len() < matrix.len() {
+            self.nodes.resize(matrix.len(), Node::with_level(0));
+        }
+        for row in rows {
+            let level = self.random_level();
+            self.nodes[row] = Node::with_level(level);
+            self.insert(row as u32, matrix);
+        }
+    }
+
+    fn insert(&mut self, node: u32, matrix: Matrix) {
+        let entry = match self.entry_point {
+            Some(entry) => entry,
+            None => {
+                self.entry_point = Some(node);
+                return;
+            }
+        };
+        let level = self.nodes[node as usize].level();
+        let top = self.nodes[entry as usize].level();
+        let score = |n: u32| matrix.similarity(node as usize, n as usize);
+        let similarity = |a: u32, b: u32| matrix.similarity(a as usize, b as usize);
+
+        let mut entry_points = vec![Candidate {
+            similarity: score(entry),
+            node: entry,
+        }];
+        for layer in (level + 1..=top).rev() {
+            entry_points = self.search_layer(&score, &entry_points, 1, layer);
+        }
+        for layer in (0..=level.min(top)).rev() {
+            let found = self.search_layer(&score, &entry_points, self.ef_construction, layer);
+            let max = self.max_neighbours(layer);
+            let selected = select_neighbours(found.clone(), max, &similarity);
+            for &neighbour in &selected {
+                let links = &mut self.nodes[neighbour as usize].neighbours[layer];
+                links.push(node);
+                if links.len() > max {
+                    let candidates = links
+                        .iter()
+                        .map(|&n| Candidate {
+                            similarity: similarity(neighbour, n),
+                            node: n,
+                        })
+                        .collect();
+                    self.nodes[neighbour as usize].neighbours[layer] =
+                        select_neighbours(candidates, max, &similarity);
+                }
+            }
+            self.nodes[node as usize].neighbours[layer] = selected;
+            entry_points = found;
+        }
+        if level > top {
+            self.entry_point = Some(node);
+        }
+    }
+
+    /// Reconnects nodes that link to removed nodes, in old node numbering,
+    /// through the removed nodes' own neighbours
+    fn repair(&mut self, old_to_new: &[Option<usize>], matrix: Matrix) {
+        let similarity = |a: u32, b: u32| {
+            matrix.similarity(
+                old_to_new[a as usize].expect("only live nodes are compared"),
+                old_to_new[b as usize].expect("only live nodes are compared"),
+            )
+        };
+        let live = |n: &u32| old_to_new[*n as usize].is_some();
+        for (node, new) in old_to_new.iter().enumerate() {
+            if new.is_none() {
+                continue;
+            }
+            for layer in 0..self.nodes[node].neighbours.len() {
+                let links = &self.nodes[node].neighbours[layer];
+                if links.iter().all(live) {
+                    continue;
+                }
+                let mut candidates: HashSet<u32> = HashSet::new();
+                for &n in links {
+                    if live(&n) {
+                        candidates.insert(n);
+                    } else {
+                        candidates.extend(
+                            self.nodes[n as usize].neighbours[layer]
+                                .iter()
+                                .filter(|c| live(c) && **c as usize != node),
+                        );
+                    }
+                }
+                let candidates = candidates
+                    .into_iter()
+                    .map(|n| Candidate {
+                        similarity: similarity(node as u32, n),
+                        node: n,
+                    })
+                    .collect();
+                self.nodes[node].neighbours[layer] =
+                    select_neighbours(candidates, self.max_neighbours(layer), &similarity);
+            }
+        }
+    }
+
+    /// Best-first search of a single layer, returning up to `ef` candidates
+    /// sorted best first
+    fn search_layer(
+        &self,
+        score: &dyn Fn(u32) -> f32,
+        entry_points: &[Candidate],
+        ef: usize,
+        layer: usize,
+    ) -> Vec<Candidate> {
+        let mut visited: HashSet<u32> = entry_points.iter().map(|c| c.node).collect();
+        let mut candidates: BinaryHeap<Candidate> = entry_points.iter().cloned().collect();
+        let mut results: BinaryHeap<Reverse<Candidate>> =
+            entry_points.iter().cloned().map(Reverse).collect();
+        while results.len() > ef {
+            results.pop();
+        }
+
+        while let Some(candidate) = candidates.pop() {
+            let worst = results.peek().map(|r| r.0.similarity).unwrap_or(f32::NEG_INFINITY);
+            if results.len() >= ef && candidate.similarity < worst {
+                break;
+            }
+            let links = match self.nodes[candidate.node as usize].neighbours.get(layer) {
+                Some(links) => links,
+                None => continue,
+            };
+            for &neighbour in links {
+                if !visited.insert(neighbour) {
+                    continue;
+                }
+                let similarity = score(neighbour);
+                let worst = results.peek().map(|r| r.0.similarity).unwrap_or(f32::NEG_INFINITY);
+                if results.len() < ef || similarity > worst {
+                    let next = Candidate {
+                        similarity,
+                        node: neighbour,
+                    };
+                    candidates.push(next);
+                    results.push(Reverse(next));
+                    if results.len() > ef {
+                        results.pop();
+                    }
+                }
+            }
+        }
+
+        let mut results: Vec<Candidate> = results.into_iter().map(|r| r.0).collect();
+        results.sort_unstable_by(|a, b| b.cmp(a));
+        results
+    }
+
+    pub fn to_bytes(&self) -> Result<Vec<u8>> {
+        let keys = serde_json::to_vec(&self.keys).context("Failed to serialize HNSW keys")?;
+        let mut bytes = Vec::with_capacity(36 + keys.len() + self.nodes.len() * self.m * 8);
+        bytes.extend_from_slice(MAGIC);
+        bytes.extend_from_slice(&FORMAT_VERSION.to_le_bytes());
+        push_u32(&mut bytes, self.m)?;
+        push_u32(&mut bytes, self.ef_construction)?;
+        bytes.extend_from_slice(&self.rng.to_le_bytes());
+        bytes.extend_from_slice(&self.entry_point.unwrap_or(NO_ENTRY_POINT).to_le_bytes());
+        push_u32(&mut bytes, keys.len())?;
+        bytes.extend_from_slice(&keys);
+        push_u32(&mut bytes, self.nodes.len())?;
+        for node in &self.nodes {
+            push_u32(&mut bytes, node.neighbours.len())?;
+            for layer in &node.neighbours {
+                push_u32(&mut bytes, layer.len())?;
+                layer
+                    .iter()
+                    .for_each(|n| bytes.extend_from_slice(&n.to_le_bytes()));
+            }
+        }
+        Ok(bytes)
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        let mut reader = ByteReader::new(bytes, "HNSW graph");
+        if reader.take(MAGIC.len())? != MAGIC {
+            return Err(anyhow!("Not an HNSW graph file"));
+        }
+        let version = reader.read_u32()?;
+        if version != FORMAT_VERSION {
+            return Err(anyhow!("Unsupported HNSW graph format version {}", version));
+        }
+        let m = reader.read_u32()? as usize;
+        let ef_construction = reader.read_u32()? as usize;
+        let rng = reader.read_u64()?;
+        let entry_point = Some(reader.read_u32()?).filter(|e| *e != NO_ENTRY_POINT);
+        let keys: Vec<String> =
+            serde_json::from_slice(reader.read_block()?).context("Failed to deserialize HNSW keys")?;
+
+        let count = reader.read_u32()? as usize;
+        if count != keys.len() {
+            return Err(anyhow!("HNSW graph has {} nodes but {} keys", count, keys.len()));
+        }
+        let mut nodes = Vec::with_capacity(count);
+        for _ in 0..count {
+            let layers = reader.read_u32()? as usize;
+            if layers == 0 || layers > MAX_LEVEL + 1 {
+                return Err(anyhow!("HNSW node has invalid number of layers {}", layers));
+            }
+            let mut neighbours = Vec::with_capacity(layers);
+            for _ in 0..layers {
+                let len = reader.read_u32()? as usize;
+                let layer = (0..len)
+                    .map(|_| reader.read_u32())
+                    .collect::<Result<Vec<u32>>>()?;
+                if layer.iter().any(|n| *n as usize >= count) {
+                    return Err(anyhow!("HNSW node links to a node out of range"));
+                }
+                neighbours.push(layer);
+            }
+            nodes.push(Node { neighbours });
+        }
+        if entry_point.is_some_and(|e| e as usize >= count) {
+            return Err(anyhow!("HNSW entry point is out of range"));
+        }
+        reader.finish()?;
+
+        Ok(Self {
+            m,
+            ef_construction,
+            keys,
+            nodes,
+            entry_point,
+            rng,
+        })
+    }
+}
+
+/// Neighbour selection heuristic from the HNSW paper: prefer candidates that
+/// are closer to the base node than to any already selected neighbour, which
+/// keeps links spread out across clusters, then fill up with the best of the
+/// rest.
+fn select_neighbours(
+    mut candidates: Vec<Candidate>,
+    max: usize,
+    similarity: &dyn Fn(u32, u32) -> f32,
+) -> Vec<u32> {
+    candidates.sort_unstable_by(|a, b| b.cmp(a));
+    let mut selected: Vec<u32> = Vec::with_capacity(max);
+    let mut skipped: Vec<u32> = Vec::new();
+    for candidate in candidates {
+        if selected.len() >= max {
+            break;
+        }
+        if selected
+            .iter()
+            .all(|s| similarity(candidate.node, *s) < candidate.similarity)
+        {
+            selected.push(candidate.node);
+        } else {
+            skipped.push(candidate.node);
+        }
+    }
+    let remaining = max - selected.len();
+    selected.extend(skipped.into_iter().take(remaining));
+    selected
+}
+
+fn push_u32(bytes: &mut Vec<u8>, value: usize) -> Result<()> {
+    let value: u32 = value.try_into().context("HNSW graph is too large")?;
+    bytes.extend_from_slice(&value.to_le_bytes());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::index::{dot, normalize};
+
+    const DIMENSION: usize = 16;
+
+    fn vectors(n: usize, seed: u64) -> Vec<f32> {
+        let mut state = seed;
+        let mut data: Vec<f32> = (0..n * DIMENSION)
+            .map(|_| {
+                state = state.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+                ((state >> 33) as f32 / (1u64 << 31) as f32) - 0.5
+            })
+            .collect();
+        data.chunks_exact_mut(DIMENSION).for_each(normalize);
+        data
+    }
+
+    fn keys(range: std::ops::Range<usize>) -> Vec<String> {
+        range.map(|i| format!("key{}", i)).collect()
+    }
+
+    fn recall(graph: &Hnsw, data: &[f32], queries: &[f32], k: usize) -> f64 {
+        let matrix = Matrix::new(data, DIMENSION);
+        let mut hits = 0;
+        for query in queries.chunks_exact(DIMENSION) {
+            let score = |i: usize| dot(query, matrix.row(i));
+            let mut exact: Vec<(usize, f32)> = (0..matrix.len()).map(|i| (i, score(i))).collect();
+            exact.sort_unstable_by(|a, b| b.1.total_cmp(&a.1));
+            let expected: HashSet<usize> = exact.iter().take(k).map(|(i, _)| *i).collect();
+            hits += graph
+                .search(score, k, 64)
+                .iter()
+                .filter(|(i, _)| expected.contains(i))
+                .count();
+        }
+        hits as f64 / (k * queries.len() / DIMENSION) as f64
+    }
+
+    #[test]
+    fn empty_graph() {
+        let graph = Hnsw::default();
+
+        assert!(graph.search(|_| 0.0, 10, 10).is_empty());
+    }
+
+    #[test]
+    fn high_recall() {
+        let data = vectors(500, 1);
+        let mut graph = Hnsw::default();
+        graph.update(keys(0..500), Matrix::new(&data, DIMENSION));
+
+        assert!(recall(&graph, &data, &vectors(20, 2), 10) > 0.9);
+    }
+
+    #[test]
+    fn results_are_sorted() {
+        let data = vectors(100, 3);
+        let mut graph = Hnsw::default();
+        graph.update(keys(0..100), Matrix::new(&data, DIMENSION));
+        let query = vectors(1, 4);
+
+        let res = graph.search(|i| dot(&query, &data[i * DIMENSION..(i + 1) * DIMENSION]), 10, 32);
+
+        assert_eq!(res.len(), 10);
+        assert!(res.windows(2).all(|w| w[0].1 >= w[1].1));
+    }
+
+    #[test]
+    fn incremental_update() {
+        let data = vectors(400, 5);
+        let mut graph = Hnsw::default();
+        graph.update(keys(0..300), Matrix::new(&data[..300 * DIMENSION], DIMENSION));
+
+        // drop the first 50 rows and append 100 new ones
+        let updated = &data[50 * DIMENSION..];
+        graph.update(keys(50..400), Matrix::new(updated, DIMENSION));
+
+        assert_eq!(graph.len(), 350);
+        assert_eq!(graph.keys(), keys(50..400).as_slice());
+        assert!(graph
+            .nodes
+            .iter()
+            .flat_map(|n| n.neighbours.iter().flatten())
+            .all(|n| (*n as usize) < 350));
+        assert!(recall(&graph, updated, &vectors(20, 6), 10) > 0.9);
+    }
+
+    #[test]
+    fn rebuilds_after_large_removal() {
+        let data = vectors(200, 7);
+        let mut graph = Hnsw::default();
+        graph.update(keys(0..200), Matrix::new(&data, DIMENSION));
+
+        let updated = &data[150 * DIMENSION..];
+        graph.update(keys(150..200), Matrix::new(updated, DIMENSION));
+
+        assert_eq!(graph.len(), 50);
+        assert!(recall(&graph, updated, &vectors(10, 8), 5) > 0.9);
+    }
+
+    #[test]
+    fn roundtrip() {
+        let data = vectors(100, 9);
+        let mut graph = Hnsw::default();
+        graph.update(keys(0..100), Matrix::new(&data, DIMENSION));
+
+        let decoded = Hnsw::from_bytes(&graph.to_bytes().unwrap()).unwrap();
+
+        assert_eq!(decoded, graph);
+    }
+
+    #[test]
+    fn rejects_truncated() {
+        let data = vectors(10, 10);
+        let mut graph = Hnsw::default();
+        graph.update(keys(0..10), Matrix::new(&data, DIMENSION));
+        let bytes = graph.to_bytes().unwrap();
+
+        assert!(Hnsw::from_bytes(&bytes[..bytes.len() - 2]).is_err());
+    }
+}
@@ -0,0 +1,469 @@
+use std::cell::RefCell;
+use std::cmp::Ordering;
+use std::rc::Rc;
+
+use anyhow::{anyhow, Context, Result};
+use js_sys::JsString;
+use log::debug;
+use log::info;
+use log::warn;
+use serde::Deserialize;
+use serde::Serialize;
+use wasm_bindgen::prelude::*;
+
+use crate::embedding::EmbeddingPurpose;
+use crate::error::ErrorKind;
+use crate::error::SemanticSearchError;
+use crate::file_processor::FileProcessor;
+use crate::file_processor::EMBEDDING_FILE_PATH;
+use crate::hnsw::Hnsw;
+use crate::obsidian::semanticSearchSettings;
+use crate::obsidian::App;
+use crate::quantization::PreparedQuery;
+use crate::quantization::QuantizedMatrix;
+use crate::store::EmbeddingStore;
+use crate::Client;
+
+const MAX_SUGGESTIONS: usize = 10;
+/// Candidates per requested suggestion that are rescored at full precision
+const RESCORE_CANDIDATE_FACTOR: usize = 5;
+/// Below this many sections a brute-force scan is fast and exact
+const EXACT_SEARCH_THRESHOLD: usize = 2000;
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Suggestions {
+    name: String,
+    path: String,
+    header: String,
+}
+
+/// Section metadata kept alongside each vector in the index
+#[derive(Debug)]
+struct IndexEntry {
+    name: String,
+    path: String,
+    header: String,
+}
+
+/// How the index holds its vectors in memory
+#[derive(Debug)]
+enum Vectors {
+    Full(Vec<f32>),
+    /// Quantized codes scored in a first pass, optionally followed by
+    /// rescoring the best candidates against full precision vectors
+    Quantized {
+        matrix: QuantizedMatrix,
+        rescore: Option<Vec<f32>>,
+    },
+}
+
+/// Embeddings parsed and L2-normalized once, laid out contiguously so that
+/// cosine similarity against a normalized query reduces to a dot product.
+#[derive(Debug)]
+pub(crate) struct LoadedIndex {
+    entries: Vec<IndexEntry>,
+    vectors: Vectors,
+    graph: Option<Hnsw>,
+    dimension: usize,
+    /// Dimensions the index was requested at, which queries are embedded at
+    output_dimensions: Option<u32>,
+    source_mtime: Option<f64>,
+}
+
+impl LoadedIndex {
+    /// Full precision vectors of a quantized store are only kept in memory
+    /// when `rescore` is set. The graph is dropped if it was built for a
+    /// different set of sections than the store holds.
+    pub(crate) fn from_store(
+        store: EmbeddingStore,
+        graph: Option<Hnsw>,
+        rescore: bool,
+        source_mtime: Option<f64>,
+    ) -> Self {
+        let dimension = store.header.dimension;
+        let output_dimensions = store.header.output_dimensions;
+        let vectors = match store.quantized() {
+            Some(matrix) => Vectors::Quantized {
+                matrix: matrix.clone(),
+                rescore: store
+                    .full_precision_vectors()
+                    .filter(|_| rescore)
+                    .map(|_| store.normalized_vectors()),
+            },
+            None => Vectors::Full(store.normalized_vectors()),
+        };
+        let graph = graph.filter(|graph| {
+            let matches = graph.keys() == store.keys().as_slice();
+            if !matches {
+                info!("HNSW graph does not match the embedding index, using exact search");
+            }
+            matches
+        });
+        let entries = store
+            .sections
+            .into_iter()
+            .map(|section| IndexEntry {
+                name: section.name,
+                path: section.path,
+                header: section.header,
+            })
+            .collect();
+
+        Self {
+            entries,
+            vectors,
+            graph,
+            dimension,
+            output_dimensions,
+            source_mtime,
+        }
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Returns the `k` highest scoring sections for the query, best first.
+    /// Large indexes with a graph are searched approximately with candidate
+    /// list size `ef`, unless `ef` is 0.
+    pub(crate) fn rank(&self, query: &[f32], k: usize, ef: usize) -> Result<Vec<Suggestions>> {
+        if self.entries.is_empty() {
+            return Ok(Vec::new());
+        }
+        if query.len() != self.dimension {
+            return Err(SemanticSearchError::new(
+                ErrorKind::DimensionMismatch,
+                anyhow!(
+                    "Query embedding has {} dimensions but the index has {}",
+                    query.len(),
+                    self.dimension
+                ),
+            )
+            .into());
+        }
+        let mut query = query.to_vec();
+        normalize(&mut query);
+        let prepared = PreparedQuery::new(&query);
+
+        let rescore = match &self.vectors {
+            Vectors::Quantized {
+                rescore: Some(vectors),
+                ..
+            } => Some(vectors),
+            _ => None,
+        };
+        let num_candidates = if rescore.is_some() {
+            k * RESCORE_CANDIDATE_FACTOR
+        } else {
+            k
+        };
+
+        let score = |i: usize| match &self.vectors {
+            Vectors::Full(vectors) => dot(&query, self.row(vectors, i)),
+            Vectors::Quantized { matrix, .. } => matrix.score(&prepared, i),
+        };
+        let candidates = match &self.graph {
+            Some(graph) if ef > 0 && self.len() >= EXACT_SEARCH_THRESHOLD => {
+                graph.search(score, num_candidates, ef)
+            }
+            _ => top_k((0..self.len()).map(|i| (i, score(i))).collect(), num_candidates),
+        };
+        let ranked = match rescore {
+            Some(vectors) => top_k(
+                candidates
+                    .into_iter()
+                    .map(|(i, _)| (i, dot(&query, self.row(vectors, i))))
+                    .collect(),
+                k,
+            ),
+            None => candidates,
+        };
+
+        Ok(ranked
+            .into_iter()
+            .map(|(i, _)| Suggestions {
+                name: self.entries[i].name.to_string(),
+                path: self.entries[i].path.to_string(),
+                header: self.entries[i].header.to_string(),
+            })
+            .collect())
+    }
+
+    fn row<'a>(&self, vectors: &'a [f32], i: usize) -> &'a [f32] {
+        &vectors[i * self.dimension..(i + 1) * self.dimension]
+    }
+}
+
+/// Keeps the `k` highest scores, sorted best first
+fn top_k(mut scored: Vec<(usize, f32)>, k: usize) -> Vec<(usize, f32)> {
+    let by_score_desc = |a: &(usize, f32), b: &(usize, f32)| {
+        b.1.partial_cmp(&a.1).unwrap_or(Ordering::Equal)
+    };
+    let k = k.min(scored.len());
+    if k < scored.len() {
+        scored.select_nth_unstable_by(k, by_score_desc);
+        scored.truncate(k);
+    }
+    scored.sort_unstable_by(by_score_desc);
+    scored
+}
+
+pub(crate) fn dot(a: &[f32], b: &[f32]) -> f32 {
+    a.iter().zip(b).map(|(x, y)| x * y).sum()
+}
+
+pub(crate) fn normalize(vector: &mut [f32]) {
+    let norm = dot(vector, vector).sqrt();
+    if norm > 0.0 {
+        vector.iter_mut().for_each(|x| *x /= norm);
+    }
+}
+
+/// Long-lived handle to the vault's embeddings. The embedding file is parsed
+/// on the first query and kept in memory until it changes on disk or
+/// `invalidate` is called.
+#[wasm_bindgen]
+pub struct EmbeddingIndex {
+    file_processor: FileProcessor,
+    settings: semanticSearchSettings,
+    loaded: RefCell<Option<Rc<LoadedIndex>>>,
+}
+
+#[wasm_bindgen]
+impl EmbeddingIndex {
+    #[wasm_bindgen(constructor)]
+    pub fn new(app: App, settings: semanticSearchSettings) -> EmbeddingIndex {
+        EmbeddingIndex {
+            file_processor: FileProcessor::new(app.vault()),
+            settings,
+            loaded: RefCell::new(None),
+        }
+    }
+
+    /// Drops the in-memory index so that the next query reloads it from disk
+    pub fn invalidate(&self) {
+        self.loaded.replace(None);
+    }
+
+    pub fn is_loaded(&self) -> bool {
+        self.loaded.borrow().is_some()
+    }
+
+    pub async fn get_suggestions(&self, query: JsString) -> Result<JsValue, SemanticSearchError> {
+        let query_string = match query.as_string() {
+            Some(s) => s,
+            None => {
+                return Err(anyhow!("Input to get_suggestions could not be parsed into a String").into())
+            }
+        };
+        let ranked_suggestions = self.get_similarity(query_string).await?;
+        serde_wasm_bindgen::to_value(&ranked_suggestions)
+            .map_err(|e| anyhow!("Failed to serialize suggestions: {}", e).into())
+    }
+}
+
+impl EmbeddingIndex {
+    async fn get_similarity(&self, query: String) -> Result<Vec<Suggestions>, SemanticSearchError> {
+        let index = self.load().await?;
+        let client = Client::new(&self.settings)?.with_dimensions(index.output_dimensions);
+        let response: Vec<Vec<f32>> = client
+            .get_embedding(query.into(), EmbeddingPurpose::Query)
+            .await?;
+        info!("Sucessfully obtained {} embeddings", response.len());
+        let query_embedding = response
+            .first()
+            .context("Embedding response for query was empty")?;
+        Ok(index.rank(
+            query_embedding,
+            MAX_SUGGESTIONS,
+            self.settings.hnswEfSearch() as usize,
+        )?)
+    }
+
+    async fn load(&self) -> Result<Rc<LoadedIndex>, SemanticSearchError> {
+        let mtime = self.file_processor.get_file_mtime(EMBEDDING_FILE_PATH);
+        if let Some(index) = self.loaded.borrow().as_ref() {
+            if index.source_mtime == mtime {
+                return Ok(Rc::clone(index));
+            }
+            debug!("{} changed on disk, reloading index", EMBEDDING_FILE_PATH);
+        }
+
+        let client = Client::new(&self.settings)?;
+        let store = self
+            .file_processor
+            .read_index(&client.index_header())
+            .await?
+            .ok_or_else(|| {
+                SemanticSearchError::new(ErrorKind::IndexMissing, anyhow!("No embeddings found"))
+            })?;
+        // Queries embedded differently from the notes rank them meaninglessly.
+        // Only the dimensions are adapted, by embedding queries to match.
+        let template = client
+            .with_dimensions(store.header.output_dimensions)
+            .index_header();
+        if let Some(mismatch) = store.header.mismatch(&template) {
+            return Err(SemanticSearchError::new(
+                ErrorKind::IndexMismatch,
+                anyhow!("{} was embedded with {}", EMBEDDING_FILE_PATH, mismatch),
+            ));
+        }
+        let graph = match self.file_processor.read_hnsw().await {
+            Ok(graph) => graph,
+            Err(e) => {
+                warn!("Failed to read HNSW graph, using exact search: {:?}", e);
+                None
+            }
+        };
+        // migrating a legacy embedding file creates the index, changing its mtime
+        let mtime = self.file_processor.get_file_mtime(EMBEDDING_FILE_PATH);
+        let index = Rc::new(LoadedIndex::from_store(
+            store,
+            graph,
+            self.settings.quantizationRescore(),
+            mtime,
+        ));
+        info!("Loaded {} embeddings into memory", index.len());
+        self.loaded.replace(Some(Rc::clone(&index)));
+        Ok(index)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::file_processor::EmbeddingRow;
+    use crate::hnsw::Matrix;
+    use crate::quantization::Quantization;
+    use crate::store::IndexHeader;
+
+    fn row(name: &str, embedding: Vec<f32>) -> EmbeddingRow {
+        EmbeddingRow {
+            name: name.to_string(),
+            path: name.to_string(),
+            mtime: "0".to_string(),
+            section_id: name.to_string(),
+            header: name.to_string(),
+            hash: name.to_string(),
+            embedding,
+            quantized: None,
+        }
+    }
+
+    fn index(rows: Vec<EmbeddingRow>) -> LoadedIndex {
+        let header = IndexHeader::new("model", "Ollama", 0, 0.0);
+        LoadedIndex::from_store(EmbeddingStore::from_rows(header, rows).unwrap(), None, false, None)
+    }
+
+    fn quantized_index(rows: Vec<EmbeddingRow>, quantization: Quantization, rescore: bool) -> LoadedIndex {
+        let mut header = IndexHeader::new("model", "Ollama", 0, 0.0);
+        header.quantization = quantization;
+        header.full_precision = rescore;
+        LoadedIndex::from_store(EmbeddingStore::from_rows(header, rows).unwrap(), None, rescore, None)
+    }
+
+    #[test]
+    fn ranks_by_cosine_similarity() {
+        let index = index(vec![
+            row("a", vec![1.0, 0.0]),
+            row("b", vec![0.0, 1.0]),
+            row("c", vec![3.0, 3.0]),
+        ]);
+
+        let res = index.rank(&[0.0, 2.0], 10, 0).unwrap();
+
+        let names: Vec<&str> = res.iter().map(|s| s.name.as_str()).collect();
+        assert_eq!(names, vec!["b", "c", "a"]);
+    }
+
+    #[test]
+    fn truncates_to_k() {
+        let index = index(vec![
+            row("a", vec![1.0, 0.0]),
+            row("b", vec![0.0, 1.0]),
+            row("c", vec![1.0, 1.0]),
+        ]);
+
+        let res = index.rank(&[1.0, 0.1], 2, 0).unwrap();
+
+        assert_eq!(res.len(), 2);
+        assert_eq!(res[0].name, "a");
+        assert_eq!(res[1].name, "c");
+    }
+
+    #[test]
+    fn rejects_mismatched_query() {
+        let index = index(vec![row("a", vec![1.0, 0.0])]);
+
+        assert!(index.rank(&[1.0, 0.0, 0.0], 10, 0).is_err());
+    }
+
+    #[test]
+    fn ranks_quantized() {
+        for quantization in [Quantization::Int8, Quantization::Binary] {
+            for rescore in [true, false] {
+                let index = quantized_index(
+                    vec![
+                        row("a", vec![1.0, 0.0, 0.2, -0.5]),
+                        row("b", vec![0.0, 1.0, -0.3, 0.4]),
+                        row("c", vec![-1.0, 0.1, 0.9, 0.0]),
+                    ],
+                    quantization,
+                    rescore,
+                );
+
+                let res = index.rank(&[0.1, 0.9, -0.2, 0.5], 1, 0).unwrap();
+
+                assert_eq!(res[0].name, "b");
+            }
+        }
+    }
+
+    #[test]
+    fn rescoring_restores_exact_order() {
+        let rows = vec![
+            row("a", vec![0.9, 0.1, 0.0, 0.0]),
+            row("b", vec![0.8, 0.2, 0.0, 0.0]),
+            row("c", vec![0.0, 0.0, 1.0, 0.0]),
+        ];
+        let index = quantized_index(rows, Quantization::Binary, true);
+
+        let res = index.rank(&[0.8, 0.2, 0.0, 0.0], 2, 0).unwrap();
+
+        let names: Vec<&str> = res.iter().map(|s| s.name.as_str()).collect();
+        assert_eq!(names, vec!["b", "a"]);
+    }
+
+    #[test]
+    fn drops_mismatched_graph() {
+        let header = IndexHeader::new("model", "Ollama", 0, 0.0);
+        let store = EmbeddingStore::from_rows(header, vec![row("a", vec![1.0, 0.0])]).unwrap();
+        let mut graph = Hnsw::default();
+        graph.update(vec!["other".to_string()], Matrix::new(&[1.0, 0.0], 2));
+
+        let index = LoadedIndex::from_store(store, Some(graph), false, None);
+
+        assert!(index.graph.is_none());
+    }
+
+    #[test]
+    fn searches_large_index_with_graph() {
+        let rows: Vec<EmbeddingRow> = (0..EXACT_SEARCH_THRESHOLD)
+            .map(|i| {
+                let angle = i as f32 / EXACT_SEARCH_THRESHOLD as f32 * std::f32::consts::PI;
+                row(&i.to_string(), vec![angle.cos(), angle.sin()])
+            })
+            .collect();
+        let header = IndexHeader::new("model", "Ollama", 0, 0.0);
+        let store = EmbeddingStore::from_rows(header, rows).unwrap();
+        let vectors = store.normalized_vectors();
+        let mut graph = Hnsw::default();
+        graph.update(store.keys(), Matrix::new(&vectors, 2));
+        let index = LoadedIndex::from_store(store, Some(graph), false, None);
+
+        let res = index.rank(&[0.0, 1.0], 1, 64).unwrap();
+
+        assert!(index.graph.is_some());
+        assert_eq!(res[0].name, (EXACT_SEARCH_THRESHOLD / 2).to_string());
+    }
+}
@@ -0,0 +1,59 @@
+use std::cell::Cell;
+
+use crate::retry;
+
+/// How often a command waiting for the lock checks whether it was released
+const POLL_MS: f64 = 250.0;
+
+thread_local! {
+    static LOCKED: Cell<bool> = const { Cell::new(false) };
+}
+
+/// Held while a command reads, modifies and writes embedding.bin, so that
+/// 'Generate Embeddings' and live indexing do not discard each other's
+/// updates. Released when dropped.
+#[derive(Debug)]
+pub struct IndexLock(());
+
+impl IndexLock {
+    /// The lock, or `None` if another command holds it
+    pub fn try_acquire() -> Option<Self> {
+        LOCKED.with(|locked| {
+            if locked.replace(true) {
+                None
+            } else {
+                Some(IndexLock(()))
+            }
+        })
+    }
+
+    /// Waits for the command holding the lock to release it
+    pub async fn acquire() -> Self {
+        loop {
+            if let Some(lock) = Self::try_acquire() {
+                return lock;
+            }
+            retry::sleep(POLL_MS).await;
+        }
+    }
+}
+
+impl Drop for IndexLock {
+    fn drop(&mut self) {
+        LOCKED.with(|locked| locked.set(false));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn is_held_until_dropped() {
+        let lock = IndexLock::try_acquire().unwrap();
+
+        assert!(IndexLock::try_acquire().is_none());
+        drop(lock);
+        assert!(IndexLock::try_acquire().is_some());
+    }
+}
@@ -1,48 +1,104 @@
+mod byte_reader;
+mod cache;
+mod concurrency;
 mod embedding;
 mod error;
 mod file_processor;
 mod generate_input;
+mod hash;
+mod hnsw;
+mod index;
+mod index_lock;
+mod live_index;
 mod obsidian;
+mod progress;
+mod provider;
+mod quantization;
+mod rate_limit;
+mod retry;
+mod store;
 
 extern crate console_error_panic_hook;
 
-use crate::embedding::EmbeddingRequestBuilder;
+use crate::cache::EmbeddingCache;
 use crate::file_processor::EmbeddingRow;
+use crate::file_processor::CACHE_FILE_PATH;
 use crate::file_processor::EMBEDDING_FILE_PATH;
+use crate::file_processor::InputRow;
+use crate::file_processor::LEGACY_EMBEDDING_FILE_PATH;
+use crate::file_processor::ModifiedInput;
+use crate::file_processor::STAGING_FOLDER_PATH;
+use crate::generate_input::SectionReader;
+use crate::store::EmbeddingStore;
+use crate::store::IndexHeader;
 use crate::obsidian::Notice;
+use crate::progress::Phase;
+use crate::progress::Progress;
+use crate::progress::ProgressReporter;
+use crate::quantization::Quantization;
+use std::convert::TryFrom;
+use std::cell::Cell;
+use std::cell::RefCell;
 use std::panic;
+use std::rc::Rc;
 
 use anyhow::anyhow;
-use anyhow::Context;
 use anyhow::Result;
-use embedding::EmbeddingRequest;
-use embedding::SupportedAPIs;
+use provider::EmbeddingProvider;
+use provider::Endpoint;
+use error::ErrorKind;
 use error::SemanticSearchError;
-use error::WrappedError;
 use file_processor::FileProcessor;
-use js_sys::JsString;
+use futures_util::future;
+use futures_util::future::Either;
+use futures_util::FutureExt;
 use log::debug;
+use log::error;
 use log::info;
-use ndarray::Array1;
+use log::warn;
 use obsidian::semanticSearchSettings;
 use obsidian::App;
-use serde::Deserialize;
-use serde::Serialize;
 use tiktoken_rs::cl100k_base;
+use tiktoken_rs::cl100k_base_singleton;
+use js_sys::Function;
 use wasm_bindgen::prelude::*;
+use web_sys::AbortSignal;
 
+use crate::concurrency::BoundedTasks;
 use crate::embedding::EmbeddingInput;
+use crate::embedding::EmbeddingPurpose;
+use crate::embedding::PrefixTemplates;
+use crate::hash::content_hash;
+use crate::index::normalize;
+use crate::index_lock::IndexLock;
+use crate::rate_limit::RateLimiter;
+use crate::retry::RetryPolicy;
+
+/// How often progress is refreshed while no batch completes
+const PROGRESS_INTERVAL_MS: f64 = 1000.0;
 
 #[wasm_bindgen]
 pub struct GenerateEmbeddingsCommand {
     file_processor: FileProcessor,
+    sections: SectionReader,
+    /// Sections read for the estimates shown before embedding, so that the
+    /// vault is read once for all of them
+    estimated_input: RefCell<Option<Vec<InputRow>>>,
     client: Client,
-    num_batches: u32,
+    quantization: Quantization,
+    full_precision: bool,
 }
 
 #[wasm_bindgen]
 pub struct NumModifiedResponse {
+    /// Sections that need embedding, or -1 if there is no index yet
     pub nfiles: i64,
+    pub added: u32,
+    pub updated: u32,
+    pub removed: u32,
+    pub moved: u32,
+    /// Sections already embedded by an unfinished run
+    pub resumed: u32,
 }
 
 #[wasm_bindgen]
@@ -50,105 +106,195 @@ pub struct CostEstimateResponse {
     pub cost: f32,
 }
 
+#[wasm_bindgen]
+pub struct TimeEstimateResponse {
+    /// Time the rate limits add to embedding the modified sections
+    pub seconds: f64,
+}
+
 #[wasm_bindgen]
 impl GenerateEmbeddingsCommand {
     #[wasm_bindgen(constructor)]
-    pub fn new(app: App, settings: &semanticSearchSettings) -> GenerateEmbeddingsCommand {
+    pub fn new(
+        app: App,
+        settings: &semanticSearchSettings,
+    ) -> Result<GenerateEmbeddingsCommand, SemanticSearchError> {
         let file_processor = FileProcessor::new(app.vault());
-        let client = Client::new(settings);
-        let num_batches = settings.numBatches();
-        GenerateEmbeddingsCommand {
+        let sections = SectionReader::new(settings);
+        let client = Client::new(settings)?;
+        let quantization = settings.quantization().into();
+        let full_precision = settings.quantizationRescore();
+        Ok(GenerateEmbeddingsCommand {
             file_processor,
+            sections,
+            estimated_input: RefCell::new(None),
             client,
-            num_batches,
-        }
+            quantization,
+            full_precision,
+        })
     }
 
-    pub async fn get_embeddings(&self) -> Result<(), SemanticSearchError> {
-        let (_, modified_input, mut reusable_embeddings) =
-            self.file_processor.read_modified_input().await?;
-        self.file_processor.delete_embeddings().await?;
-
-        let mut num_processed = 0;
-        let num_batches = self.num_batches;
-        let mut batch = 1;
-        let num_records = modified_input.len();
-        info!("Found {} records.", num_records);
-        let batch_size = (num_records as f64 / num_batches as f64).ceil() as usize;
-        let mut with_headers = true;
-
-        while num_processed < num_records {
-            let num_to_process = if batch == num_batches {
-                num_records - num_processed
-            } else {
-                batch_size
-            };
-
-            let records = &modified_input[num_processed..num_processed + num_to_process].to_vec();
-            debug!(
-                "Processing batch {}: {} to {}",
-                batch,
-                num_processed,
-                num_processed + num_to_process
-            );
-
-            let response: Vec<Vec<f32>> = self.client.get_embedding(records.into()).await?;
-            info!("Sucessfully obtained {} embeddings", response.len());
-
-            if records.len() != response.len() {
-                return Err(SemanticSearchError(anyhow!(
-                    "Requested for {} embeddings but got {}",
-                    records.len(),
-                    response.len()
-                )));
+    /// Reads the vault's notes, embeds the modified sections and updates the
+    /// index. `on_progress` is called with a `Progress` as notes are read and
+    /// batches complete, and once `signal` is aborted no further batches are
+    /// started; those in flight are completed and checkpointed for the next
+    /// run.
+    pub async fn get_embeddings(
+        &self,
+        on_progress: Option<Function>,
+        signal: Option<AbortSignal>,
+    ) -> Result<(), SemanticSearchError> {
+        let reporter = ProgressReporter::new(on_progress, signal);
+        let _lock = match IndexLock::try_acquire() {
+            Some(lock) => lock,
+            None => {
+                info!("Waiting for live indexing to finish");
+                IndexLock::acquire().await
             }
-
-            let mut embedding_rows: Vec<EmbeddingRow> = Vec::with_capacity(num_to_process);
-
-            records.into_iter().enumerate().for_each(|(i, record)| {
-                let embedding = response
-                    .get(i)
-                    .map(|res| {
-                        res.clone()
-                            .into_iter()
-                            .map(|f| f.to_string())
-                            .collect::<Vec<String>>()
-                            .join(",")
-                    })
-                    .expect("Length of records and response data should be aligned");
-
-                embedding_rows.push(EmbeddingRow {
-                    name: record.name.to_string(),
-                    mtime: record.mtime.to_string(),
-                    header: record.section.to_string(),
-                    embedding,
-                });
-            });
-
-            embedding_rows.append(&mut reusable_embeddings);
-            self.file_processor
-                .write_embedding_csv(embedding_rows, with_headers)
-                .await?;
-
-            num_processed += num_to_process;
-            batch += 1;
-            with_headers = false;
+        };
+        let mut header = self.client.index_header();
+        header.quantization = self.quantization;
+        header.full_precision = self.full_precision;
+        let input = match self
+            .sections
+            .read_vault(&self.file_processor, &reporter)
+            .await?
+        {
+            Some(input) => input,
+            None => {
+                return Err(SemanticSearchError::new(
+                    ErrorKind::Aborted,
+                    anyhow!("Stopped while reading notes"),
+                ))
+            }
+        };
+        let modified = self.file_processor.modified_input(input, &header).await?;
+        info!("Changes since the last index: {:?}", modified.changes);
+        info!("Found {} records.", modified.input.len());
+
+        let mut embedding_rows: Vec<EmbeddingRow> = modified.reusable;
+        // Each completed batch is staged in a file of its own, so that a run
+        // failing part way resumes from its completed batches
+        let staging_header = self.client.index_header();
+        let run = js_sys::Date::now() as u64;
+        self.client.load_cache(&self.file_processor).await;
+        let batches = self.client.batches(&modified.input);
+        let num_batches = batches.len();
+        // Batches complete in any order, but are added to the index in input
+        // order. A failed batch stops new ones from starting, while those in
+        // flight are still staged. An abort does the same.
+        let mut embedded: Vec<Option<Vec<EmbeddingRow>>> = vec![None; num_batches];
+        let mut failure = None;
+        let mut progress = Progress::new(Phase::Embedding);
+        progress.sections_total = modified.input.len();
+        progress.batches_remaining = num_batches;
+        reporter.report(&progress);
+        let mut tasks = self.client.embed_batches(&batches);
+        let aborted = reporter.aborted().fuse();
+        futures_util::pin_mut!(aborted);
+        let mut stopping = false;
+        loop {
+            if stopping {
+                tasks.stop();
+            }
+            // Wakes up every second to show how long the rate limits hold
+            // requests back
+            let tick = Box::pin(retry::sleep(PROGRESS_INTERVAL_MS));
+            let (batch, result) = match future::select(
+                Box::pin(tasks.next()),
+                future::select(aborted.as_mut(), tick),
+            )
+            .await
+            {
+                Either::Left((Some(next), _)) => next,
+                Either::Left((None, _)) => break,
+                Either::Right((Either::Left(_), _)) => {
+                    info!("Stopping after the batches in flight");
+                    stopping = true;
+                    continue;
+                }
+                Either::Right((Either::Right(_), _)) => {
+                    let wait = self.client.rate_limit_wait_seconds(js_sys::Date::now());
+                    if wait != progress.rate_limit_wait_seconds {
+                        progress.rate_limit_wait_seconds = wait;
+                        reporter.report(&progress);
+                    }
+                    continue;
+                }
+            };
+            progress.batches_remaining -= 1;
+            let rows = match result {
+                Ok(rows) => rows,
+                Err(e) => {
+                    error!("Failed to embed batch {} of {}: {:?}", batch + 1, num_batches, e);
+                    failure.get_or_insert(e);
+                    continue;
+                }
+            };
+            debug!("Embedded batch {}: {} records", batch + 1, rows.len());
+            progress.sections_embedded += rows.len();
+            progress.estimated_cost += estimate_cost(batches[batch]);
+            progress.rate_limit_wait_seconds =
+                self.client.rate_limit_wait_seconds(js_sys::Date::now());
+            reporter.report(&progress);
+            let staged = EmbeddingStore::from_rows(staging_header.clone(), rows.clone())?;
+            self.file_processor.write_staging_batch(run, batch, &staged).await?;
+            embedded[batch] = Some(rows);
+            info!("Staged batch {} of {} in {}", batch + 1, num_batches, STAGING_FOLDER_PATH);
         }
-
-        info!("Saved embeddings to {}", EMBEDDING_FILE_PATH);
+        self.client.save_cache(&self.file_processor).await;
+        if let Some(e) = failure {
+            return Err(e);
+        }
+        if embedded.iter().any(Option::is_none) {
+            let completed = embedded.iter().flatten().count();
+            info!("Stopped after {} of {} batches", completed, num_batches);
+            return Err(SemanticSearchError::new(
+                ErrorKind::Aborted,
+                anyhow!("Stopped after {} of {} batches", completed, num_batches),
+            ));
+        }
+        embedding_rows.extend(embedded.into_iter().flatten().flatten());
+
+        progress.phase = Phase::Saving;
+        reporter.report(&progress);
+        let store = EmbeddingStore::from_rows(header, embedding_rows)?;
+        self.file_processor.save_index(&store).await?;
+        self.file_processor.delete_staging().await?;
+        info!("Saved {} embeddings to {}", store.len(), EMBEDDING_FILE_PATH);
+        progress.phase = Phase::Done;
+        reporter.report(&progress);
         Ok(())
     }
 
     pub async fn get_input_n_modified(&self) -> Result<NumModifiedResponse, SemanticSearchError> {
-        let (n_modified, _, _) = self.file_processor.read_modified_input().await?;
-        Ok(NumModifiedResponse { nfiles: n_modified })
+        let modified = self.estimated_modified_input().await?;
+        let count = |n: usize| u32::try_from(n).expect("Too many sections");
+        Ok(NumModifiedResponse {
+            nfiles: if modified.indexed {
+                count(modified.input.len()).into()
+            } else {
+                -1
+            },
+            added: count(modified.changes.added),
+            updated: count(modified.changes.updated),
+            removed: count(modified.changes.removed),
+            moved: count(modified.changes.moved),
+            resumed: count(
+                modified
+                    .reusable
+                    .iter()
+                    .filter(|row| modified.staged_hashes.contains(&row.hash))
+                    .count(),
+            ),
+        })
     }
 
     pub async fn get_input_cost_estimate(
         &self,
     ) -> Result<CostEstimateResponse, SemanticSearchError> {
-        let (_, input, _) = self.file_processor.read_modified_input().await?;
-        let string_records = input.into_iter().fold(String::new(), |mut acc, x| {
+        let modified = self.estimated_modified_input().await?;
+        let string_records = modified.input.into_iter().fold(String::new(), |mut acc, x| {
             acc.push_str(&x.body);
             acc
         });
@@ -156,107 +302,114 @@ impl GenerateEmbeddingsCommand {
         Ok(CostEstimateResponse { cost: estimate })
     }
 
+    pub async fn get_input_time_estimate(
+        &self,
+    ) -> Result<TimeEstimateResponse, SemanticSearchError> {
+        let modified = self.estimated_modified_input().await?;
+        let seconds = self.client.rate_limit_estimate_ms(&modified.input) / 1000.0;
+        Ok(TimeEstimateResponse { seconds })
+    }
+
     pub async fn check_embedding_file_exists(&self) -> bool {
-        return self
-            .file_processor
+        self.file_processor
             .check_file_exists_at_path(EMBEDDING_FILE_PATH)
-            .await;
+            .await
+            || self
+                .file_processor
+                .check_file_exists_at_path(LEGACY_EMBEDDING_FILE_PATH)
+                .await
     }
 }
 
-#[wasm_bindgen]
-pub struct QueryCommand {
-    file_processor: FileProcessor,
-    client: Client,
-}
-
-#[wasm_bindgen]
-impl QueryCommand {
-    async fn get_similarity(&self, query: String) -> Result<Vec<Suggestions>, SemanticSearchError> {
-        struct Embedding<'a> {
-            row: &'a EmbeddingRow,
-            score: f32,
-        }
-        let rows = self.file_processor.read_embedding_csv().await?;
-        let response: Vec<Vec<f32>> = self.client.get_embedding(query.into()).await?;
-        info!("Sucessfully obtained {} embeddings", response.len());
-        let query_embedding = Array1::from_vec(response[0].clone());
-
-        let mut embeddings: Vec<Embedding> = Vec::with_capacity(rows.len());
-        for row in &rows {
-            let deserialized = deserialize_embeddings(&row.embedding).with_context(|| format!("Failed to deserialize embedding for file: {} and section: {} with embedding: {}", &row.name, &row.header, &row.embedding))?;
-            embeddings.push(Embedding {
-                score: cosine_similarity(&query_embedding, deserialized),
-                row: &row,
-            });
-        }
-
-        embeddings.sort_unstable_by(|row1: &Embedding, row2: &Embedding| {
-            row1.score
-                .partial_cmp(&row2.score)
-                .expect("scores should be comparable")
-        });
-        embeddings.reverse();
-        let ranked = embeddings
-            .iter()
-            .map(|e| Suggestions {
-                name: e.row.name.to_string(),
-                header: e.row.header.to_string(),
-            })
-            .collect();
-        Ok(ranked)
+impl GenerateEmbeddingsCommand {
+    /// The sections the estimates are based on, reading the vault's notes on
+    /// first use
+    async fn estimated_modified_input(&self) -> Result<ModifiedInput, SemanticSearchError> {
+        let cached = self.estimated_input.borrow().clone();
+        let input = match cached {
+            Some(input) => input,
+            None => {
+                let input = self
+                    .sections
+                    .read_vault(&self.file_processor, &ProgressReporter::default())
+                    .await?
+                    .expect("reading without an abort signal finishes");
+                self.estimated_input.replace(Some(input.clone()));
+                input
+            }
+        };
+        Ok(self
+            .file_processor
+            .modified_input(input, &self.client.index_header())
+            .await?)
     }
 }
 
-fn deserialize_embeddings(embedding: &str) -> Result<Vec<f32>> {
-    embedding
-        .split(",")
-        .map(|s| {
-            s.parse::<f32>()
-                .context("Embedding should be comma-separated list of f32")
-        })
-        .collect()
+/// Flat rate in dollars the cost estimates assume
+const TOKEN_COST: f32 = 0.0004 / 1000.0;
+
+#[wasm_bindgen]
+pub fn get_query_cost_estimate(query: &str) -> f32 {
+	// TODO: parametrize the tokenizer
+    let tokens = cl100k_base().expect("Failed to init tokenizer").encode_with_special_tokens(query);
+    let tokens_length = tokens.len() as f32;
+    TOKEN_COST * tokens_length
 }
 
-fn cosine_similarity(a1: &Array1<f32>, right: Vec<f32>) -> f32 {
-    let a2 = Array1::from_vec(right);
-    a1.dot(&a2) / a1.dot(a1).sqrt() * a2.dot(&a2).sqrt()
+/// Estimated cost of embedding the bodies of `records`
+fn estimate_cost(records: &[InputRow]) -> f32 {
+    let tokens: usize = count_tokens(records.iter().map(|row| row.body.as_str()))
+        .iter()
+        .sum();
+    TOKEN_COST * tokens as f32
 }
 
-#[derive(Deserialize, Serialize)]
-pub struct Suggestions {
-    name: String,
-    header: String,
+/// Token counts of `texts` under cl100k_base, which only approximates the
+/// tokenizers of models other than OpenAI's
+fn count_tokens<'a>(texts: impl Iterator<Item = &'a str>) -> Vec<usize> {
+    let bpe = cl100k_base_singleton();
+    let bpe = bpe.lock();
+    texts
+        .map(|text| bpe.encode_with_special_tokens(text).len())
+        .collect()
 }
 
-#[wasm_bindgen]
-pub async fn get_suggestions(
-    app: &obsidian::App,
-    settings: &obsidian::semanticSearchSettings,
-    query: JsString,
-) -> Result<JsValue, JsError> {
-    let query_string = match query.as_string() {
-		Some(s) => s,
-		None => return Err(JsError::new("Input to get_suggestions could not be parsed into a String")),
-	};
-    let file_processor = FileProcessor::new(app.vault());
-    let client = Client::new(settings);
-    let query_cmd = QueryCommand {
-        file_processor,
-        client,
-    };
-    let mut ranked_suggestions = query_cmd.get_similarity(query_string).await?;
-    ranked_suggestions.truncate(10);
-    Ok(serde_wasm_bindgen::to_value(&ranked_suggestions)?)
+/// Packs consecutive rows of `input` into batches of at most `max_items` rows
+/// and `max_tokens` tokens, where `tokens[i]` is the token count of
+/// `input[i]`. A row over the token budget by itself is sent alone.
+fn batches<'a>(
+    input: &'a [InputRow],
+    tokens: &[usize],
+    max_items: usize,
+    max_tokens: usize,
+) -> Vec<&'a [InputRow]> {
+    let mut batches = Vec::new();
+    let mut start = 0;
+    let mut batch_tokens: usize = 0;
+    for (i, row_tokens) in tokens.iter().enumerate() {
+        if i > start
+            && (i - start >= max_items || batch_tokens.saturating_add(*row_tokens) > max_tokens)
+        {
+            batches.push(&input[start..i]);
+            start = i;
+            batch_tokens = 0;
+        }
+        batch_tokens = batch_tokens.saturating_add(*row_tokens);
+    }
+    if start < input.len() {
+        batches.push(&input[start..]);
+    }
+    batches
 }
 
-#[wasm_bindgen]
-pub fn get_query_cost_estimate(query: &str) -> f32 {
-    const TOKEN_COST: f32 = 0.0004 / 1000.0;
-	// TODO: parametrize the tokenizer
-    let tokens = cl100k_base().expect("Failed to init tokenizer").encode_with_special_tokens(query);
-    let tokens_length = tokens.len() as f32;
-    return TOKEN_COST * tokens_length;
+/// Shortens an embedding longer than `dimensions`, for models trained so that
+/// the leading dimensions of an embedding are an embedding themselves. Kept
+/// unit length, since the leading dimensions alone are shorter.
+fn truncate_embedding(embedding: &mut Vec<f32>, dimensions: usize) {
+    if embedding.len() > dimensions {
+        embedding.truncate(dimensions);
+        normalize(embedding);
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -264,7 +417,20 @@ pub struct Client {
     api_url: String,
     api_key: String,
     model: String,
-    api_response: SupportedAPIs,
+    provider: Rc<dyn EmbeddingProvider>,
+    prefixes: PrefixTemplates,
+    /// Requested length of the embeddings, the model's default if `None`
+    dimensions: Option<u32>,
+    retry: RetryPolicy,
+    /// Shared by clones so that all their requests count towards the limits
+    limiter: Rc<RefCell<RateLimiter>>,
+    /// Shared by clones, when the request waiting longest for the rate
+    /// limits is sent, in milliseconds since the unix epoch
+    rate_limited_until: Rc<Cell<f64>>,
+    /// Most embedding requests in flight at once
+    concurrency: usize,
+    /// Shared by clones, empty until loaded with `load_cache`
+    cache: Rc<RefCell<EmbeddingCache>>,
 }
 
 impl Client {
@@ -276,73 +442,315 @@ impl Client {
         &self.api_key
     }
 
-    fn new(settings: &obsidian::semanticSearchSettings) -> Self {
-        Self {
+    /// Header describing embeddings produced by this client, with the
+    /// dimension filled in once the first embedding is known
+    fn index_header(&self) -> IndexHeader {
+        let mut header = IndexHeader::new(&self.model, self.provider.name(), 0, js_sys::Date::now());
+        header.document_prefix = self.prefixes.document.to_string();
+        header.query_prefix = self.prefixes.query.to_string();
+        header.output_dimensions = self.dimensions;
+        header
+    }
+
+    fn new(settings: &obsidian::semanticSearchSettings) -> Result<Self, SemanticSearchError> {
+        Ok(Self {
             api_url: settings.apiUrl(),
             api_key: settings.apiKey(),
             model: settings.model(),
-            api_response: settings.apiResponseType().into(),
+            provider: provider::from_settings(settings)?,
+            prefixes: PrefixTemplates {
+                document: settings.documentPrefix(),
+                query: settings.queryPrefix(),
+            },
+            dimensions: provider::embedding_dimensions(settings),
+            retry: RetryPolicy::new(settings.maxRetries()),
+            limiter: Rc::new(RefCell::new(RateLimiter::new(
+                settings.requestsPerMinute(),
+                settings.tokensPerMinute(),
+                js_sys::Date::now(),
+            ))),
+            rate_limited_until: Rc::new(Cell::new(0.0)),
+            concurrency: settings.concurrentRequests() as usize,
+            cache: Rc::new(RefCell::new(EmbeddingCache::new(
+                settings.embeddingCacheSize(),
+            ))),
+        })
+    }
+
+    /// Requests embeddings of `dimensions` dimensions regardless of the
+    /// settings, to embed queries matching an existing index
+    fn with_dimensions(mut self, dimensions: Option<u32>) -> Self {
+        self.dimensions = dimensions;
+        self
+    }
+
+    /// Identifies the embedding of `text` for `purpose` by everything that
+    /// changes it, so that a cached embedding is only reused where this
+    /// client would request the same one
+    fn cache_key(&self, text: &str, purpose: EmbeddingPurpose) -> String {
+        let dimensions = self
+            .dimensions
+            .map_or_else(|| "default".to_string(), |d| d.to_string());
+        let embedding = [
+            self.provider.name(),
+            &self.model,
+            &dimensions,
+            &format!("{:?}", purpose),
+            self.prefixes.template(purpose),
+        ]
+        .join("\u{1f}");
+        format!("{}{}", content_hash(&embedding), content_hash(text))
+    }
+
+    /// Replaces the cache with the one saved in the vault, starting from an
+    /// empty one if it cannot be read
+    async fn load_cache(&self, file_processor: &FileProcessor) {
+        let max_megabytes = self.cache.borrow().max_megabytes();
+        if max_megabytes == 0 {
+            return;
+        }
+        match file_processor.read_cache(max_megabytes).await {
+            Ok(cache) => {
+                info!("Loaded {} cached embeddings", cache.len());
+                self.cache.replace(cache);
+            }
+            Err(e) => warn!("Ignoring unreadable {}: {:?}", CACHE_FILE_PATH, e),
         }
     }
 
+    /// Saves the cache to the vault. The cache only saves requests, so
+    /// failing to save it is not an error.
+    async fn save_cache(&self, file_processor: &FileProcessor) {
+        if !self.cache.borrow().is_enabled() {
+            return;
+        }
+        let cache = self.cache.take();
+        info!(
+            "Saving {} cached embeddings, {:.0}% of lookups hit the cache",
+            cache.len(),
+            cache.hit_rate() * 100.0
+        );
+        if let Err(e) = file_processor.write_cache(&cache).await {
+            warn!("Failed to save the embedding cache: {:?}", e);
+        }
+        self.cache.replace(cache);
+    }
+
+    /// Embeds `input`, requesting only the embeddings that are not cached
     pub async fn get_embedding(
         &self,
         input: EmbeddingInput,
+        purpose: EmbeddingPurpose,
     ) -> Result<Vec<Vec<f32>>, SemanticSearchError> {
-        let request = self.create_embedding_request(input)?;
-        let response = self.post_embedding_request(request).await?;
+        let EmbeddingInput::StringArray(texts) = input;
+        let keys: Vec<String> = texts
+            .iter()
+            .map(|text| self.cache_key(text, purpose))
+            .collect();
+        let mut embeddings: Vec<Option<Vec<f32>>> = {
+            let mut cache = self.cache.borrow_mut();
+            keys.iter().map(|key| cache.get(key)).collect()
+        };
+        let uncached: Vec<String> = texts
+            .into_iter()
+            .zip(&embeddings)
+            .filter(|(_, embedding)| embedding.is_none())
+            .map(|(text, _)| text)
+            .collect();
+        if uncached.is_empty() {
+            return Ok(embeddings.into_iter().flatten().collect());
+        }
+        if uncached.len() < keys.len() {
+            debug!(
+                "Found {} of {} embeddings in the cache",
+                keys.len() - uncached.len(),
+                keys.len()
+            );
+        }
+
+        let requested = uncached.len();
+        let response = self.request_embedding(uncached.into(), purpose).await?;
+        if response.len() != requested {
+            return Err(SemanticSearchError::new(
+                ErrorKind::Provider,
+                anyhow!("Requested for {} embeddings but got {}", requested, response.len()),
+            ));
+        }
+        let mut response = response.into_iter();
+        let mut cache = self.cache.borrow_mut();
+        for (key, embedding) in keys.into_iter().zip(embeddings.iter_mut()) {
+            if embedding.is_none() {
+                let requested = response.next().expect("one embedding per uncached text");
+                cache.insert(key, requested.clone());
+                *embedding = Some(requested);
+            }
+        }
+        Ok(embeddings.into_iter().flatten().collect())
+    }
+
+    /// Requests embeddings of `input` from the API
+    async fn request_embedding(
+        &self,
+        input: EmbeddingInput,
+        purpose: EmbeddingPurpose,
+    ) -> Result<Vec<Vec<f32>>, SemanticSearchError> {
+        let input = self.prefixes.apply(input, purpose);
+        let mut response = self.post_embedding_request(input, purpose).await?;
+        if let Some(dimensions) = self.dimensions {
+            response
+                .iter_mut()
+                .for_each(|embedding| truncate_embedding(embedding, dimensions as usize));
+        }
         Ok(response)
     }
 
-    fn create_embedding_request(&self, input: EmbeddingInput) -> Result<EmbeddingRequest> {
-        let embedding_request = EmbeddingRequestBuilder::default()
-            // TODO: add user param for model
-            .model(self.model.clone())
-            .input(input)
-            .build()
-            .context("Failed to build embedding request")?;
-        Ok(embedding_request)
+    /// Waits until the rate limits allow sending `input`
+    async fn pace(&self, input: &EmbeddingInput) {
+        let tokens = if self.limiter.borrow().limits_tokens() {
+            let EmbeddingInput::StringArray(texts) = input;
+            count_tokens(texts.iter().map(String::as_str)).iter().sum()
+        } else {
+            0
+        };
+        let now = js_sys::Date::now();
+        let wait = self.limiter.borrow_mut().reserve(tokens, now);
+        if wait > 0.0 {
+            info!("Waiting {:.1} s for the rate limit", wait / 1000.0);
+            let until = self.rate_limited_until.get().max(now + wait);
+            self.rate_limited_until.set(until);
+            retry::sleep(wait).await;
+        }
     }
 
-    async fn post_embedding_request<I: serde::ser::Serialize>(
+    /// Seconds until the request waiting longest for the rate limits is sent
+    fn rate_limit_wait_seconds(&self, now: f64) -> u32 {
+        ((self.rate_limited_until.get() - now).max(0.0) / 1000.0).ceil() as u32
+    }
+
+    /// Milliseconds the rate limits are expected to add to embedding `input`
+    fn rate_limit_estimate_ms(&self, input: &[InputRow]) -> f64 {
+        let limiter = self.limiter.borrow();
+        let tokens = if limiter.limits_tokens() {
+            count_tokens(input.iter().map(|row| row.body.as_str())).iter().sum()
+        } else {
+            0
+        };
+        limiter.estimate_ms(self.batches(input).len(), tokens, js_sys::Date::now())
+    }
+
+    /// Splits `input` into as few requests as the provider's item and token
+    /// limits allow, with no request larger than the tokens per minute limit
+    fn batches<'a>(&self, input: &'a [InputRow]) -> Vec<&'a [InputRow]> {
+        let max_tokens = [
+            self.provider.max_tokens_per_request(),
+            self.limiter.borrow().max_tokens(),
+        ]
+        .iter()
+        .filter_map(|max| *max)
+        .min();
+        let tokens: Vec<usize> = match max_tokens {
+            Some(_) => count_tokens(input.iter().map(|row| row.body.as_str())),
+            None => vec![0; input.len()],
+        };
+        batches(
+            input,
+            &tokens,
+            self.provider.max_batch_size(),
+            max_tokens.unwrap_or(usize::MAX),
+        )
+    }
+
+    /// Embeds the bodies of `input`, split into batches the provider accepts
+    async fn embed_sections(
         &self,
-        request: I,
-    ) -> Result<Vec<Vec<f32>>> {
-        let request = reqwest::Client::new()
-            .post(self.api_url())
-            .bearer_auth(self.api_key())
-            .json(&request)
-            .build()?;
+        input: &[InputRow],
+    ) -> Result<Vec<EmbeddingRow>, SemanticSearchError> {
+        let batches = self.batches(input);
+        let mut embedded: Vec<Option<Vec<EmbeddingRow>>> = vec![None; batches.len()];
+        let mut tasks = self.embed_batches(&batches);
+        while let Some((batch, result)) = tasks.next().await {
+            embedded[batch] = Some(result?);
+        }
+        Ok(embedded.into_iter().flatten().flatten().collect())
+    }
 
-        let reqwest_client = reqwest::Client::new();
-        let response = reqwest_client
-            .execute(request)
-            .await
-            .context(format!("Failed POST request to {}", self.api_url()))?;
+    /// Embeds `batches` with at most `self.concurrency` requests in flight
+    fn embed_batches<'a>(
+        &'a self,
+        batches: &[&'a [InputRow]],
+    ) -> BoundedTasks<'a, Vec<EmbeddingRow>, SemanticSearchError> {
+        let tasks = batches
+            .iter()
+            .map(|records| self.embed_batch(records).boxed_local())
+            .collect();
+        BoundedTasks::new(tasks, self.concurrency)
+    }
 
-        let status = response.status();
-        let bytes = response.bytes().await?;
+    /// Embeds the bodies of `records` in a single request
+    async fn embed_batch(
+        &self,
+        records: &[InputRow],
+    ) -> Result<Vec<EmbeddingRow>, SemanticSearchError> {
+        let response: Vec<Vec<f32>> = self
+            .get_embedding(records.into(), EmbeddingPurpose::Document)
+            .await?;
+        info!("Sucessfully obtained {} embeddings", response.len());
 
-        if !status.is_success() {
-            let wrapped_error: WrappedError = serde_json::from_slice(bytes.as_ref())?;
-            return Err(anyhow!(wrapped_error));
+        if records.len() != response.len() {
+            return Err(SemanticSearchError::new(
+                ErrorKind::Provider,
+                anyhow!("Requested for {} embeddings but got {}", records.len(), response.len()),
+            ));
         }
 
-        let response: Vec<Vec<f32>> = match self.api_response {
-            SupportedAPIs::Ollama => {
-                let mut response: embedding::OllamaEmbeddingResponse =
-                    serde_json::from_slice(bytes.as_ref())
-                        .context("Failed deserializing Ollama embedding response")?;
-                (&mut response).into()
-            }
-            SupportedAPIs::OpenAI => {
-                let mut response: embedding::OpenAIEmbeddingResponse =
-                    serde_json::from_slice(bytes.as_ref())
-                        .context("Failed deserializing OpenAI embedding response")?;
-                (&mut response).into()
-            }
+        Ok(records
+            .iter()
+            .zip(response)
+            .map(|(record, embedding)| EmbeddingRow {
+                name: record.name.to_string(),
+                path: record.path.to_string(),
+                mtime: record.mtime.to_string(),
+                section_id: record.section_id.to_string(),
+                header: record.section.to_string(),
+                hash: record.hash.to_string(),
+                embedding,
+                quantized: None,
+            })
+            .collect())
+    }
+
+    /// Sends the request, retrying transient failures as `self.retry` allows.
+    /// Every attempt waits for the rate limits, so that retries count towards
+    /// them too.
+    async fn post_embedding_request(
+        &self,
+        input: EmbeddingInput,
+        purpose: EmbeddingPurpose,
+    ) -> Result<Vec<Vec<f32>>> {
+        let reqwest_client = reqwest::Client::new();
+        let endpoint = Endpoint {
+            url: self.api_url(),
+            api_key: self.api_key(),
+            model: &self.model,
+            dimensions: self.dimensions,
         };
-        Ok(response)
+        retry::with_retries(
+            &self.retry,
+            || async {
+                self.pace(&input).await;
+                provider::request_embeddings(
+                    self.provider.as_ref(),
+                    &reqwest_client,
+                    endpoint,
+                    input.clone(),
+                    purpose,
+                )
+                .await
+            },
+            retry::sleep,
+            js_sys::Math::random,
+        )
+        .await
     }
 }
 
@@ -356,3 +764,145 @@ pub fn onload(plugin: &obsidian::Plugin) {
     panic::set_hook(Box::new(console_error_panic_hook::hook));
     info!("Semantic Search Loaded!");
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn input(n: usize) -> Vec<InputRow> {
+        (0..n)
+            .map(|i| InputRow {
+                name: format!("{}.md", i),
+                path: format!("{}.md", i),
+                mtime: "0".to_string(),
+                section_id: String::new(),
+                section: String::new(),
+                hash: String::new(),
+                body: String::new(),
+            })
+            .collect()
+    }
+
+    pub(crate) fn client() -> Client {
+        Client {
+            api_url: "http://localhost:11434/api/embed".to_string(),
+            api_key: String::new(),
+            model: "nomic-embed-text".to_string(),
+            provider: Rc::new(provider::Ollama),
+            prefixes: PrefixTemplates::default(),
+            dimensions: None,
+            retry: RetryPolicy::new(0),
+            limiter: Rc::new(RefCell::new(RateLimiter::default())),
+            rate_limited_until: Rc::new(Cell::new(0.0)),
+            concurrency: 1,
+            cache: Rc::new(RefCell::new(EmbeddingCache::new(1))),
+        }
+    }
+
+    fn sizes(batches: Vec<&[InputRow]>) -> Vec<usize> {
+        batches.iter().map(|batch| batch.len()).collect()
+    }
+
+    #[test]
+    fn respects_max_items() {
+        let input = input(10);
+
+        assert_eq!(sizes(batches(&input, &[1; 10], 4, usize::MAX)), vec![4, 4, 2]);
+    }
+
+    #[test]
+    fn respects_token_budget() {
+        let input = input(5);
+        let tokens = [300, 500, 200, 900, 100];
+
+        assert_eq!(sizes(batches(&input, &tokens, 100, 1000)), vec![3, 2]);
+    }
+
+    #[test]
+    fn sends_oversized_row_alone() {
+        let input = input(3);
+        let tokens = [10, 5000, 10];
+
+        assert_eq!(sizes(batches(&input, &tokens, 100, 1000)), vec![1, 1, 1]);
+    }
+
+    #[test]
+    fn batches_small_input() {
+        assert_eq!(sizes(batches(&input(2), &[1, 1], 100, 1000)), vec![2]);
+        assert!(batches(&input(0), &[], 100, 1000).is_empty());
+    }
+
+    #[test]
+    fn caps_batches_at_tokens_per_minute() {
+        let mut input = input(4);
+        input
+            .iter_mut()
+            .for_each(|row| row.body = "lorem ipsum ".repeat(100));
+        let tokens = count_tokens(input.iter().map(|row| row.body.as_str()));
+        let mut limited = client();
+        limited.limiter = Rc::new(RefCell::new(RateLimiter::new(
+            0,
+            (tokens[0] * 2) as u32,
+            0.0,
+        )));
+
+        assert_eq!(sizes(limited.batches(&input)), vec![2, 2]);
+        assert_eq!(sizes(client().batches(&input)), vec![4]);
+    }
+
+    #[test]
+    fn truncates_and_renormalizes() {
+        let mut embedding = vec![0.6, 0.0, 0.8, 0.0];
+
+        truncate_embedding(&mut embedding, 2);
+
+        assert_eq!(embedding, vec![1.0, 0.0]);
+    }
+
+    #[test]
+    fn keeps_shorter_embedding() {
+        let mut embedding = vec![3.0, 4.0];
+
+        truncate_embedding(&mut embedding, 256);
+
+        assert_eq!(embedding, vec![3.0, 4.0]);
+    }
+
+    #[test]
+    fn cache_keys_cover_what_changes_embeddings() {
+        let client = client();
+        let key = client.cache_key("text", EmbeddingPurpose::Document);
+
+        assert_eq!(key, client.cache_key("text", EmbeddingPurpose::Document));
+        assert_ne!(key, client.cache_key("other text", EmbeddingPurpose::Document));
+        assert_ne!(key, client.cache_key("text", EmbeddingPurpose::Query));
+        let mut other = client.clone();
+        other.model = "mxbai-embed-large".to_string();
+        assert_ne!(key, other.cache_key("text", EmbeddingPurpose::Document));
+        let other = client.clone().with_dimensions(Some(256));
+        assert_ne!(key, other.cache_key("text", EmbeddingPurpose::Document));
+        let mut other = client.clone();
+        other.prefixes.document = "search_document: ".to_string();
+        assert_ne!(key, other.cache_key("text", EmbeddingPurpose::Document));
+    }
+
+    #[tokio::test]
+    async fn serves_cached_embeddings_without_requests() {
+        let client = client();
+        for (text, embedding) in [("a", vec![1.0, 0.0]), ("b", vec![0.0, 1.0])].iter() {
+            let key = client.cache_key(text, EmbeddingPurpose::Document);
+            client.cache.borrow_mut().insert(key, embedding.clone());
+        }
+
+        let embeddings = client
+            .get_embedding(
+                vec!["b".to_string(), "a".to_string()].into(),
+                EmbeddingPurpose::Document,
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(embeddings, vec![vec![0.0, 1.0], vec![1.0, 0.0]]);
+        assert_eq!(client.cache.borrow().hits(), 2);
+    }
+}
@@ -0,0 +1,474 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::rc::Rc;
+
+use anyhow::anyhow;
+use log::error;
+use log::info;
+use wasm_bindgen::prelude::*;
+use wasm_bindgen::JsCast;
+use wasm_bindgen_futures::spawn_local;
+
+use crate::error::ErrorKind;
+use crate::file_processor::diff_sections;
+use crate::file_processor::EmbeddingRow;
+use crate::file_processor::FileProcessor;
+use crate::file_processor::ModifiedInput;
+use crate::file_processor::EMBEDDING_FILE_PATH;
+use crate::generate_input::process_file;
+use crate::index_lock::IndexLock;
+use crate::obsidian;
+use crate::obsidian::semanticSearchSettings;
+use crate::obsidian::App;
+use crate::obsidian::EventRef;
+use crate::obsidian::Notice;
+use crate::obsidian::TAbstractFile;
+use crate::provider;
+use crate::store::EmbeddingStore;
+use crate::Client;
+use crate::SemanticSearchError;
+
+/// What has to happen to the sections of a note at the next flush
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum PendingChange {
+    /// Re-chunk the note and embed the sections that changed
+    Upsert,
+    /// Drop the note's sections
+    Delete,
+}
+
+/// Vault changes waiting to be indexed, coalesced by path so that any number
+/// of edits to a note between two flushes costs a single re-chunk.
+#[derive(Debug, Default)]
+struct ChangeQueue {
+    pending: HashMap<String, PendingChange>,
+}
+
+impl ChangeQueue {
+    fn modified(&mut self, path: String) {
+        if is_note(&path) {
+            self.pending.insert(path, PendingChange::Upsert);
+        }
+    }
+
+    fn deleted(&mut self, path: String) {
+        if is_note(&path) {
+            self.pending.insert(path, PendingChange::Delete);
+        }
+    }
+
+    /// Indexed as a delete and a create, which are flushed together so the
+    /// sections at the new path pick up the embeddings of the old one
+    fn renamed(&mut self, old_path: String, path: String) {
+        self.deleted(old_path);
+        self.modified(path);
+    }
+
+    fn len(&self) -> usize {
+        self.pending.len()
+    }
+
+    fn is_empty(&self) -> bool {
+        self.pending.is_empty()
+    }
+
+    fn take(&mut self) -> HashMap<String, PendingChange> {
+        std::mem::take(&mut self.pending)
+    }
+
+    /// Queues `changes` taken earlier again, unless a note has changed since
+    fn restore(&mut self, changes: HashMap<String, PendingChange>) {
+        for (path, change) in changes {
+            self.pending.entry(path).or_insert(change);
+        }
+    }
+}
+
+/// Only markdown notes are indexed, which also keeps the plugin's own writes
+/// to the vault from queueing changes
+fn is_note(path: &str) -> bool {
+    path.ends_with(".md")
+}
+
+struct LiveIndexerState {
+    app: App,
+    settings: semanticSearchSettings,
+    queue: ChangeQueue,
+    paused: bool,
+    /// Whether a flush is in progress; only one runs at a time
+    running: bool,
+    /// Flushes failed in a row, only the first of which is shown in a notice
+    failures: u32,
+    /// Whether a timer is scheduled. Timers are never cancelled, instead each
+    /// event pushes back the deadline and a timer firing early reschedules.
+    timer_pending: bool,
+    deadline: f64,
+}
+
+type SharedState = Rc<RefCell<LiveIndexerState>>;
+
+/// Milliseconds before changes whose flush failed are flushed again
+const RETRY_DELAY_MS: f64 = 60_000.0;
+
+/// Keeps the embedding index up to date with the vault in the background.
+/// Vault events are queued and flushed once no further event arrives for
+/// `liveIndexingDelay` milliseconds. Only maintains an existing index, the
+/// first one has to be created with 'Generate Embeddings'.
+#[wasm_bindgen]
+pub struct LiveIndexer {
+    state: SharedState,
+    app: App,
+    event_refs: Vec<EventRef>,
+    _file_callback: Closure<dyn FnMut(TAbstractFile)>,
+    _delete_callback: Closure<dyn FnMut(TAbstractFile)>,
+    _rename_callback: Closure<dyn FnMut(TAbstractFile, String)>,
+}
+
+#[wasm_bindgen]
+impl LiveIndexer {
+    #[wasm_bindgen(constructor)]
+    pub fn new(app: App, settings: semanticSearchSettings) -> LiveIndexer {
+        let state = Rc::new(RefCell::new(LiveIndexerState {
+            app: app.clone(),
+            settings,
+            queue: ChangeQueue::default(),
+            paused: false,
+            running: false,
+            failures: 0,
+            timer_pending: false,
+            deadline: 0.0,
+        }));
+
+        let file_state = state.clone();
+        let file_callback = Closure::wrap(Box::new(move |file: TAbstractFile| {
+            enqueue(&file_state, |queue| queue.modified(file.path()));
+        }) as Box<dyn FnMut(TAbstractFile)>);
+        let delete_state = state.clone();
+        let delete_callback = Closure::wrap(Box::new(move |file: TAbstractFile| {
+            enqueue(&delete_state, |queue| queue.deleted(file.path()));
+        }) as Box<dyn FnMut(TAbstractFile)>);
+        let rename_state = state.clone();
+        let rename_callback =
+            Closure::wrap(Box::new(move |file: TAbstractFile, old_path: String| {
+                enqueue(&rename_state, |queue| queue.renamed(old_path, file.path()));
+            }) as Box<dyn FnMut(TAbstractFile, String)>);
+
+        let vault = app.vault();
+        let event_refs = vec![
+            vault.on("create", file_callback.as_ref().unchecked_ref()),
+            vault.on("modify", file_callback.as_ref().unchecked_ref()),
+            vault.on("delete", delete_callback.as_ref().unchecked_ref()),
+            vault.on("rename", rename_callback.as_ref().unchecked_ref()),
+        ];
+
+        LiveIndexer {
+            state,
+            app,
+            event_refs,
+            _file_callback: file_callback,
+            _delete_callback: delete_callback,
+            _rename_callback: rename_callback,
+        }
+    }
+
+    /// Keeps queueing vault events but stops flushing them
+    pub fn pause(&self) {
+        self.state.borrow_mut().paused = true;
+    }
+
+    pub fn resume(&self) {
+        self.state.borrow_mut().paused = false;
+        schedule(&self.state);
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.state.borrow().paused
+    }
+
+    /// Number of notes waiting to be indexed
+    pub fn queue_length(&self) -> usize {
+        self.state.borrow().queue.len()
+    }
+}
+
+impl Drop for LiveIndexer {
+    fn drop(&mut self) {
+        let vault = self.app.vault();
+        self.event_refs
+            .iter()
+            .for_each(|event_ref| vault.offref(event_ref));
+    }
+}
+
+fn enqueue(state: &SharedState, change: impl FnOnce(&mut ChangeQueue)) {
+    {
+        let mut state = state.borrow_mut();
+        change(&mut state.queue);
+        state.deadline = js_sys::Date::now() + state.settings.liveIndexingDelay() as f64;
+    }
+    schedule(state);
+}
+
+fn schedule(state: &SharedState) {
+    let delay = {
+        let mut state = state.borrow_mut();
+        if state.timer_pending || state.paused || state.running || state.queue.is_empty() {
+            return;
+        }
+        state.timer_pending = true;
+        (state.deadline - js_sys::Date::now()).max(0.0) as u32
+    };
+    let timer_state = state.clone();
+    obsidian::setTimeout(&Closure::once_into_js(move || on_timer(timer_state)), delay);
+}
+
+fn on_timer(state: SharedState) {
+    let ready = {
+        let mut state = state.borrow_mut();
+        state.timer_pending = false;
+        if state.paused || state.running || state.queue.is_empty() {
+            return;
+        }
+        if js_sys::Date::now() < state.deadline {
+            None
+        } else if let Some(lock) = IndexLock::try_acquire() {
+            state.running = true;
+            Some((
+                lock,
+                state.app.clone(),
+                state.settings.clone(),
+                state.queue.take(),
+            ))
+        } else {
+            // 'Generate Embeddings' is running, and indexes these changes
+            // unless they were made after it read the notes
+            state.deadline = js_sys::Date::now() + state.settings.liveIndexingDelay() as f64;
+            None
+        }
+    };
+    let (lock, app, settings, changes) = match ready {
+        Some(ready) => ready,
+        // More events arrived since the timer was scheduled, or the flush is
+        // deferred
+        None => return schedule(&state),
+    };
+    spawn_local(async move {
+        let result = flush(app, settings, &changes).await;
+        drop(lock);
+        {
+            let mut state = state.borrow_mut();
+            state.running = false;
+            match result {
+                Ok(()) => state.failures = 0,
+                Err(e) => {
+                    error!("{:?}", e);
+                    if state.failures == 0 {
+                        Notice::new(&format!("Live indexing failed: {}", e.describe()));
+                    }
+                    state.failures += 1;
+                    state.queue.restore(changes);
+                    state.deadline = state.deadline.max(js_sys::Date::now() + RETRY_DELAY_MS);
+                }
+            }
+        }
+        schedule(&state);
+    });
+}
+
+/// Re-chunks the changed notes and updates the index, embedding only the
+/// sections whose content is new
+async fn flush(
+    app: App,
+    settings: semanticSearchSettings,
+    changes: &HashMap<String, PendingChange>,
+) -> Result<(), SemanticSearchError> {
+    let file_processor = FileProcessor::new(app.vault());
+    let client = Client::new(&settings)?;
+    let template = client.index_header();
+    let store = match file_processor.read_index(&template).await? {
+        Some(store) => store,
+        None => {
+            info!(
+                "No embedding index yet, skipping {} changed notes",
+                changes.len()
+            );
+            return Ok(());
+        }
+    };
+    if let Some(mismatch) = store.header.mismatch(&template) {
+        return Err(SemanticSearchError::new(
+            ErrorKind::IndexMismatch,
+            anyhow!("{} was embedded with {}", EMBEDDING_FILE_PATH, mismatch),
+        ));
+    }
+
+    let ignored_folders = settings.ignoredFolders();
+    let section_delimeter_regex = settings.sectionDelimeterRegex();
+    let mut input = Vec::new();
+    for (path, change) in changes {
+        if *change != PendingChange::Upsert {
+            continue;
+        }
+        // Deleted or moved again since the event
+        if let Some(file) = file_processor.get_markdown_file(path, &ignored_folders) {
+            input.extend(
+                process_file(
+                    &file_processor,
+                    file,
+                    &section_delimeter_regex,
+                    provider::max_token_length(&settings),
+                )
+                .await?,
+            );
+        }
+    }
+
+    let paths: HashSet<String> = changes.keys().cloned().collect();
+    let header = store.header.clone();
+    let (affected, mut embedding_rows): (Vec<_>, Vec<_>) = store
+        .into_rows()
+        .into_iter()
+        .partition(|row| paths.contains(&row.path));
+    if affected.is_empty() && input.is_empty() {
+        return Ok(());
+    }
+
+    let modified = diff_sections(input, affected);
+    info!(
+        "Live indexing {} notes: {:?}",
+        paths.len(),
+        modified.changes
+    );
+    let embedded = if modified.input.is_empty() {
+        Ok(modified.reusable)
+    } else {
+        // Undoing an edit brings back text that was embedded before, which
+        // the cache answers without a request
+        client.load_cache(&file_processor).await;
+        let embedded = embed_modified(&client, modified).await;
+        client.save_cache(&file_processor).await;
+        embedded
+    };
+    embedding_rows.extend(embedded?);
+
+    let store = EmbeddingStore::from_rows(header, embedding_rows)?;
+    file_processor.save_index(&store).await?;
+    Ok(())
+}
+
+/// The rows of the modified notes: the reused embeddings and those of the
+/// new sections
+async fn embed_modified(
+    client: &Client,
+    modified: ModifiedInput,
+) -> Result<Vec<EmbeddingRow>, SemanticSearchError> {
+    let mut rows = modified.reusable;
+    rows.extend(client.embed_sections(&modified.input).await?);
+    Ok(rows)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::embedding::EmbeddingPurpose;
+    use crate::file_processor::InputRow;
+    use crate::hash::content_hash;
+
+    #[test]
+    fn coalesces_edits() {
+        let mut queue = ChangeQueue::default();
+        queue.modified("a.md".to_string());
+        queue.modified("a.md".to_string());
+        queue.modified("b.md".to_string());
+
+        assert_eq!(queue.len(), 2);
+        assert_eq!(queue.take()["a.md"], PendingChange::Upsert);
+        assert!(queue.is_empty());
+    }
+
+    #[test]
+    fn latest_event_wins() {
+        let mut queue = ChangeQueue::default();
+        queue.modified("a.md".to_string());
+        queue.deleted("a.md".to_string());
+        queue.deleted("b.md".to_string());
+        queue.modified("b.md".to_string());
+
+        let pending = queue.take();
+        assert_eq!(pending["a.md"], PendingChange::Delete);
+        assert_eq!(pending["b.md"], PendingChange::Upsert);
+    }
+
+    #[test]
+    fn rename_deletes_old_path() {
+        let mut queue = ChangeQueue::default();
+        queue.modified("a.md".to_string());
+        queue.renamed("a.md".to_string(), "folder/a.md".to_string());
+
+        let pending = queue.take();
+        assert_eq!(pending.len(), 2);
+        assert_eq!(pending["a.md"], PendingChange::Delete);
+        assert_eq!(pending["folder/a.md"], PendingChange::Upsert);
+    }
+
+    #[test]
+    fn restores_failed_changes_behind_newer_ones() {
+        let mut queue = ChangeQueue::default();
+        queue.modified("a.md".to_string());
+        queue.modified("b.md".to_string());
+        let failed = queue.take();
+        queue.deleted("a.md".to_string());
+
+        queue.restore(failed);
+
+        let pending = queue.take();
+        assert_eq!(pending.len(), 2);
+        assert_eq!(pending["a.md"], PendingChange::Delete);
+        assert_eq!(pending["b.md"], PendingChange::Upsert);
+    }
+
+    #[test]
+    fn ignores_other_files() {
+        let mut queue = ChangeQueue::default();
+        queue.modified("embedding.bin".to_string());
+        queue.deleted("folder".to_string());
+        queue.renamed("image.png".to_string(), "folder/image.png".to_string());
+
+        assert!(queue.is_empty());
+    }
+
+    #[tokio::test]
+    async fn answers_undone_edits_from_the_cache() {
+        let client = crate::tests::client();
+        let key = client.cache_key("original", EmbeddingPurpose::Document);
+        client.cache.borrow_mut().insert(key, vec![1.0, 0.0]);
+        let input = InputRow {
+            name: "a.md".to_string(),
+            path: "a.md".to_string(),
+            mtime: "2".to_string(),
+            section_id: "intro".to_string(),
+            section: "Intro".to_string(),
+            hash: content_hash("original"),
+            body: "original".to_string(),
+        };
+        let edited = EmbeddingRow {
+            name: "a.md".to_string(),
+            path: "a.md".to_string(),
+            mtime: "1".to_string(),
+            section_id: "intro".to_string(),
+            header: "Intro".to_string(),
+            hash: content_hash("edited"),
+            embedding: vec![0.0, 1.0],
+            quantized: None,
+        };
+
+        let rows = embed_modified(&client, diff_sections(vec![input], vec![edited]))
+            .await
+            .unwrap();
+
+        assert_eq!(rows.len(), 1);
+        assert_eq!(rows[0].embedding, vec![1.0, 0.0]);
+        assert_eq!(client.cache.borrow().hits(), 1);
+    }
+}
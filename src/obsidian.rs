@@ -1,3 +1,7 @@
+#![allow(non_camel_case_types)]
+
+use js_sys::ArrayBuffer;
+use js_sys::Function;
 use wasm_bindgen::prelude::*;
 
 #[wasm_bindgen(module = "obsidian")]
@@ -11,6 +15,7 @@ extern "C" {
     #[wasm_bindgen(method, getter)]
     pub fn settings(this: &Plugin) -> semanticSearchSettings;
 
+    #[derive(Clone)]
     pub type semanticSearchSettings;
 
     #[wasm_bindgen(method, getter)]
@@ -22,15 +27,49 @@ extern "C" {
     #[wasm_bindgen(method, getter)]
     pub fn apiResponseType(this: &semanticSearchSettings) -> String;
     #[wasm_bindgen(method, getter)]
+    pub fn customRequestTemplate(this: &semanticSearchSettings) -> String;
+    #[wasm_bindgen(method, getter)]
+    pub fn customResponsePath(this: &semanticSearchSettings) -> String;
+    #[wasm_bindgen(method, getter)]
+    pub fn customBatchSize(this: &semanticSearchSettings) -> u32;
+    #[wasm_bindgen(method, getter)]
+    pub fn teiTruncate(this: &semanticSearchSettings) -> bool;
+    #[wasm_bindgen(method, getter)]
+    pub fn teiNormalize(this: &semanticSearchSettings) -> bool;
+    #[wasm_bindgen(method, getter)]
+    pub fn azureApiVersion(this: &semanticSearchSettings) -> String;
+    #[wasm_bindgen(method, getter)]
+    pub fn embeddingDimensions(this: &semanticSearchSettings) -> u32;
+    #[wasm_bindgen(method, getter)]
+    pub fn maxRetries(this: &semanticSearchSettings) -> u32;
+    #[wasm_bindgen(method, getter)]
+    pub fn requestsPerMinute(this: &semanticSearchSettings) -> u32;
+    #[wasm_bindgen(method, getter)]
+    pub fn tokensPerMinute(this: &semanticSearchSettings) -> u32;
+    #[wasm_bindgen(method, getter)]
+    pub fn concurrentRequests(this: &semanticSearchSettings) -> u32;
+    #[wasm_bindgen(method, getter)]
+    pub fn embeddingCacheSize(this: &semanticSearchSettings) -> u32;
+    #[wasm_bindgen(method, getter)]
+    pub fn documentPrefix(this: &semanticSearchSettings) -> String;
+    #[wasm_bindgen(method, getter)]
+    pub fn queryPrefix(this: &semanticSearchSettings) -> String;
+    #[wasm_bindgen(method, getter)]
     pub fn debugMode(this: &semanticSearchSettings) -> bool;
     #[wasm_bindgen(method, getter)]
     pub fn ignoredFolders(this: &semanticSearchSettings) -> String;
     #[wasm_bindgen(method, getter)]
     pub fn sectionDelimeterRegex(this: &semanticSearchSettings) -> String;
     #[wasm_bindgen(method, getter)]
-    pub fn numBatches(this: &semanticSearchSettings) -> u32;
-    #[wasm_bindgen(method, getter)]
     pub fn maxTokenLength(this: &semanticSearchSettings) -> u32;
+    #[wasm_bindgen(method, getter)]
+    pub fn quantization(this: &semanticSearchSettings) -> String;
+    #[wasm_bindgen(method, getter)]
+    pub fn quantizationRescore(this: &semanticSearchSettings) -> bool;
+    #[wasm_bindgen(method, getter)]
+    pub fn hnswEfSearch(this: &semanticSearchSettings) -> u32;
+    #[wasm_bindgen(method, getter)]
+    pub fn liveIndexingDelay(this: &semanticSearchSettings) -> u32;
 
     #[derive(Clone)]
     pub type App;
@@ -47,16 +86,37 @@ extern "C" {
     #[wasm_bindgen(method, catch)]
     pub async fn cachedRead(this: &Vault, file: TFile) -> Result<JsValue, JsValue>;
     #[wasm_bindgen(method, catch)]
+    pub async fn readBinary(this: &Vault, file: TFile) -> Result<JsValue, JsValue>;
+    #[wasm_bindgen(method, catch)]
+    pub async fn createBinary(this: &Vault, path: String, data: ArrayBuffer) -> Result<JsValue, JsValue>;
+    #[wasm_bindgen(method, catch)]
+    pub async fn modifyBinary(this: &Vault, file: TFile, data: ArrayBuffer) -> Result<JsValue, JsValue>;
+    #[wasm_bindgen(method, catch)]
     pub async fn append(this: &Vault, file: TFile, data: String) -> Result<JsValue, JsValue>;
     #[wasm_bindgen(method, catch)]
     pub async fn create(this: &Vault, path: String, data: String) -> Result<JsValue, JsValue>;
     #[wasm_bindgen(method, catch)]
     pub async fn delete(this: &Vault, file: TFile) -> Result<JsValue, JsValue>;
+    #[wasm_bindgen(method, catch)]
+    pub async fn createFolder(this: &Vault, path: String) -> Result<JsValue, JsValue>;
+    /// Deletes a folder along with its contents when `force` is set
+    #[wasm_bindgen(method, catch, js_name = delete)]
+    pub async fn deleteFolder(this: &Vault, folder: TFolder, force: bool) -> Result<JsValue, JsValue>;
     #[wasm_bindgen(method)]
     pub fn getAbstractFileByPath(this: &Vault, path: String) -> TAbstractFile;
+    /// Subscribes to a vault event: `create`, `modify` and `delete` pass the
+    /// file, `rename` passes the file and its old path
+    #[wasm_bindgen(method)]
+    pub fn on(this: &Vault, name: &str, callback: &Function) -> EventRef;
+    #[wasm_bindgen(method)]
+    pub fn offref(this: &Vault, event_ref: &EventRef);
+
+    pub type EventRef;
 
     #[derive(Debug)]
     pub type TAbstractFile;
+    #[wasm_bindgen(method, getter)]
+    pub fn path(this: &TAbstractFile) -> String;
 
     #[derive(Debug)]
     pub type FileStats;
@@ -91,6 +151,11 @@ extern "C" {
     pub fn new(message: &str) -> Notice;
 }
 
+#[wasm_bindgen]
+extern "C" {
+    pub fn setTimeout(handler: &JsValue, timeout: u32) -> JsValue;
+}
+
 #[wasm_bindgen(module = "main")]
 extern "C" {
     pub type GenerateEmbeddingsModal;
@@ -0,0 +1,137 @@
+use futures_util::future;
+use js_sys::Function;
+use log::warn;
+use serde::Serialize;
+use wasm_bindgen::JsValue;
+use wasm_bindgen_futures::JsFuture;
+use web_sys::AbortSignal;
+
+/// What a long running command is doing
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Phase {
+    ReadingNotes,
+    WritingInput,
+    Embedding,
+    Saving,
+    Done,
+}
+
+/// Passed to the progress callback of 'Export Input' and 'Generate
+/// Embeddings' as a plain object. Counts that do not apply to the current
+/// command are 0.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Progress {
+    pub phase: Phase,
+    pub files_processed: usize,
+    pub files_total: usize,
+    pub sections_embedded: usize,
+    pub sections_total: usize,
+    pub batches_remaining: usize,
+    /// Estimated cost in dollars of the sections embedded so far
+    pub estimated_cost: f32,
+    /// Seconds until a request waiting for the rate limits is sent, 0 if none
+    /// is waiting
+    pub rate_limit_wait_seconds: u32,
+}
+
+impl Progress {
+    pub fn new(phase: Phase) -> Self {
+        Self {
+            phase,
+            files_processed: 0,
+            files_total: 0,
+            sections_embedded: 0,
+            sections_total: 0,
+            batches_remaining: 0,
+            estimated_cost: 0.0,
+            rate_limit_wait_seconds: 0,
+        }
+    }
+}
+
+/// Reports progress to an optional JS callback and tells whether an optional
+/// `AbortSignal` asked the command to stop
+#[derive(Debug, Clone, Default)]
+pub struct ProgressReporter {
+    callback: Option<Function>,
+    signal: Option<AbortSignal>,
+}
+
+impl ProgressReporter {
+    pub fn new(callback: Option<Function>, signal: Option<AbortSignal>) -> Self {
+        Self { callback, signal }
+    }
+
+    /// A throwing callback is logged rather than failing the command
+    pub fn report(&self, progress: &Progress) {
+        let callback = match &self.callback {
+            Some(callback) => callback,
+            None => return,
+        };
+        let result = serde_wasm_bindgen::to_value(progress)
+            .map_err(JsValue::from)
+            .and_then(|progress| callback.call1(&JsValue::NULL, &progress));
+        if let Err(e) = result {
+            warn!("Progress callback failed: {:?}", e);
+        }
+    }
+
+    pub fn is_aborted(&self) -> bool {
+        self.signal.as_ref().is_some_and(AbortSignal::aborted)
+    }
+
+    /// Resolves once the signal is aborted, never if there is none
+    pub async fn aborted(&self) {
+        let signal = match &self.signal {
+            Some(signal) => signal,
+            None => return future::pending().await,
+        };
+        if signal.aborted() {
+            return;
+        }
+        // A listener, unlike `onabort`, leaves the caller's handlers in place
+        let promise = js_sys::Promise::new(&mut |resolve, _reject| {
+            if let Err(e) = signal.add_event_listener_with_callback("abort", &resolve) {
+                warn!("Failed to listen for the abort signal: {:?}", e);
+            }
+        });
+        // the promise never rejects
+        let _ = JsFuture::from(promise).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn serializes_as_plain_fields() {
+        let mut progress = Progress::new(Phase::ReadingNotes);
+        progress.files_processed = 3;
+        progress.files_total = 10;
+
+        assert_eq!(
+            serde_json::to_value(&progress).unwrap(),
+            serde_json::json!({
+                "phase": "reading_notes",
+                "files_processed": 3,
+                "files_total": 10,
+                "sections_embedded": 0,
+                "sections_total": 0,
+                "batches_remaining": 0,
+                "estimated_cost": 0.0,
+                "rate_limit_wait_seconds": 0,
+            })
+        );
+    }
+
+    #[test]
+    fn reporter_without_callback_or_signal_does_nothing() {
+        let reporter = ProgressReporter::default();
+
+        reporter.report(&Progress::new(Phase::Done));
+
+        assert!(!reporter.is_aborted());
+    }
+}
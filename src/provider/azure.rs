@@ -0,0 +1,202 @@
+use anyhow::{anyhow, Context, Result};
+use reqwest::RequestBuilder;
+use reqwest::Url;
+use serde::Deserialize;
+use serde_json::json;
+
+use super::raw_error;
+use super::EmbeddingProvider;
+use super::Endpoint;
+use crate::embedding::EmbeddingInput;
+use crate::embedding::EmbeddingPurpose;
+
+/// Azure OpenAI, which serves each model from a named deployment of a
+/// resource. The API URL is the resource endpoint, e.g.
+/// `https://my-resource.openai.azure.com`, and the model setting is the name
+/// of the deployment.
+#[derive(Debug, Clone)]
+pub struct Azure {
+    api_version: String,
+}
+
+impl Azure {
+    pub const NAME: &'static str = "Azure";
+
+    pub fn new(api_version: &str) -> Self {
+        Self {
+            api_version: api_version.to_string(),
+        }
+    }
+
+    /// `{resource}/openai/deployments/{deployment}/embeddings?api-version=..`,
+    /// keeping a URL that already points at a deployment as it is
+    fn deployment_url(&self, endpoint: &Endpoint) -> Result<Url> {
+        let mut url =
+            Url::parse(endpoint.url).context(format!("Invalid Azure endpoint {}", endpoint.url))?;
+        if !url.path().contains("/openai/deployments/") {
+            url.path_segments_mut()
+                .map_err(|_| anyhow!("Invalid Azure endpoint {}", endpoint.url))?
+                .pop_if_empty()
+                .extend(&["openai", "deployments", endpoint.model, "embeddings"]);
+        }
+        if !url.query_pairs().any(|(key, _)| key == "api-version") {
+            url.query_pairs_mut()
+                .append_pair("api-version", &self.api_version);
+        }
+        Ok(url)
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct AzureEmbeddingResponse {
+    data: Vec<Embedding>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Embedding {
+    embedding: Vec<f32>,
+}
+
+/// `{"error": {"code": "DeploymentNotFound", "message": "..."}}`, where
+/// unlike OpenAI's error object the type is usually missing
+#[derive(Debug, Deserialize)]
+struct AzureErrorEnvelope {
+    error: AzureError,
+}
+
+#[derive(Debug, Deserialize)]
+struct AzureError {
+    code: Option<String>,
+    message: String,
+}
+
+impl EmbeddingProvider for Azure {
+    fn name(&self) -> &'static str {
+        Self::NAME
+    }
+
+    /// The deployment determines the model, so only the input is sent
+    fn request_body(
+        &self,
+        _model: &str,
+        input: EmbeddingInput,
+        _purpose: EmbeddingPurpose,
+    ) -> Result<serde_json::Value> {
+        Ok(json!({ "input": input }))
+    }
+
+    /// Only honoured by text-embedding-3 and later deployments
+    fn request_dimensions(&self, body: &mut serde_json::Value, dimensions: u32) {
+        body["dimensions"] = json!(dimensions);
+    }
+
+    fn authenticate(&self, request: RequestBuilder, api_key: &str) -> RequestBuilder {
+        request.header("api-key", api_key)
+    }
+
+    fn build_request(
+        &self,
+        http: &reqwest::Client,
+        endpoint: Endpoint,
+        input: EmbeddingInput,
+        purpose: EmbeddingPurpose,
+    ) -> Result<reqwest::Request> {
+        let url = self.deployment_url(&endpoint)?;
+        let body = self.request_json(&endpoint, input, purpose)?;
+        self.authenticate(http.post(url), endpoint.api_key)
+            .json(&body)
+            .build()
+            .context("Failed to build embedding request")
+    }
+
+    fn decode_response(&self, body: &[u8]) -> Result<Vec<Vec<f32>>> {
+        let response: AzureEmbeddingResponse = serde_json::from_slice(body)
+            .context("Failed deserializing Azure OpenAI embedding response")?;
+        Ok(response.data.into_iter().map(|x| x.embedding).collect())
+    }
+
+    fn decode_error(&self, status: u16, body: &[u8]) -> anyhow::Error {
+        match serde_json::from_slice::<AzureErrorEnvelope>(body) {
+            Ok(AzureErrorEnvelope { error }) => match error.code {
+                Some(code) => anyhow!(
+                    "Azure OpenAI request failed with status {} ({}): {}",
+                    status,
+                    code,
+                    error.message
+                ),
+                None => anyhow!(
+                    "Azure OpenAI request failed with status {}: {}",
+                    status,
+                    error.message
+                ),
+            },
+            Err(_) => raw_error("Azure OpenAI", status, body),
+        }
+    }
+
+    fn max_batch_size(&self) -> usize {
+        2048
+    }
+
+    fn max_input_tokens(&self) -> Option<usize> {
+        Some(8191)
+    }
+
+    fn max_tokens_per_request(&self) -> Option<usize> {
+        Some(300_000)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(url: &str) -> reqwest::Request {
+        let endpoint = Endpoint {
+            url,
+            api_key: "secret",
+            model: "text-embedding-3-small",
+            dimensions: Some(256),
+        };
+        Azure::new("2024-02-01")
+            .build_request(
+                &reqwest::Client::new(),
+                endpoint,
+                "text".to_string().into(),
+                EmbeddingPurpose::Document,
+            )
+            .unwrap()
+    }
+
+    #[test]
+    fn builds_deployment_request() {
+        let request = request("https://my-resource.openai.azure.com/");
+
+        assert_eq!(
+            request.url().as_str(),
+            "https://my-resource.openai.azure.com/openai/deployments/text-embedding-3-small/embeddings?api-version=2024-02-01"
+        );
+        assert_eq!(request.headers()["api-key"], "secret");
+        assert!(request.headers().get("authorization").is_none());
+        let body: serde_json::Value =
+            serde_json::from_slice(request.body().unwrap().as_bytes().unwrap()).unwrap();
+        assert_eq!(body, json!({"input": ["text"], "dimensions": 256}));
+    }
+
+    #[test]
+    fn keeps_deployment_url() {
+        let url = "https://my-resource.openai.azure.com/openai/deployments/embeddings/embeddings?api-version=2023-05-15";
+
+        assert_eq!(request(url).url().as_str(), url);
+    }
+
+    #[test]
+    fn decodes_error() {
+        let body = br#"{"error": {"code": "DeploymentNotFound", "message": "The API deployment for this resource does not exist."}}"#;
+
+        assert_eq!(
+            Azure::new("2024-02-01").decode_error(404, body).to_string(),
+            "Azure OpenAI request failed with status 404 (DeploymentNotFound): The API deployment for this resource does not exist."
+        );
+    }
+}
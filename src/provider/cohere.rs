@@ -0,0 +1,139 @@
+use anyhow::{anyhow, Context, Result};
+use serde::Deserialize;
+use serde_json::json;
+
+use super::raw_error;
+use super::EmbeddingProvider;
+use crate::embedding::EmbeddingInput;
+use crate::embedding::EmbeddingPurpose;
+
+/// Cohere's `/v2/embed` endpoint
+#[derive(Debug, Clone, Copy)]
+pub struct Cohere;
+
+impl Cohere {
+    pub const NAME: &'static str = "Cohere";
+}
+
+#[derive(Debug, Deserialize)]
+struct CohereEmbeddingResponse {
+    embeddings: CohereEmbeddings,
+}
+
+/// Embeddings keyed by type when `embedding_types` is requested, as it always
+/// is by v2, or a bare array from v1 without it
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum CohereEmbeddings {
+    ByType { float: Vec<Vec<f32>> },
+    Float(Vec<Vec<f32>>),
+}
+
+#[derive(Debug, Deserialize)]
+struct CohereError {
+    message: String,
+}
+
+impl EmbeddingProvider for Cohere {
+    fn name(&self) -> &'static str {
+        Self::NAME
+    }
+
+    fn request_body(
+        &self,
+        model: &str,
+        input: EmbeddingInput,
+        purpose: EmbeddingPurpose,
+    ) -> Result<serde_json::Value> {
+        let input_type = match purpose {
+            EmbeddingPurpose::Document => "search_document",
+            EmbeddingPurpose::Query => "search_query",
+        };
+        Ok(json!({
+            "model": model,
+            "texts": input,
+            "input_type": input_type,
+            "embedding_types": ["float"],
+        }))
+    }
+
+    /// Only honoured by embed-v4 and later models
+    fn request_dimensions(&self, body: &mut serde_json::Value, dimensions: u32) {
+        body["output_dimension"] = json!(dimensions);
+    }
+
+    fn decode_response(&self, body: &[u8]) -> Result<Vec<Vec<f32>>> {
+        let response: CohereEmbeddingResponse = serde_json::from_slice(body)
+            .context("Failed deserializing Cohere embedding response")?;
+        Ok(match response.embeddings {
+            CohereEmbeddings::ByType { float } => float,
+            CohereEmbeddings::Float(embeddings) => embeddings,
+        })
+    }
+
+    fn decode_error(&self, status: u16, body: &[u8]) -> anyhow::Error {
+        match serde_json::from_slice::<CohereError>(body) {
+            Ok(error) => anyhow!(
+                "Cohere request failed with status {}: {}",
+                status,
+                error.message
+            ),
+            Err(_) => raw_error("Cohere", status, body),
+        }
+    }
+
+    fn max_batch_size(&self) -> usize {
+        96
+    }
+
+    fn max_input_tokens(&self) -> Option<usize> {
+        Some(512)
+    }
+
+    fn max_tokens_per_request(&self) -> Option<usize> {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sets_input_type() {
+        let body = Cohere
+            .request_body(
+                "embed-english-v3.0",
+                "text".to_string().into(),
+                EmbeddingPurpose::Query,
+            )
+            .unwrap();
+
+        assert_eq!(
+            body,
+            json!({"model": "embed-english-v3.0", "texts": ["text"], "input_type": "search_query", "embedding_types": ["float"]})
+        );
+    }
+
+    #[test]
+    fn decodes_response() {
+        let by_type = br#"{"id": "1", "embeddings": {"float": [[0.5, -1.0]]}, "texts": ["text"]}"#;
+        let bare = br#"{"id": "1", "embeddings": [[0.5, -1.0]], "texts": ["text"]}"#;
+
+        assert_eq!(
+            Cohere.decode_response(by_type).unwrap(),
+            vec![vec![0.5, -1.0]]
+        );
+        assert_eq!(Cohere.decode_response(bare).unwrap(), vec![vec![0.5, -1.0]]);
+    }
+
+    #[test]
+    fn decodes_error() {
+        let error = Cohere.decode_error(401, br#"{"message": "invalid api token"}"#);
+
+        assert_eq!(
+            error.to_string(),
+            "Cohere request failed with status 401: invalid api token"
+        );
+    }
+}
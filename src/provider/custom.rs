@@ -0,0 +1,416 @@
+use anyhow::{anyhow, Context, Result};
+use serde_json::Value;
+
+use super::EmbeddingProvider;
+use crate::embedding::EmbeddingInput;
+use crate::embedding::EmbeddingPurpose;
+use crate::error::ConfigurationError;
+
+const MODEL_PLACEHOLDER: &str = "{{model}}";
+const INPUT_PLACEHOLDER: &str = "{{input}}";
+const INPUT_TYPE_PLACEHOLDER: &str = "{{input_type}}";
+
+/// Any JSON endpoint, described by a request body template and a path to the
+/// embeddings in its response
+#[derive(Debug, Clone)]
+pub struct Custom {
+    template: Value,
+    path: JsonPath,
+    batch_size: usize,
+}
+
+impl Custom {
+    pub const NAME: &'static str = "Custom";
+
+    /// `template` is a JSON body in which the string `"{{input}}"` is replaced
+    /// by the array of inputs, and `{{model}}` and `{{input_type}}` by the
+    /// model and by `document` or `query` inside any string.
+    /// `path` locates the embeddings in the response, see `JsonPath`, and
+    /// `batch_size` is the most inputs sent in one request.
+    pub fn new(template: &str, path: &str, batch_size: u32) -> Result<Self, ConfigurationError> {
+        let template: Value = serde_json::from_str(template).map_err(|e| {
+            ConfigurationError::new("Custom request template", format!("not valid JSON: {}", e))
+        })?;
+        if !contains_input_placeholder(&template) {
+            return Err(ConfigurationError::new(
+                "Custom request template",
+                format!("must contain \"{}\" where the inputs go", INPUT_PLACEHOLDER),
+            ));
+        }
+        let path = JsonPath::parse(path)
+            .map_err(|e| ConfigurationError::new("Custom response path", e.to_string()))?;
+        if batch_size == 0 {
+            return Err(ConfigurationError::new(
+                "Custom batch size",
+                "must be at least 1".to_string(),
+            ));
+        }
+        Ok(Self {
+            template,
+            path,
+            batch_size: batch_size as usize,
+        })
+    }
+}
+
+fn contains_input_placeholder(value: &Value) -> bool {
+    match value {
+        Value::String(s) => s == INPUT_PLACEHOLDER,
+        Value::Array(values) => values.iter().any(contains_input_placeholder),
+        Value::Object(map) => map.values().any(contains_input_placeholder),
+        _ => false,
+    }
+}
+
+fn fill_template(value: &Value, model: &str, input: &Value, input_type: &str) -> Value {
+    match value {
+        Value::String(s) if s == INPUT_PLACEHOLDER => input.clone(),
+        Value::String(s) => Value::String(
+            s.replace(MODEL_PLACEHOLDER, model)
+                .replace(INPUT_TYPE_PLACEHOLDER, input_type),
+        ),
+        Value::Array(values) => Value::Array(
+            values
+                .iter()
+                .map(|v| fill_template(v, model, input, input_type))
+                .collect(),
+        ),
+        Value::Object(map) => Value::Object(
+            map.iter()
+                .map(|(k, v)| (k.to_string(), fill_template(v, model, input, input_type)))
+                .collect(),
+        ),
+        other => other.clone(),
+    }
+}
+
+impl EmbeddingProvider for Custom {
+    fn name(&self) -> &'static str {
+        Self::NAME
+    }
+
+    fn request_body(
+        &self,
+        model: &str,
+        input: EmbeddingInput,
+        purpose: EmbeddingPurpose,
+    ) -> Result<serde_json::Value> {
+        let input = serde_json::to_value(input)?;
+        let input_type = match purpose {
+            EmbeddingPurpose::Document => "document",
+            EmbeddingPurpose::Query => "query",
+        };
+        Ok(fill_template(&self.template, model, &input, input_type))
+    }
+
+    fn decode_response(&self, body: &[u8]) -> Result<Vec<Vec<f32>>> {
+        let response: Value =
+            serde_json::from_slice(body).context("Custom embedding response is not JSON")?;
+        self.path.embeddings(&response)
+    }
+
+    /// The API's limit is unknown, so it is set by the user
+    fn max_batch_size(&self) -> usize {
+        self.batch_size
+    }
+
+    fn max_input_tokens(&self) -> Option<usize> {
+        None
+    }
+
+    fn max_tokens_per_request(&self) -> Option<usize> {
+        None
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Segment {
+    Key(String),
+    Index(usize),
+    /// Every element of an array
+    Each,
+}
+
+/// A dot separated path into a JSON document such as `data[*].embedding` or
+/// `result.vectors`, where `[n]` selects an array element and `[*]` every
+/// element. It must resolve either to an array of embeddings or, through `[*]`,
+/// to one embedding per element.
+#[derive(Debug, Clone, PartialEq)]
+pub struct JsonPath {
+    source: String,
+    segments: Vec<Segment>,
+}
+
+impl JsonPath {
+    pub fn parse(path: &str) -> Result<Self> {
+        let path = path.trim();
+        let mut segments = Vec::new();
+        for part in path
+            .trim_start_matches('$')
+            .split('.')
+            .filter(|part| !part.is_empty())
+        {
+            let (key, mut indices) = match part.find('[') {
+                Some(start) => part.split_at(start),
+                None => (part, ""),
+            };
+            if !key.is_empty() {
+                segments.push(Segment::Key(key.to_string()));
+            }
+            while !indices.is_empty() {
+                let end = indices
+                    .find(']')
+                    .filter(|_| indices.starts_with('['))
+                    .ok_or_else(|| anyhow!("'{}' has an unclosed or misplaced '['", path))?;
+                segments.push(match &indices[1..end] {
+                    "*" => Segment::Each,
+                    index => {
+                        Segment::Index(index.parse().map_err(|_| {
+                            anyhow!("'{}' is not an array index in '{}'", index, path)
+                        })?)
+                    }
+                });
+                indices = &indices[end + 1..];
+            }
+        }
+        if segments.is_empty() {
+            return Err(anyhow!("path to the embeddings is empty"));
+        }
+        Ok(Self {
+            source: path.to_string(),
+            segments,
+        })
+    }
+
+    /// Embeddings found at this path in `document`, in document order
+    pub fn embeddings(&self, document: &Value) -> Result<Vec<Vec<f32>>> {
+        let mut current: Vec<(String, &Value)> = vec![("$".to_string(), document)];
+        for segment in &self.segments {
+            let mut next = Vec::with_capacity(current.len());
+            for (location, value) in current {
+                match (segment, value) {
+                    (Segment::Key(key), Value::Object(map)) => match map.get(key) {
+                        Some(child) => next.push((format!("{}.{}", location, key), child)),
+                        None => {
+                            return Err(self.error(&format!("no key '{}' at {}", key, location)))
+                        }
+                    },
+                    (Segment::Index(i), Value::Array(values)) => match values.get(*i) {
+                        Some(child) => next.push((format!("{}[{}]", location, i), child)),
+                        None => {
+                            return Err(self.error(&format!(
+                                "index {} is out of bounds for {} elements at {}",
+                                i,
+                                values.len(),
+                                location
+                            )))
+                        }
+                    },
+                    (Segment::Each, Value::Array(values)) => next.extend(
+                        values
+                            .iter()
+                            .enumerate()
+                            .map(|(i, child)| (format!("{}[{}]", location, i), child)),
+                    ),
+                    (Segment::Key(_), other) => {
+                        return Err(self.error(&format!(
+                            "expected an object at {}, found {}",
+                            location,
+                            kind(other)
+                        )))
+                    }
+                    (_, other) => {
+                        return Err(self.error(&format!(
+                            "expected an array at {}, found {}",
+                            location,
+                            kind(other)
+                        )))
+                    }
+                }
+            }
+            current = next;
+        }
+
+        let fans_out = self.segments.contains(&Segment::Each);
+        if fans_out {
+            current
+                .into_iter()
+                .map(|(location, value)| self.vector(&location, value))
+                .collect()
+        } else {
+            let (location, value) = &current[0];
+            match value {
+                Value::Array(values) => values
+                    .iter()
+                    .enumerate()
+                    .map(|(i, value)| self.vector(&format!("{}[{}]", location, i), value))
+                    .collect(),
+                other => Err(self.error(&format!(
+                    "expected an array of embeddings at {}, found {}",
+                    location,
+                    kind(other)
+                ))),
+            }
+        }
+    }
+
+    fn vector(&self, location: &str, value: &Value) -> Result<Vec<f32>> {
+        let values = value.as_array().ok_or_else(|| {
+            self.error(&format!(
+                "expected an embedding at {}, found {}",
+                location,
+                kind(value)
+            ))
+        })?;
+        values
+            .iter()
+            .map(|x| x.as_f64().map(|x| x as f32))
+            .collect::<Option<Vec<f32>>>()
+            .ok_or_else(|| {
+                self.error(&format!(
+                    "embedding at {} contains a value that is not a number",
+                    location
+                ))
+            })
+    }
+
+    fn error(&self, reason: &str) -> anyhow::Error {
+        anyhow!(
+            "Custom response path '{}' did not resolve: {}",
+            self.source,
+            reason
+        )
+    }
+}
+
+fn kind(value: &Value) -> &'static str {
+    match value {
+        Value::Null => "null",
+        Value::Bool(_) => "a boolean",
+        Value::Number(_) => "a number",
+        Value::String(_) => "a string",
+        Value::Array(_) => "an array",
+        Value::Object(_) => "an object",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn resolve(path: &str, document: Value) -> Result<Vec<Vec<f32>>> {
+        JsonPath::parse(path).unwrap().embeddings(&document)
+    }
+
+    #[test]
+    fn fills_template() {
+        let custom = Custom::new(
+            r#"{"model": "{{model}}", "options": {"texts": "{{input}}", "tag": "{{input_type}} via {{model}}"}}"#,
+            "embeddings",
+            96,
+        )
+        .unwrap();
+
+        let body = custom
+            .request_body(
+                "e5",
+                vec!["a".to_string(), "b".to_string()].into(),
+                EmbeddingPurpose::Query,
+            )
+            .unwrap();
+
+        assert_eq!(
+            body,
+            json!({"model": "e5", "options": {"texts": ["a", "b"], "tag": "query via e5"}})
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_templates() {
+        assert!(Custom::new("{model: e5}", "embeddings", 96)
+            .unwrap_err()
+            .to_string()
+            .contains("not valid JSON"));
+        assert!(Custom::new(r#"{"input": "texts"}"#, "embeddings", 96)
+            .unwrap_err()
+            .to_string()
+            .contains("{{input}}"));
+        assert!(Custom::new(r#"{"input": "{{input}}"}"#, " ", 96)
+            .unwrap_err()
+            .to_string()
+            .contains("empty"));
+        assert!(Custom::new(r#"{"input": "{{input}}"}"#, "embeddings", 0)
+            .unwrap_err()
+            .to_string()
+            .contains("at least 1"));
+    }
+
+    #[test]
+    fn resolves_array_of_embeddings() {
+        let document = json!({"result": {"vectors": [[1.0, 2.0], [3.0, 4.0]]}});
+
+        assert_eq!(
+            resolve("result.vectors", document).unwrap(),
+            vec![vec![1.0, 2.0], vec![3.0, 4.0]]
+        );
+    }
+
+    #[test]
+    fn resolves_wildcards() {
+        let document = json!({"data": [{"embedding": [1.0]}, {"embedding": [2.0]}]});
+
+        assert_eq!(
+            resolve("$.data[*].embedding", document).unwrap(),
+            vec![vec![1.0], vec![2.0]]
+        );
+    }
+
+    #[test]
+    fn resolves_top_level_array() {
+        let document = json!([[[1.0, 2.0]], [[3.0, 4.0]]]);
+
+        assert_eq!(
+            resolve("[*][0]", document).unwrap(),
+            vec![vec![1.0, 2.0], vec![3.0, 4.0]]
+        );
+    }
+
+    #[test]
+    fn reports_where_resolution_failed() {
+        let document = json!({"data": [{"vector": [1.0]}]});
+
+        let error = resolve("data[*].embedding", document.clone())
+            .unwrap_err()
+            .to_string();
+        assert!(
+            error.contains("no key 'embedding' at $.data[0]"),
+            "{}",
+            error
+        );
+
+        let error = resolve("data[3]", document.clone())
+            .unwrap_err()
+            .to_string();
+        assert!(
+            error.contains("index 3 is out of bounds for 1 elements at $.data"),
+            "{}",
+            error
+        );
+
+        let error = resolve("data[*].vector[*]", document)
+            .unwrap_err()
+            .to_string();
+        assert!(
+            error.contains("expected an embedding at $.data[0].vector[0], found a number"),
+            "{}",
+            error
+        );
+    }
+
+    #[test]
+    fn rejects_malformed_paths() {
+        assert!(JsonPath::parse("data[x]").is_err());
+        assert!(JsonPath::parse("data[0").is_err());
+    }
+}
@@ -0,0 +1,258 @@
+use anyhow::{anyhow, Context, Result};
+use reqwest::RequestBuilder;
+use reqwest::Url;
+use serde::Deserialize;
+use serde_json::json;
+
+use super::raw_error;
+use super::EmbeddingProvider;
+use super::Endpoint;
+use crate::embedding::EmbeddingInput;
+use crate::embedding::EmbeddingPurpose;
+
+/// Google's `batchEmbedContents` API. The API URL is the API root, e.g.
+/// `https://generativelanguage.googleapis.com/v1beta`, to which
+/// `/models/{model}:batchEmbedContents` is appended.
+#[derive(Debug, Clone, Copy)]
+pub struct Gemini;
+
+impl Gemini {
+    pub const NAME: &'static str = "Gemini";
+
+    /// Keeps a URL that already names the method as it is
+    fn batch_url(&self, endpoint: &Endpoint) -> Result<Url> {
+        let mut url = Url::parse(endpoint.url)
+            .context(format!("Invalid Gemini endpoint {}", endpoint.url))?;
+        if !url.path().ends_with(":batchEmbedContents") {
+            let method = format!("{}:batchEmbedContents", model_id(endpoint.model));
+            url.path_segments_mut()
+                .map_err(|_| anyhow!("Invalid Gemini endpoint {}", endpoint.url))?
+                .pop_if_empty()
+                .extend(&["models", &method]);
+        }
+        if !endpoint.api_key.is_empty() {
+            url.query_pairs_mut().append_pair("key", endpoint.api_key);
+        }
+        Ok(url)
+    }
+}
+
+/// The model without the `models/` prefix Gemini's resource names carry
+fn model_id(model: &str) -> &str {
+    model.strip_prefix("models/").unwrap_or(model)
+}
+
+#[derive(Debug, Deserialize)]
+struct GeminiEmbeddingResponse {
+    embeddings: Vec<ContentEmbedding>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ContentEmbedding {
+    values: Vec<f32>,
+}
+
+/// `{"error": {"code": 400, "message": "...", "status": "INVALID_ARGUMENT"}}`
+#[derive(Debug, Deserialize)]
+struct GeminiErrorEnvelope {
+    error: GeminiError,
+}
+
+#[derive(Debug, Deserialize)]
+struct GeminiError {
+    message: String,
+    status: Option<String>,
+}
+
+impl EmbeddingProvider for Gemini {
+    fn name(&self) -> &'static str {
+        Self::NAME
+    }
+
+    fn request_body(
+        &self,
+        model: &str,
+        input: EmbeddingInput,
+        purpose: EmbeddingPurpose,
+    ) -> Result<serde_json::Value> {
+        let task_type = match purpose {
+            EmbeddingPurpose::Document => "RETRIEVAL_DOCUMENT",
+            EmbeddingPurpose::Query => "RETRIEVAL_QUERY",
+        };
+        let model = format!("models/{}", model_id(model));
+        let EmbeddingInput::StringArray(texts) = input;
+        let requests: Vec<serde_json::Value> = texts
+            .into_iter()
+            .map(|text| {
+                json!({
+                    "model": model,
+                    "content": {"parts": [{"text": text}]},
+                    "taskType": task_type,
+                })
+            })
+            .collect();
+        Ok(json!({ "requests": requests }))
+    }
+
+    fn request_dimensions(&self, body: &mut serde_json::Value, dimensions: u32) {
+        if let Some(requests) = body["requests"].as_array_mut() {
+            requests
+                .iter_mut()
+                .for_each(|request| request["outputDimensionality"] = json!(dimensions));
+        }
+    }
+
+    /// The key is sent as the `key` query parameter instead
+    fn authenticate(&self, request: RequestBuilder, _api_key: &str) -> RequestBuilder {
+        request
+    }
+
+    fn build_request(
+        &self,
+        http: &reqwest::Client,
+        endpoint: Endpoint,
+        input: EmbeddingInput,
+        purpose: EmbeddingPurpose,
+    ) -> Result<reqwest::Request> {
+        let url = self.batch_url(&endpoint)?;
+        let body = self.request_json(&endpoint, input, purpose)?;
+        self.authenticate(http.post(url), endpoint.api_key)
+            .json(&body)
+            .build()
+            .context("Failed to build embedding request")
+    }
+
+    fn decode_response(&self, body: &[u8]) -> Result<Vec<Vec<f32>>> {
+        let response: GeminiEmbeddingResponse = serde_json::from_slice(body)
+            .context("Failed deserializing Gemini embedding response")?;
+        Ok(response.embeddings.into_iter().map(|x| x.values).collect())
+    }
+
+    fn decode_error(&self, status: u16, body: &[u8]) -> anyhow::Error {
+        match serde_json::from_slice::<GeminiErrorEnvelope>(body) {
+            Ok(GeminiErrorEnvelope { error }) => match error.status {
+                Some(code) => anyhow!(
+                    "Gemini request failed with status {} ({}): {}",
+                    status,
+                    code,
+                    error.message
+                ),
+                None => anyhow!(
+                    "Gemini request failed with status {}: {}",
+                    status,
+                    error.message
+                ),
+            },
+            Err(_) => raw_error("Gemini", status, body),
+        }
+    }
+
+    fn max_batch_size(&self) -> usize {
+        100
+    }
+
+    fn max_input_tokens(&self) -> Option<usize> {
+        Some(2048)
+    }
+
+    fn max_tokens_per_request(&self) -> Option<usize> {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn endpoint(url: &str) -> Endpoint<'_> {
+        Endpoint {
+            url,
+            api_key: "secret",
+            model: "text-embedding-004",
+            dimensions: Some(256),
+        }
+    }
+
+    #[test]
+    fn builds_batch_request() {
+        let request = Gemini
+            .build_request(
+                &reqwest::Client::new(),
+                endpoint("https://generativelanguage.googleapis.com/v1beta"),
+                vec!["a".to_string(), "b".to_string()].into(),
+                EmbeddingPurpose::Query,
+            )
+            .unwrap();
+
+        assert_eq!(
+            request.url().as_str(),
+            "https://generativelanguage.googleapis.com/v1beta/models/text-embedding-004:batchEmbedContents?key=secret"
+        );
+        assert!(request.headers().get("authorization").is_none());
+        let body: serde_json::Value =
+            serde_json::from_slice(request.body().unwrap().as_bytes().unwrap()).unwrap();
+        assert_eq!(body["requests"].as_array().unwrap().len(), 2);
+        assert_eq!(
+            body["requests"][1],
+            json!({
+                "model": "models/text-embedding-004",
+                "content": {"parts": [{"text": "b"}]},
+                "taskType": "RETRIEVAL_QUERY",
+                "outputDimensionality": 256,
+            })
+        );
+    }
+
+    #[test]
+    fn keeps_method_url() {
+        let url =
+            "https://generativelanguage.googleapis.com/v1/models/embedding-001:batchEmbedContents";
+        let mut endpoint = endpoint(url);
+        endpoint.api_key = "";
+
+        assert_eq!(Gemini.batch_url(&endpoint).unwrap().as_str(), url);
+    }
+
+    #[test]
+    fn omits_default_dimensionality() {
+        let mut endpoint = endpoint("https://generativelanguage.googleapis.com/v1beta");
+        endpoint.model = "models/text-embedding-004";
+        endpoint.dimensions = None;
+        let body = Gemini
+            .request_json(
+                &endpoint,
+                "a".to_string().into(),
+                EmbeddingPurpose::Document,
+            )
+            .unwrap();
+
+        assert_eq!(
+            body["requests"][0],
+            json!({
+                "model": "models/text-embedding-004",
+                "content": {"parts": [{"text": "a"}]},
+                "taskType": "RETRIEVAL_DOCUMENT",
+            })
+        );
+    }
+
+    #[test]
+    fn decodes_response() {
+        let body = br#"{"embeddings": [{"values": [0.5, -1.0]}, {"values": [2.0, 0.0]}]}"#;
+
+        assert_eq!(
+            Gemini.decode_response(body).unwrap(),
+            vec![vec![0.5, -1.0], vec![2.0, 0.0]]
+        );
+    }
+
+    #[test]
+    fn decodes_error() {
+        let body = br#"{"error": {"code": 400, "message": "API key not valid. Please pass a valid API key.", "status": "INVALID_ARGUMENT"}}"#;
+
+        assert_eq!(
+            Gemini.decode_error(400, body).to_string(),
+            "Gemini request failed with status 400 (INVALID_ARGUMENT): API key not valid. Please pass a valid API key."
+        );
+    }
+}
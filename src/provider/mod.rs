@@ -0,0 +1,442 @@
+mod azure;
+mod cohere;
+mod custom;
+mod gemini;
+#[cfg(test)]
+mod mock_server;
+mod ollama;
+mod openai;
+mod tei;
+mod voyage;
+
+use std::convert::TryFrom;
+use std::rc::Rc;
+
+use anyhow::{anyhow, Context, Result};
+use lazy_static::lazy_static;
+use regex::Regex;
+use reqwest::RequestBuilder;
+
+use crate::embedding::EmbeddingInput;
+use crate::embedding::EmbeddingPurpose;
+use crate::error::ConfigurationError;
+use crate::error::ErrorKind;
+use crate::error::HttpError;
+use crate::error::SemanticSearchError;
+use crate::error::WrappedError;
+use crate::obsidian::semanticSearchSettings;
+use crate::retry;
+
+pub use azure::Azure;
+pub use cohere::Cohere;
+pub use custom::Custom;
+pub use gemini::Gemini;
+pub use ollama::Ollama;
+pub use openai::OpenAI;
+pub use tei::Tei;
+pub use voyage::Voyage;
+
+/// Names accepted by the "API response type" setting
+pub const SUPPORTED_PROVIDERS: &[&str] = &[
+    Ollama::NAME,
+    OpenAI::NAME,
+    Tei::NAME,
+    Cohere::NAME,
+    Voyage::NAME,
+    Azure::NAME,
+    Gemini::NAME,
+    Custom::NAME,
+];
+
+/// Where and as whom embedding requests are sent
+#[derive(Debug, Clone, Copy)]
+pub struct Endpoint<'a> {
+    pub url: &'a str,
+    pub api_key: &'a str,
+    pub model: &'a str,
+    /// Requested length of the embeddings, the model's default if `None`
+    pub dimensions: Option<u32>,
+}
+
+/// An embedding API: how requests to it are built and authenticated, and how
+/// its responses and errors are decoded.
+pub trait EmbeddingProvider: std::fmt::Debug {
+    /// Name recorded in the index header, changing it invalidates existing
+    /// embeddings
+    fn name(&self) -> &'static str;
+
+    /// JSON body requesting embeddings of `input`
+    fn request_body(
+        &self,
+        model: &str,
+        input: EmbeddingInput,
+        purpose: EmbeddingPurpose,
+    ) -> Result<serde_json::Value>;
+
+    /// Asks for embeddings of `dimensions` dimensions. APIs that cannot
+    /// shorten embeddings leave the body as it is and the client truncates
+    /// them instead.
+    fn request_dimensions(&self, _body: &mut serde_json::Value, _dimensions: u32) {}
+
+    /// The request body for `endpoint`, including the requested dimensions
+    fn request_json(
+        &self,
+        endpoint: &Endpoint,
+        input: EmbeddingInput,
+        purpose: EmbeddingPurpose,
+    ) -> Result<serde_json::Value> {
+        let mut body = self.request_body(endpoint.model, input, purpose)?;
+        if let Some(dimensions) = endpoint.dimensions {
+            self.request_dimensions(&mut body, dimensions);
+        }
+        Ok(body)
+    }
+
+    /// Sends the API key as a bearer token unless it is empty
+    fn authenticate(&self, request: RequestBuilder, api_key: &str) -> RequestBuilder {
+        if api_key.is_empty() {
+            request
+        } else {
+            request.bearer_auth(api_key)
+        }
+    }
+
+    fn build_request(
+        &self,
+        http: &reqwest::Client,
+        endpoint: Endpoint,
+        input: EmbeddingInput,
+        purpose: EmbeddingPurpose,
+    ) -> Result<reqwest::Request> {
+        let body = self.request_json(&endpoint, input, purpose)?;
+        self.authenticate(http.post(endpoint.url), endpoint.api_key)
+            .json(&body)
+            .build()
+            .context("Failed to build embedding request")
+    }
+
+    /// One embedding per input, in input order
+    fn decode_response(&self, body: &[u8]) -> Result<Vec<Vec<f32>>>;
+
+    /// Error for a response with a non-success status. Understands the
+    /// common shapes of JSON error bodies, falling back to the raw body.
+    fn decode_error(&self, status: u16, body: &[u8]) -> anyhow::Error {
+        match json_error_message(body) {
+            Some(message) => anyhow!(
+                "{} request failed with status {}: {}",
+                self.name(),
+                status,
+                message
+            ),
+            None => raw_error(self.name(), status, body),
+        }
+    }
+
+    /// Most inputs accepted in one request
+    fn max_batch_size(&self) -> usize;
+
+    /// Most tokens accepted per input, if the API has a fixed limit
+    fn max_input_tokens(&self) -> Option<usize>;
+
+    /// Most tokens accepted across all inputs of one request, if the API
+    /// has a fixed limit
+    fn max_tokens_per_request(&self) -> Option<usize>;
+}
+
+/// Longest part of an unrecognized error body included in the error
+const MAX_RAW_ERROR_CHARS: usize = 500;
+
+/// The message of a JSON error body in one of the shapes APIs commonly use:
+/// `{"error": {"message": ..}}`, `{"error": ".."}`, `{"message": ".."}` or
+/// `{"detail": ".."}`
+fn json_error_message(body: &[u8]) -> Option<String> {
+    if let Ok(wrapped) = serde_json::from_slice::<WrappedError>(body) {
+        return Some(wrapped.to_string());
+    }
+    let body: serde_json::Value = serde_json::from_slice(body).ok()?;
+    ["error", "message", "detail"]
+        .iter()
+        .find_map(|key| body.get(key).and_then(|value| value.as_str()))
+        .map(str::to_string)
+}
+
+/// Error for a response whose body is not an error the provider documents,
+/// such as the plain text or HTML page of a proxy, with the status and as much
+/// of the body as is useful
+pub(crate) fn raw_error(provider: &str, status: u16, body: &[u8]) -> anyhow::Error {
+    let text = String::from_utf8_lossy(body);
+    let text = text.trim();
+    if text.is_empty() {
+        let reason = reqwest::StatusCode::from_u16(status)
+            .ok()
+            .and_then(|status| status.canonical_reason())
+            .unwrap_or("unknown status");
+        return anyhow!(
+            "{} request failed with status {} ({}) and an empty response",
+            provider,
+            status,
+            reason
+        );
+    }
+    if is_html(text) {
+        return match html_title(text) {
+            Some(title) => anyhow!(
+                "{} request failed with status {} and an HTML page titled '{}'",
+                provider,
+                status,
+                title
+            ),
+            None => anyhow!(
+                "{} request failed with status {} and an HTML page",
+                provider,
+                status
+            ),
+        };
+    }
+    let mut detail: String = text.chars().take(MAX_RAW_ERROR_CHARS).collect();
+    if detail.len() < text.len() {
+        detail.push('…');
+    }
+    anyhow!("{} request failed with status {}: {}", provider, status, detail)
+}
+
+fn is_html(text: &str) -> bool {
+    let start: String = text.chars().take(100).collect::<String>().to_lowercase();
+    start.starts_with("<!doctype html") || start.starts_with("<html")
+}
+
+fn html_title(html: &str) -> Option<String> {
+    lazy_static! {
+        static ref TITLE_REGEX: Regex = Regex::new(r"(?is)<title[^>]*>(.*?)</title>").unwrap();
+    }
+    TITLE_REGEX
+        .captures(html)
+        .map(|captures| captures[1].split_whitespace().collect::<Vec<_>>().join(" "))
+        .filter(|title| !title.is_empty())
+}
+
+/// Sends one embedding request and decodes the response
+pub async fn request_embeddings(
+    provider: &dyn EmbeddingProvider,
+    http: &reqwest::Client,
+    endpoint: Endpoint<'_>,
+    input: EmbeddingInput,
+    purpose: EmbeddingPurpose,
+) -> Result<Vec<Vec<f32>>> {
+    let request = provider.build_request(http, endpoint, input, purpose)?;
+    let response = http
+        .execute(request)
+        .await
+        .context(format!("Failed POST request to {}", endpoint.url))?;
+
+    let status = response.status();
+    let retry_after_ms = retry::retry_after_ms(response.headers());
+    let bytes = response.bytes().await?;
+
+    if !status.is_success() {
+        return Err(HttpError {
+            status: status.as_u16(),
+            retry_after_ms,
+            source: provider.decode_error(status.as_u16(), bytes.as_ref()),
+        }
+        .into());
+    }
+
+    provider
+        .decode_response(bytes.as_ref())
+        .map_err(|e| SemanticSearchError::new(ErrorKind::Provider, e).into())
+}
+
+/// The provider selected by the "API response type" setting
+pub fn from_settings(
+    settings: &semanticSearchSettings,
+) -> Result<Rc<dyn EmbeddingProvider>, ConfigurationError> {
+    match settings.apiResponseType().as_str() {
+        Custom::NAME => Ok(Rc::new(Custom::new(
+            &settings.customRequestTemplate(),
+            &settings.customResponsePath(),
+            settings.customBatchSize(),
+        )?)),
+        Tei::NAME => Ok(Rc::new(Tei::new(
+            settings.teiTruncate(),
+            settings.teiNormalize(),
+        ))),
+        Azure::NAME => Ok(Rc::new(Azure::new(&settings.azureApiVersion()))),
+        name => from_name(name),
+    }
+}
+
+/// The "Max token length" setting, capped to the input limit of the selected
+/// provider
+pub fn max_token_length(settings: &semanticSearchSettings) -> u32 {
+    let setting = settings.maxTokenLength();
+    match from_settings(settings)
+        .ok()
+        .and_then(|p| p.max_input_tokens())
+    {
+        Some(limit) => setting.min(u32::try_from(limit).unwrap_or(u32::MAX)),
+        None => setting,
+    }
+}
+
+/// The "Embedding dimensions" setting, where 0 keeps the model's default
+pub fn embedding_dimensions(settings: &semanticSearchSettings) -> Option<u32> {
+    Some(settings.embeddingDimensions()).filter(|dimensions| *dimensions > 0)
+}
+
+/// Providers that need no settings besides the API URL, key and model
+fn from_name(name: &str) -> Result<Rc<dyn EmbeddingProvider>, ConfigurationError> {
+    match name {
+        Ollama::NAME => Ok(Rc::new(Ollama)),
+        OpenAI::NAME => Ok(Rc::new(OpenAI)),
+        Cohere::NAME => Ok(Rc::new(Cohere)),
+        Voyage::NAME => Ok(Rc::new(Voyage)),
+        Gemini::NAME => Ok(Rc::new(Gemini)),
+        other => Err(ConfigurationError::new(
+            "API response type",
+            format!(
+                "'{}' is not a supported API, expected one of: {}",
+                other,
+                SUPPORTED_PROVIDERS.join(", ")
+            ),
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn endpoint(api_key: &str) -> Endpoint<'_> {
+        Endpoint {
+            url: "http://localhost:11434/api/embed",
+            api_key,
+            model: "nomic-embed-text",
+            dimensions: None,
+        }
+    }
+
+    #[test]
+    fn resolves_supported_names() {
+        for name in [
+            Ollama::NAME,
+            OpenAI::NAME,
+            Cohere::NAME,
+            Voyage::NAME,
+            Gemini::NAME,
+        ] {
+            assert_eq!(from_name(name).unwrap().name(), name);
+        }
+    }
+
+    #[test]
+    fn rejects_unknown_name() {
+        let error = from_name("Mistral").unwrap_err();
+
+        assert_eq!(error.setting, "API response type");
+        assert!(error.to_string().contains("Mistral"));
+        assert!(error.to_string().contains("Ollama, OpenAI"));
+    }
+
+    #[test]
+    fn builds_authenticated_request() {
+        let request = OpenAI
+            .build_request(
+                &reqwest::Client::new(),
+                endpoint("secret"),
+                "text".to_string().into(),
+                EmbeddingPurpose::Document,
+            )
+            .unwrap();
+
+        assert_eq!(request.method(), reqwest::Method::POST);
+        assert_eq!(request.headers()["authorization"], "Bearer secret");
+        let body: serde_json::Value =
+            serde_json::from_slice(request.body().unwrap().as_bytes().unwrap()).unwrap();
+        assert_eq!(
+            body,
+            serde_json::json!({"model": "nomic-embed-text", "input": ["text"]})
+        );
+    }
+
+    #[test]
+    fn omits_empty_api_key() {
+        let request = Ollama
+            .build_request(
+                &reqwest::Client::new(),
+                endpoint(""),
+                "text".to_string().into(),
+                EmbeddingPurpose::Document,
+            )
+            .unwrap();
+
+        assert!(request.headers().get("authorization").is_none());
+    }
+
+    #[test]
+    fn decodes_openai_style_errors() {
+        let body = br#"{"error": {"message": "Invalid key", "type": "auth", "param": null, "code": null}}"#;
+
+        assert!(OpenAI
+            .decode_error(401, body)
+            .to_string()
+            .contains("Invalid key"));
+        assert!(Ollama
+            .decode_error(500, b"Internal Server Error")
+            .to_string()
+            .contains("status 500: Internal Server Error"));
+    }
+
+    #[test]
+    fn decodes_common_json_errors() {
+        let custom = Custom::new(r#"{"input": "{{input}}"}"#, "embeddings", 96).unwrap();
+
+        assert_eq!(
+            custom
+                .decode_error(400, br#"{"error": "input too long"}"#)
+                .to_string(),
+            "Custom request failed with status 400: input too long"
+        );
+        assert_eq!(
+            custom
+                .decode_error(422, br#"{"detail": "field required"}"#)
+                .to_string(),
+            "Custom request failed with status 422: field required"
+        );
+        assert_eq!(
+            custom
+                .decode_error(401, br#"{"error": {"message": "Invalid key"}}"#)
+                .to_string(),
+            "Custom request failed with status 401: Invalid key"
+        );
+    }
+
+    #[test]
+    fn describes_empty_error_bodies() {
+        assert_eq!(
+            raw_error("Ollama", 502, b"").to_string(),
+            "Ollama request failed with status 502 (Bad Gateway) and an empty response"
+        );
+    }
+
+    #[test]
+    fn describes_html_error_pages() {
+        let page = b"<!DOCTYPE html>\n<html><head><title>\n  504 Gateway Time-out\n</title></head><body>...</body></html>";
+
+        assert_eq!(
+            raw_error("OpenAI", 504, page).to_string(),
+            "OpenAI request failed with status 504 and an HTML page titled '504 Gateway Time-out'"
+        );
+    }
+
+    #[test]
+    fn truncates_long_error_bodies() {
+        let body = "x".repeat(2000);
+
+        let error = raw_error("TEI", 413, body.as_bytes()).to_string();
+
+        assert!(error.starts_with("TEI request failed with status 413: xxx"));
+        assert!(error.ends_with('…'));
+        assert!(error.len() < 600);
+    }
+}
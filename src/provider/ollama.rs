@@ -0,0 +1,104 @@
+use anyhow::{anyhow, Context, Result};
+use serde::Deserialize;
+
+use super::raw_error;
+use super::EmbeddingProvider;
+use crate::embedding::EmbeddingInput;
+use crate::embedding::EmbeddingPurpose;
+use crate::embedding::EmbeddingRequestBuilder;
+
+/// Ollama's `/api/embed` endpoint
+#[derive(Debug, Clone, Copy)]
+pub struct Ollama;
+
+impl Ollama {
+    pub const NAME: &'static str = "Ollama";
+}
+
+#[derive(Debug, Deserialize, Clone)]
+struct OllamaEmbeddingResponse {
+    embeddings: Vec<Vec<f32>>,
+}
+
+/// `{"error": "model \"nomic-embed-text\" not found, try pulling it first"}`
+#[derive(Debug, Deserialize)]
+struct OllamaError {
+    error: String,
+}
+
+impl EmbeddingProvider for Ollama {
+    fn name(&self) -> &'static str {
+        Self::NAME
+    }
+
+    fn request_body(
+        &self,
+        model: &str,
+        input: EmbeddingInput,
+        _purpose: EmbeddingPurpose,
+    ) -> Result<serde_json::Value> {
+        let request = EmbeddingRequestBuilder::default()
+            .model(model.to_string())
+            .input(input)
+            .build()
+            .context("Failed to build embedding request")?;
+        Ok(serde_json::to_value(request)?)
+    }
+
+    fn decode_response(&self, body: &[u8]) -> Result<Vec<Vec<f32>>> {
+        let response: OllamaEmbeddingResponse = serde_json::from_slice(body)
+            .context("Failed deserializing Ollama embedding response")?;
+        Ok(response.embeddings)
+    }
+
+    fn decode_error(&self, status: u16, body: &[u8]) -> anyhow::Error {
+        match serde_json::from_slice::<OllamaError>(body) {
+            Ok(error) => anyhow!(
+                "Ollama request failed with status {}: {}",
+                status,
+                error.error
+            ),
+            Err(_) => raw_error(Self::NAME, status, body),
+        }
+    }
+
+    /// Ollama has no documented limit, this keeps requests to a local
+    /// server reasonably sized
+    fn max_batch_size(&self) -> usize {
+        512
+    }
+
+    /// Depends on the context length of the model being served
+    fn max_input_tokens(&self) -> Option<usize> {
+        None
+    }
+
+    fn max_tokens_per_request(&self) -> Option<usize> {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decodes_response() {
+        let body = br#"{"model": "nomic-embed-text", "embeddings": [[0.5, -1.0], [2.0, 0.0]]}"#;
+
+        assert_eq!(
+            Ollama.decode_response(body).unwrap(),
+            vec![vec![0.5, -1.0], vec![2.0, 0.0]]
+        );
+    }
+
+    #[test]
+    fn decodes_error() {
+        let body = br#"{"error":"model \"nomic-embed-text\" not found, try pulling it first"}"#;
+
+        assert_eq!(
+            Ollama.decode_error(404, body).to_string(),
+            "Ollama request failed with status 404: model \"nomic-embed-text\" not found, try pulling it first"
+        );
+    }
+}
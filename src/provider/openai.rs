@@ -0,0 +1,123 @@
+use anyhow::{anyhow, Context, Result};
+use serde::Deserialize;
+
+use super::raw_error;
+use super::EmbeddingProvider;
+use crate::embedding::EmbeddingInput;
+use crate::embedding::EmbeddingPurpose;
+use crate::embedding::EmbeddingRequestBuilder;
+use crate::error::WrappedError;
+
+/// The OpenAI embeddings API, also served by many compatible endpoints
+#[derive(Debug, Clone, Copy)]
+pub struct OpenAI;
+
+impl OpenAI {
+    pub const NAME: &'static str = "OpenAI";
+}
+
+#[derive(Debug, Deserialize, Clone)]
+struct OpenAIEmbeddingResponse {
+    data: Vec<Embedding>,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+struct Embedding {
+    embedding: Vec<f32>,
+}
+
+impl EmbeddingProvider for OpenAI {
+    fn name(&self) -> &'static str {
+        Self::NAME
+    }
+
+    fn request_body(
+        &self,
+        model: &str,
+        input: EmbeddingInput,
+        _purpose: EmbeddingPurpose,
+    ) -> Result<serde_json::Value> {
+        let request = EmbeddingRequestBuilder::default()
+            .model(model.to_string())
+            .input(input)
+            .build()
+            .context("Failed to build embedding request")?;
+        Ok(serde_json::to_value(request)?)
+    }
+
+    fn request_dimensions(&self, body: &mut serde_json::Value, dimensions: u32) {
+        body["dimensions"] = serde_json::json!(dimensions);
+    }
+
+    fn decode_response(&self, body: &[u8]) -> Result<Vec<Vec<f32>>> {
+        let response: OpenAIEmbeddingResponse = serde_json::from_slice(body)
+            .context("Failed deserializing OpenAI embedding response")?;
+        Ok(response.data.into_iter().map(|x| x.embedding).collect())
+    }
+
+    fn decode_error(&self, status: u16, body: &[u8]) -> anyhow::Error {
+        match serde_json::from_slice::<WrappedError>(body) {
+            Ok(wrapped) => anyhow!(
+                "OpenAI request failed with status {}: {}",
+                status,
+                wrapped
+            ),
+            Err(_) => raw_error(Self::NAME, status, body),
+        }
+    }
+
+    fn max_batch_size(&self) -> usize {
+        2048
+    }
+
+    fn max_input_tokens(&self) -> Option<usize> {
+        Some(8191)
+    }
+
+    fn max_tokens_per_request(&self) -> Option<usize> {
+        Some(300_000)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn requests_dimensions() {
+        let endpoint = super::super::Endpoint {
+            url: "https://api.openai.com/v1/embeddings",
+            api_key: "",
+            model: "text-embedding-3-small",
+            dimensions: Some(512),
+        };
+        let body = OpenAI
+            .request_json(&endpoint, "text".to_string().into(), EmbeddingPurpose::Document)
+            .unwrap();
+
+        assert_eq!(
+            body,
+            serde_json::json!({"model": "text-embedding-3-small", "input": ["text"], "dimensions": 512})
+        );
+    }
+
+    #[test]
+    fn decodes_response() {
+        let body = br#"{"object": "list", "data": [{"index": 0, "embedding": [0.5, -1.0]}, {"index": 1, "embedding": [2.0, 0.0]}], "model": "text-embedding-3-small"}"#;
+
+        assert_eq!(
+            OpenAI.decode_response(body).unwrap(),
+            vec![vec![0.5, -1.0], vec![2.0, 0.0]]
+        );
+    }
+
+    #[test]
+    fn decodes_error() {
+        let body = br#"{"error": {"message": "Incorrect API key provided", "type": "invalid_request_error", "param": null, "code": "invalid_api_key"}}"#;
+
+        assert_eq!(
+            OpenAI.decode_error(401, body).to_string(),
+            "OpenAI request failed with status 401: Incorrect API key provided (type: invalid_request_error, code: invalid_api_key)"
+        );
+    }
+}
@@ -0,0 +1,171 @@
+use anyhow::{anyhow, Context, Result};
+use serde::Deserialize;
+use serde_json::json;
+
+use super::raw_error;
+use super::EmbeddingProvider;
+use crate::embedding::EmbeddingInput;
+use crate::embedding::EmbeddingPurpose;
+
+/// Hugging Face Text Embeddings Inference, whose `/embed` endpoint serves the
+/// single model the server was started with
+#[derive(Debug, Clone, Copy)]
+pub struct Tei {
+    /// Truncate inputs longer than the model's maximum instead of failing
+    truncate: bool,
+    /// Return L2-normalized embeddings
+    normalize: bool,
+}
+
+impl Tei {
+    pub const NAME: &'static str = "TEI";
+
+    pub fn new(truncate: bool, normalize: bool) -> Self {
+        Self {
+            truncate,
+            normalize,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct TeiError {
+    error: String,
+    error_type: Option<String>,
+}
+
+impl EmbeddingProvider for Tei {
+    fn name(&self) -> &'static str {
+        Self::NAME
+    }
+
+    fn request_body(
+        &self,
+        _model: &str,
+        input: EmbeddingInput,
+        _purpose: EmbeddingPurpose,
+    ) -> Result<serde_json::Value> {
+        Ok(json!({
+            "inputs": input,
+            "truncate": self.truncate,
+            "normalize": self.normalize,
+        }))
+    }
+
+    fn decode_response(&self, body: &[u8]) -> Result<Vec<Vec<f32>>> {
+        serde_json::from_slice(body).context("Failed deserializing TEI embedding response")
+    }
+
+    /// TEI answers with a JSON error object, but errors raised before a
+    /// request reaches the model, such as an oversized payload, are plain text
+    fn decode_error(&self, status: u16, body: &[u8]) -> anyhow::Error {
+        match serde_json::from_slice::<TeiError>(body) {
+            Ok(TeiError {
+                error,
+                error_type: Some(error_type),
+            }) => anyhow!(
+                "TEI request failed with status {} ({}): {}",
+                status,
+                error_type,
+                error
+            ),
+            Ok(TeiError { error, .. }) => {
+                anyhow!("TEI request failed with status {}: {}", status, error)
+            }
+            Err(_) => raw_error("TEI", status, body),
+        }
+    }
+
+    /// The server's default `--max-client-batch-size`
+    fn max_batch_size(&self) -> usize {
+        32
+    }
+
+    /// Depends on the model, longer inputs are truncated by the server if
+    /// `truncate` is set
+    fn max_input_tokens(&self) -> Option<usize> {
+        None
+    }
+
+    /// TEI splits requests into batches of its `--max-batch-tokens` itself
+    fn max_tokens_per_request(&self) -> Option<usize> {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::provider::mock_server::MockServer;
+    use crate::provider::request_embeddings;
+    use crate::provider::Endpoint;
+
+    async fn embed(server: &MockServer, input: Vec<String>) -> Result<Vec<Vec<f32>>> {
+        let endpoint = Endpoint {
+            url: &server.url,
+            api_key: "",
+            model: "BAAI/bge-small-en-v1.5",
+            dimensions: None,
+        };
+        request_embeddings(
+            &Tei::new(true, false),
+            &reqwest::Client::new(),
+            endpoint,
+            input.into(),
+            EmbeddingPurpose::Document,
+        )
+        .await
+    }
+
+    #[tokio::test]
+    async fn embeds_through_server() {
+        let server = MockServer::start(vec![(
+            200,
+            "application/json",
+            "[[0.5, -1.0], [2.0, 0.0]]".to_string(),
+        )]);
+
+        let embeddings = embed(&server, vec!["first".to_string(), "second".to_string()])
+            .await
+            .unwrap();
+
+        assert_eq!(embeddings, vec![vec![0.5, -1.0], vec![2.0, 0.0]]);
+        let request: serde_json::Value = serde_json::from_str(&server.requests()[0]).unwrap();
+        assert_eq!(
+            request,
+            json!({"inputs": ["first", "second"], "truncate": true, "normalize": false})
+        );
+    }
+
+    #[tokio::test]
+    async fn reports_json_errors() {
+        let body = r#"{"error": "Input validation error: inputs must have less than 512 tokens", "error_type": "Validation"}"#;
+        let server = MockServer::start(vec![(413, "application/json", body.to_string())]);
+
+        let error = embed(&server, vec!["long".to_string()])
+            .await
+            .unwrap_err()
+            .to_string();
+
+        assert_eq!(
+            error,
+            "TEI request failed with status 413 (Validation): Input validation error: inputs must have less than 512 tokens"
+        );
+    }
+
+    #[tokio::test]
+    async fn reports_plain_text_errors() {
+        let server =
+            MockServer::start(vec![(413, "text/plain", "Payload Too Large\n".to_string())]);
+
+        let error = embed(&server, vec!["long".to_string()])
+            .await
+            .unwrap_err()
+            .to_string();
+
+        assert_eq!(
+            error,
+            "TEI request failed with status 413: Payload Too Large"
+        );
+    }
+}
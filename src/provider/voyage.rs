@@ -0,0 +1,134 @@
+use anyhow::{anyhow, Context, Result};
+use serde::Deserialize;
+use serde_json::json;
+
+use super::raw_error;
+use super::EmbeddingProvider;
+use crate::embedding::EmbeddingInput;
+use crate::embedding::EmbeddingPurpose;
+
+/// Voyage AI's `/v1/embeddings` endpoint
+#[derive(Debug, Clone, Copy)]
+pub struct Voyage;
+
+impl Voyage {
+    pub const NAME: &'static str = "Voyage";
+}
+
+#[derive(Debug, Deserialize)]
+struct VoyageEmbeddingResponse {
+    data: Vec<VoyageEmbedding>,
+}
+
+#[derive(Debug, Deserialize)]
+struct VoyageEmbedding {
+    embedding: Vec<f32>,
+    index: usize,
+}
+
+#[derive(Debug, Deserialize)]
+struct VoyageError {
+    detail: String,
+}
+
+impl EmbeddingProvider for Voyage {
+    fn name(&self) -> &'static str {
+        Self::NAME
+    }
+
+    fn request_body(
+        &self,
+        model: &str,
+        input: EmbeddingInput,
+        purpose: EmbeddingPurpose,
+    ) -> Result<serde_json::Value> {
+        let input_type = match purpose {
+            EmbeddingPurpose::Document => "document",
+            EmbeddingPurpose::Query => "query",
+        };
+        Ok(json!({
+            "model": model,
+            "input": input,
+            "input_type": input_type,
+        }))
+    }
+
+    fn request_dimensions(&self, body: &mut serde_json::Value, dimensions: u32) {
+        body["output_dimension"] = json!(dimensions);
+    }
+
+    fn decode_response(&self, body: &[u8]) -> Result<Vec<Vec<f32>>> {
+        let mut response: VoyageEmbeddingResponse = serde_json::from_slice(body)
+            .context("Failed deserializing Voyage embedding response")?;
+        response.data.sort_by_key(|embedding| embedding.index);
+        Ok(response.data.into_iter().map(|x| x.embedding).collect())
+    }
+
+    fn decode_error(&self, status: u16, body: &[u8]) -> anyhow::Error {
+        match serde_json::from_slice::<VoyageError>(body) {
+            Ok(error) => anyhow!(
+                "Voyage request failed with status {}: {}",
+                status,
+                error.detail
+            ),
+            Err(_) => raw_error("Voyage", status, body),
+        }
+    }
+
+    fn max_batch_size(&self) -> usize {
+        1000
+    }
+
+    fn max_input_tokens(&self) -> Option<usize> {
+        Some(32000)
+    }
+
+    /// The limit of voyage-3, lower than the one of the lite models
+    fn max_tokens_per_request(&self) -> Option<usize> {
+        Some(120_000)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sets_input_type() {
+        let body = Voyage
+            .request_body(
+                "voyage-3",
+                "text".to_string().into(),
+                EmbeddingPurpose::Document,
+            )
+            .unwrap();
+
+        assert_eq!(
+            body,
+            json!({"model": "voyage-3", "input": ["text"], "input_type": "document"})
+        );
+    }
+
+    #[test]
+    fn decodes_response_in_input_order() {
+        let body = br#"{"object": "list", "data": [{"object": "embedding", "embedding": [2.0], "index": 1}, {"object": "embedding", "embedding": [1.0], "index": 0}], "model": "voyage-3"}"#;
+
+        assert_eq!(
+            Voyage.decode_response(body).unwrap(),
+            vec![vec![1.0], vec![2.0]]
+        );
+    }
+
+    #[test]
+    fn decodes_error() {
+        let error = Voyage.decode_error(
+            400,
+            br#"{"detail": "Input type must be query or document"}"#,
+        );
+
+        assert_eq!(
+            error.to_string(),
+            "Voyage request failed with status 400: Input type must be query or document"
+        );
+    }
+}
@@ -0,0 +1,326 @@
+use std::convert::TryInto;
+
+use anyhow::{anyhow, Result};
+use log::warn;
+use serde::Deserialize;
+use serde::Serialize;
+
+/// How vectors are compressed when written to the embedding index
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum Quantization {
+    #[default]
+    None,
+    /// One byte per dimension, reconstructed as `offset + scale * code`
+    Int8,
+    /// One bit per dimension, reconstructed as `offset ± scale`
+    Binary,
+}
+
+impl From<String> for Quantization {
+    fn from(value: String) -> Self {
+        match value.as_str() {
+            "" | "None" => Self::None,
+            "Int8" => Self::Int8,
+            "Binary" => Self::Binary,
+            other => {
+                warn!("Unknown quantization '{}', storing full precision vectors", other);
+                Self::None
+            }
+        }
+    }
+}
+
+impl Quantization {
+    /// Number of bytes used to store the codes of a single vector
+    pub fn code_len(&self, dimension: usize) -> usize {
+        match self {
+            Self::None => 0,
+            Self::Int8 => dimension,
+            Self::Binary => dimension.div_ceil(8),
+        }
+    }
+}
+
+/// The codes of a single vector with its scale and offset, kept to store a
+/// vector again without quantizing its reconstruction
+#[derive(Debug, Clone, PartialEq)]
+pub struct QuantizedVector {
+    kind: Quantization,
+    scale: f32,
+    offset: f32,
+    codes: Vec<u8>,
+}
+
+impl QuantizedVector {
+    pub fn kind(&self) -> Quantization {
+        self.kind
+    }
+}
+
+/// Quantized codes for a set of vectors, with the per-vector scale and offset
+/// needed to approximately reconstruct them.
+#[derive(Debug, Clone, PartialEq)]
+pub struct QuantizedMatrix {
+    kind: Quantization,
+    dimension: usize,
+    scales: Vec<f32>,
+    offsets: Vec<f32>,
+    codes: Vec<u8>,
+}
+
+impl QuantizedMatrix {
+    pub fn new(kind: Quantization, dimension: usize) -> Self {
+        Self {
+            kind,
+            dimension,
+            scales: Vec::new(),
+            offsets: Vec::new(),
+            codes: Vec::new(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.scales.len()
+    }
+
+    pub fn push(&mut self, vector: &[f32]) {
+        let (scale, offset) = match self.kind {
+            Quantization::None => return,
+            Quantization::Int8 => quantize_int8(vector, &mut self.codes),
+            Quantization::Binary => quantize_binary(vector, &mut self.codes),
+        };
+        self.scales.push(scale);
+        self.offsets.push(offset);
+    }
+
+    /// Appends a vector quantized with the same kind and dimension
+    pub fn push_quantized(&mut self, vector: QuantizedVector) {
+        assert_eq!(vector.kind, self.kind, "Quantization kinds differ");
+        assert_eq!(vector.codes.len(), self.kind.code_len(self.dimension), "Dimensions differ");
+        self.scales.push(vector.scale);
+        self.offsets.push(vector.offset);
+        self.codes.extend_from_slice(&vector.codes);
+    }
+
+    pub fn vector(&self, i: usize) -> QuantizedVector {
+        QuantizedVector {
+            kind: self.kind,
+            scale: self.scales[i],
+            offset: self.offsets[i],
+            codes: self.codes(i).to_vec(),
+        }
+    }
+
+    fn codes(&self, i: usize) -> &[u8] {
+        let len = self.kind.code_len(self.dimension);
+        &self.codes[i * len..(i + 1) * len]
+    }
+
+    /// Approximate dot product between the query and the `i`th vector,
+    /// computed against the codes without reconstructing the vector.
+    pub fn score(&self, query: &PreparedQuery, i: usize) -> f32 {
+        let codes = self.codes(i);
+        let weighted = match self.kind {
+            Quantization::None => 0.0,
+            Quantization::Int8 => query
+                .vector
+                .iter()
+                .zip(codes)
+                .map(|(q, c)| q * *c as f32)
+                .sum(),
+            Quantization::Binary => {
+                let set: f32 = query
+                    .vector
+                    .iter()
+                    .enumerate()
+                    .filter(|(d, _)| codes[d / 8] & (1 << (d % 8)) != 0)
+                    .map(|(_, q)| q)
+                    .sum();
+                2.0 * set - query.sum
+            }
+        };
+        self.offsets[i] * query.sum + self.scales[i] * weighted
+    }
+
+    pub fn dequantize(&self, i: usize) -> Vec<f32> {
+        let codes = self.codes(i);
+        let (scale, offset) = (self.scales[i], self.offsets[i]);
+        (0..self.dimension)
+            .map(|d| match self.kind {
+                Quantization::None => 0.0,
+                Quantization::Int8 => offset + scale * codes[d] as f32,
+                Quantization::Binary if codes[d / 8] & (1 << (d % 8)) != 0 => offset + scale,
+                Quantization::Binary => offset - scale,
+            })
+            .collect()
+    }
+
+    /// Appends scales, offsets and then codes, all little endian
+    pub fn write_to(&self, bytes: &mut Vec<u8>) {
+        self.scales
+            .iter()
+            .chain(self.offsets.iter())
+            .for_each(|f| bytes.extend_from_slice(&f.to_le_bytes()));
+        bytes.extend_from_slice(&self.codes);
+    }
+
+    /// Number of bytes `write_to` produces for `count` vectors
+    /// `None` if the length overflows, which only a corrupt header claims
+    pub fn byte_len(kind: Quantization, dimension: usize, count: usize) -> Option<usize> {
+        count.checked_mul(8 + kind.code_len(dimension))
+    }
+
+    pub fn read_from(kind: Quantization, dimension: usize, count: usize, bytes: &[u8]) -> Result<Self> {
+        let expected = Self::byte_len(kind, dimension, count)
+            .ok_or_else(|| anyhow!("Quantized matrix of {} vectors is too large", count))?;
+        if bytes.len() != expected {
+            return Err(anyhow!(
+                "Quantized matrix has {} bytes, expected {}",
+                bytes.len(),
+                expected
+            ));
+        }
+        let (params, codes) = bytes.split_at(count * 8);
+        let mut floats = params
+            .chunks_exact(4)
+            .map(|b| f32::from_le_bytes(b.try_into().expect("chunk is 4 bytes")));
+        let scales = floats.by_ref().take(count).collect();
+        let offsets = floats.collect();
+        Ok(Self {
+            kind,
+            dimension,
+            scales,
+            offsets,
+            codes: codes.to_vec(),
+        })
+    }
+}
+
+/// A full precision query with the sum of its components cached, since every
+/// quantized score needs it for the offset term.
+pub struct PreparedQuery<'a> {
+    vector: &'a [f32],
+    sum: f32,
+}
+
+impl<'a> PreparedQuery<'a> {
+    pub fn new(vector: &'a [f32]) -> Self {
+        Self {
+            vector,
+            sum: vector.iter().sum(),
+        }
+    }
+}
+
+fn quantize_int8(vector: &[f32], codes: &mut Vec<u8>) -> (f32, f32) {
+    let min = vector.iter().cloned().fold(f32::INFINITY, f32::min);
+    let max = vector.iter().cloned().fold(f32::NEG_INFINITY, f32::max);
+    if vector.is_empty() || max <= min {
+        codes.extend(std::iter::repeat_n(0, vector.len()));
+        return (0.0, if vector.is_empty() { 0.0 } else { min });
+    }
+    let scale = (max - min) / 255.0;
+    codes.extend(
+        vector
+            .iter()
+            .map(|x| ((x - min) / scale).round().clamp(0.0, 255.0) as u8),
+    );
+    (scale, min)
+}
+
+fn quantize_binary(vector: &[f32], codes: &mut Vec<u8>) -> (f32, f32) {
+    let n = vector.len().max(1) as f32;
+    let mean = vector.iter().sum::<f32>() / n;
+    let scale = vector.iter().map(|x| (x - mean).abs()).sum::<f32>() / n;
+    let start = codes.len();
+    codes.extend(std::iter::repeat_n(0, vector.len().div_ceil(8)));
+    vector
+        .iter()
+        .enumerate()
+        .filter(|(_, x)| **x > mean)
+        .for_each(|(d, _)| codes[start + d / 8] |= 1 << (d % 8));
+    (scale, mean)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::index::dot;
+
+    fn matrix(kind: Quantization, vectors: &[Vec<f32>]) -> QuantizedMatrix {
+        let mut matrix = QuantizedMatrix::new(kind, vectors[0].len());
+        vectors.iter().for_each(|v| matrix.push(v));
+        matrix
+    }
+
+    #[test]
+    fn int8_reconstruction_error_is_bounded() {
+        let vector = vec![0.3, -0.7, 0.05, 0.9, -0.2];
+        let matrix = matrix(Quantization::Int8, std::slice::from_ref(&vector));
+
+        let step = (0.9 + 0.7) / 255.0;
+        for (x, y) in vector.iter().zip(matrix.dequantize(0)) {
+            assert!((x - y).abs() <= step / 2.0 + 1e-6);
+        }
+    }
+
+    #[test]
+    fn int8_score_matches_dequantized_dot() {
+        let vectors = vec![vec![0.3, -0.7, 0.05, 0.9], vec![-0.1, 0.2, 0.4, -0.8]];
+        let matrix = matrix(Quantization::Int8, &vectors);
+        let query = vec![0.5, 0.1, -0.3, 0.7];
+
+        for (i, vector) in vectors.iter().enumerate() {
+            let expected = dot(&query, &matrix.dequantize(i));
+            let score = matrix.score(&PreparedQuery::new(&query), i);
+            assert!((expected - score).abs() < 1e-4);
+            assert!((dot(&query, vector) - score).abs() < 0.02);
+        }
+    }
+
+    #[test]
+    fn binary_score_matches_dequantized_dot() {
+        let vectors = vec![
+            vec![0.3, -0.7, 0.05, 0.9, -0.2, 0.1, 0.6, -0.4, 0.8],
+            vec![-0.1, 0.2, 0.4, -0.8, 0.0, 0.3, -0.5, 0.2, -0.9],
+        ];
+        let matrix = matrix(Quantization::Binary, &vectors);
+        let query = vec![0.5, 0.1, -0.3, 0.7, 0.2, -0.6, 0.3, 0.1, 0.4];
+
+        assert_eq!(matrix.codes(0).len(), 2);
+        for i in 0..2 {
+            let expected = dot(&query, &matrix.dequantize(i));
+            let score = matrix.score(&PreparedQuery::new(&query), i);
+            assert!((expected - score).abs() < 1e-4);
+        }
+    }
+
+    #[test]
+    fn binary_preserves_ranking() {
+        let vectors = vec![vec![1.0, 0.9, -1.0, -0.8], vec![-1.0, -0.9, 1.0, 0.8]];
+        let matrix = matrix(Quantization::Binary, &vectors);
+        let query = PreparedQuery::new(&vectors[0]);
+
+        assert!(matrix.score(&query, 0) > matrix.score(&query, 1));
+    }
+
+    #[test]
+    fn constant_vector() {
+        let matrix = matrix(Quantization::Int8, &[vec![0.5, 0.5, 0.5]]);
+
+        assert_eq!(matrix.dequantize(0), vec![0.5, 0.5, 0.5]);
+    }
+
+    #[test]
+    fn roundtrip_bytes() {
+        let vectors = vec![vec![0.3, -0.7, 0.05], vec![-0.1, 0.2, 0.4]];
+        for kind in [Quantization::Int8, Quantization::Binary] {
+            let matrix = matrix(kind, &vectors);
+            let mut bytes = Vec::new();
+            matrix.write_to(&mut bytes);
+
+            assert_eq!(Some(bytes.len()), QuantizedMatrix::byte_len(kind, 3, 2));
+            assert_eq!(QuantizedMatrix::read_from(kind, 3, 2, &bytes).unwrap(), matrix);
+        }
+    }
+}
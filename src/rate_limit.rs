@@ -0,0 +1,162 @@
+const MS_PER_MINUTE: f64 = 60_000.0;
+
+/// Holds up to a minute's worth of a per-minute limit, refilling
+/// continuously. Reservations may overdraw it, in which case the caller waits
+/// until the overdraft has been refilled.
+#[derive(Debug, Clone, PartialEq)]
+struct TokenBucket {
+    capacity: f64,
+    available: f64,
+    refill_per_ms: f64,
+    updated_at: f64,
+}
+
+impl TokenBucket {
+    /// A full bucket at `now`, in milliseconds
+    fn per_minute(limit: u32, now: f64) -> Self {
+        Self {
+            capacity: limit as f64,
+            available: limit as f64,
+            refill_per_ms: limit as f64 / MS_PER_MINUTE,
+            updated_at: now,
+        }
+    }
+
+    fn refill(&mut self, now: f64) {
+        let elapsed = (now - self.updated_at).max(0.0);
+        self.available = (self.available + elapsed * self.refill_per_ms).min(self.capacity);
+        self.updated_at = now;
+    }
+
+    /// Takes `amount` and returns the milliseconds until the bucket has
+    /// refilled what it was overdrawn by
+    fn reserve(&mut self, amount: f64, now: f64) -> f64 {
+        self.refill(now);
+        self.available -= amount;
+        self.wait_ms(0.0)
+    }
+
+    /// Milliseconds until `amount` more than is available now has refilled
+    fn wait_ms(&self, amount: f64) -> f64 {
+        (amount - self.available).max(0.0) / self.refill_per_ms
+    }
+}
+
+/// Paces requests to stay under requests and tokens per minute limits
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct RateLimiter {
+    requests: Option<TokenBucket>,
+    tokens: Option<TokenBucket>,
+}
+
+impl RateLimiter {
+    /// A limit of 0 leaves that dimension unlimited
+    pub fn new(requests_per_minute: u32, tokens_per_minute: u32, now: f64) -> Self {
+        let bucket = |limit| {
+            Some(limit)
+                .filter(|l| *l > 0)
+                .map(|l| TokenBucket::per_minute(l, now))
+        };
+        Self {
+            requests: bucket(requests_per_minute),
+            tokens: bucket(tokens_per_minute),
+        }
+    }
+
+    /// Whether requests have to be sized in tokens
+    pub fn limits_tokens(&self) -> bool {
+        self.tokens.is_some()
+    }
+
+    /// Most tokens a single request may have, since a larger one would wait
+    /// for more than a minute's worth and still be rejected by the API
+    pub fn max_tokens(&self) -> Option<usize> {
+        self.tokens.as_ref().map(|bucket| bucket.capacity as usize)
+    }
+
+    /// Reserves capacity for a request of `tokens` tokens, returning the
+    /// milliseconds to wait before sending it
+    pub fn reserve(&mut self, tokens: usize, now: f64) -> f64 {
+        let requests = self.requests.as_mut().map_or(0.0, |b| b.reserve(1.0, now));
+        let tokens = self
+            .tokens
+            .as_mut()
+            .map_or(0.0, |b| b.reserve(tokens as f64, now));
+        requests.max(tokens)
+    }
+
+    /// Expected milliseconds the limits add to sending `requests` requests of
+    /// `tokens` tokens in total, starting at `now`
+    pub fn estimate_ms(&self, requests: usize, tokens: usize, now: f64) -> f64 {
+        let wait = |bucket: &Option<TokenBucket>, amount: usize| {
+            bucket.as_ref().map_or(0.0, |bucket| {
+                let mut bucket = bucket.clone();
+                bucket.refill(now);
+                bucket.wait_ms(amount as f64)
+            })
+        };
+        wait(&self.requests, requests).max(wait(&self.tokens, tokens))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unlimited_never_waits() {
+        let mut limiter = RateLimiter::new(0, 0, 0.0);
+
+        assert!(!limiter.limits_tokens());
+        assert_eq!(limiter.max_tokens(), None);
+        assert_eq!(limiter.reserve(1_000_000, 0.0), 0.0);
+        assert_eq!(limiter.estimate_ms(1000, 1_000_000, 0.0), 0.0);
+    }
+
+    #[test]
+    fn paces_requests() {
+        let mut limiter = RateLimiter::new(60, 0, 0.0);
+
+        for _ in 0..60 {
+            assert_eq!(limiter.reserve(0, 0.0), 0.0);
+        }
+        assert_eq!(limiter.reserve(0, 0.0), 1000.0);
+        assert_eq!(limiter.reserve(0, 0.0), 2000.0);
+        // refilled while waiting
+        assert_eq!(limiter.reserve(0, 3000.0), 0.0);
+    }
+
+    #[test]
+    fn paces_tokens() {
+        let mut limiter = RateLimiter::new(0, 1000, 0.0);
+
+        assert_eq!(limiter.max_tokens(), Some(1000));
+        assert_eq!(limiter.reserve(800, 0.0), 0.0);
+        assert!((limiter.reserve(500, 0.0) - 18_000.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn waits_for_the_tighter_limit() {
+        let mut limiter = RateLimiter::new(1, 1000, 0.0);
+
+        assert_eq!(limiter.reserve(10, 0.0), 0.0);
+        assert_eq!(limiter.reserve(10, 0.0), 60_000.0);
+    }
+
+    #[test]
+    fn refills_up_to_capacity() {
+        let mut limiter = RateLimiter::new(10, 0, 0.0);
+
+        assert_eq!(limiter.reserve(0, 3_600_000.0), 0.0);
+        assert_eq!(limiter.estimate_ms(10, 0, 3_600_000.0), 6000.0);
+    }
+
+    #[test]
+    fn estimates_remaining_time() {
+        let limiter = RateLimiter::new(100, 10_000, 0.0);
+
+        assert_eq!(limiter.estimate_ms(50, 5000, 0.0), 0.0);
+        assert!((limiter.estimate_ms(50, 40_000, 0.0) - 180_000.0).abs() < 1e-6);
+        assert!((limiter.estimate_ms(400, 5000, 0.0) - 180_000.0).abs() < 1e-6);
+    }
+}
@@ -0,0 +1,320 @@
+use std::future::Future;
+
+use anyhow::Result;
+use log::warn;
+use reqwest::header::HeaderMap;
+use wasm_bindgen::JsValue;
+use wasm_bindgen_futures::JsFuture;
+
+use crate::error::HttpError;
+use crate::error::RetriesExhausted;
+use crate::obsidian;
+
+/// How often and how patiently failed embedding requests are retried
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RetryPolicy {
+    /// Retries after the first attempt before giving up
+    pub max_retries: u32,
+    /// Delay before the first retry, doubled for every further one
+    pub base_delay_ms: f64,
+    pub max_delay_ms: f64,
+}
+
+impl RetryPolicy {
+    pub fn new(max_retries: u32) -> Self {
+        Self {
+            max_retries,
+            base_delay_ms: 1000.0,
+            max_delay_ms: 60_000.0,
+        }
+    }
+
+    /// Milliseconds to wait before retry number `retry`, counting from 0. The
+    /// delay the API asked for is honoured up to `max_delay_ms`, so that an
+    /// endpoint cannot stall a run indefinitely. Otherwise the exponential
+    /// backoff is scaled by `jitter` in [0, 1) so that clients failing
+    /// together do not retry together ("full jitter").
+    pub fn delay_ms(&self, retry: u32, retry_after_ms: Option<f64>, jitter: f64) -> f64 {
+        if let Some(retry_after_ms) = retry_after_ms {
+            return retry_after_ms.max(0.0).min(self.max_delay_ms);
+        }
+        let backoff = self.base_delay_ms * 2f64.powi(retry.min(30) as i32);
+        backoff.min(self.max_delay_ms) * jitter
+    }
+}
+
+/// Whether retrying the request that failed with `error` may succeed: rate
+/// limits, server errors and failures to reach the server
+pub fn is_transient(error: &anyhow::Error) -> bool {
+    if let Some(error) = error.downcast_ref::<HttpError>() {
+        return matches!(error.status, 408 | 425 | 429) || error.status >= 500;
+    }
+    error.downcast_ref::<reqwest::Error>().is_some()
+}
+
+/// How long the API asked to wait before retrying, from `retry-after-ms`,
+/// `Retry-After` in seconds, or OpenAI's `x-ratelimit-reset-*` headers for
+/// whichever limit is exhausted
+pub fn retry_after_ms(headers: &HeaderMap) -> Option<f64> {
+    let header = |name: &str| headers.get(name).and_then(|value| value.to_str().ok());
+    if let Some(ms) = header("retry-after-ms").and_then(|value| value.trim().parse().ok()) {
+        return Some(ms);
+    }
+    if let Some(seconds) = header("retry-after").and_then(|value| value.trim().parse::<f64>().ok())
+    {
+        return Some(seconds * 1000.0);
+    }
+    ["requests", "tokens"]
+        .iter()
+        .filter(|limit| header(&format!("x-ratelimit-remaining-{}", limit)) == Some("0"))
+        .filter_map(|limit| header(&format!("x-ratelimit-reset-{}", limit)))
+        .filter_map(parse_duration_ms)
+        .reduce(f64::max)
+}
+
+/// Parses Go style durations such as `1s`, `6m0s` or `20ms`
+fn parse_duration_ms(duration: &str) -> Option<f64> {
+    let mut total = 0.0;
+    let mut rest = duration.trim();
+    if rest.is_empty() {
+        return None;
+    }
+    while !rest.is_empty() {
+        let number_len = rest
+            .find(|c: char| !c.is_ascii_digit() && c != '.')
+            .unwrap_or(rest.len());
+        let value: f64 = rest[..number_len].parse().ok()?;
+        rest = &rest[number_len..];
+        let unit_len = rest
+            .find(|c: char| c.is_ascii_digit() || c == '.')
+            .unwrap_or(rest.len());
+        let scale = match &rest[..unit_len] {
+            "ms" => 1.0,
+            "s" => 1000.0,
+            "m" => 60_000.0,
+            "h" => 3_600_000.0,
+            _ => return None,
+        };
+        rest = &rest[unit_len..];
+        total += value * scale;
+    }
+    Some(total)
+}
+
+/// Runs `attempt` until it succeeds, fails permanently or `policy` runs out
+/// of retries, waiting with `sleep` between attempts. `jitter` draws from
+/// [0, 1).
+pub async fn with_retries<T, A, AFut, S, SFut>(
+    policy: &RetryPolicy,
+    mut attempt: A,
+    mut sleep: S,
+    mut jitter: impl FnMut() -> f64,
+) -> Result<T>
+where
+    A: FnMut() -> AFut,
+    AFut: Future<Output = Result<T>>,
+    S: FnMut(f64) -> SFut,
+    SFut: Future<Output = ()>,
+{
+    let mut retries = 0;
+    loop {
+        let error = match attempt().await {
+            Ok(value) => return Ok(value),
+            Err(error) => error,
+        };
+        if !is_transient(&error) {
+            return Err(error);
+        }
+        if retries >= policy.max_retries {
+            return Err(RetriesExhausted {
+                attempts: retries + 1,
+                source: error,
+            }
+            .into());
+        }
+        let retry_after = error
+            .downcast_ref::<HttpError>()
+            .and_then(|error| error.retry_after_ms);
+        let delay = policy.delay_ms(retries, retry_after, jitter());
+        warn!(
+            "Embedding request failed, retrying in {:.0} ms ({} of {}): {}",
+            delay,
+            retries + 1,
+            policy.max_retries,
+            error
+        );
+        sleep(delay).await;
+        retries += 1;
+    }
+}
+
+/// Resolves after `ms` milliseconds
+pub async fn sleep(ms: f64) {
+    let promise = js_sys::Promise::new(&mut |resolve, _reject| {
+        obsidian::setTimeout(&JsValue::from(resolve), ms as u32);
+    });
+    // the promise never rejects
+    let _ = JsFuture::from(promise).await;
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+
+    use anyhow::anyhow;
+    use reqwest::header::HeaderValue;
+
+    use super::*;
+
+    fn http_error(status: u16, retry_after_ms: Option<f64>) -> anyhow::Error {
+        HttpError {
+            status,
+            retry_after_ms,
+            source: anyhow!("status {}", status),
+        }
+        .into()
+    }
+
+    fn headers(pairs: &[(&'static str, &'static str)]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for (name, value) in pairs {
+            headers.insert(*name, HeaderValue::from_static(value));
+        }
+        headers
+    }
+
+    #[test]
+    fn backs_off_exponentially() {
+        let policy = RetryPolicy::new(5);
+
+        assert_eq!(policy.delay_ms(0, None, 1.0), 1000.0);
+        assert_eq!(policy.delay_ms(3, None, 1.0), 8000.0);
+        assert_eq!(policy.delay_ms(3, None, 0.5), 4000.0);
+        assert_eq!(policy.delay_ms(10, None, 1.0), 60_000.0);
+        assert_eq!(policy.delay_ms(1, Some(1500.0), 0.1), 1500.0);
+    }
+
+    #[test]
+    fn caps_requested_delays() {
+        let policy = RetryPolicy::new(5);
+
+        assert_eq!(policy.delay_ms(0, Some(3_600_000.0), 0.5), 60_000.0);
+        assert_eq!(policy.delay_ms(0, Some(-5.0), 0.5), 0.0);
+        assert_eq!(policy.delay_ms(0, Some(f64::NAN), 0.5), 0.0);
+    }
+
+    #[test]
+    fn classifies_errors() {
+        assert!(is_transient(&http_error(429, None)));
+        assert!(is_transient(&http_error(503, None)));
+        assert!(!is_transient(&http_error(400, None)));
+        assert!(!is_transient(&http_error(401, None)));
+        assert!(!is_transient(&anyhow!("Failed deserializing response")));
+    }
+
+    #[test]
+    fn reads_retry_after() {
+        assert_eq!(
+            retry_after_ms(&headers(&[("retry-after", "2")])),
+            Some(2000.0)
+        );
+        assert_eq!(
+            retry_after_ms(&headers(&[("retry-after-ms", "250"), ("retry-after", "1")])),
+            Some(250.0)
+        );
+        assert_eq!(retry_after_ms(&headers(&[])), None);
+    }
+
+    #[test]
+    fn reads_openai_rate_limit_headers() {
+        let headers = headers(&[
+            ("x-ratelimit-remaining-requests", "12"),
+            ("x-ratelimit-reset-requests", "1s"),
+            ("x-ratelimit-remaining-tokens", "0"),
+            ("x-ratelimit-reset-tokens", "6m0s"),
+        ]);
+
+        assert_eq!(retry_after_ms(&headers), Some(360_000.0));
+    }
+
+    #[test]
+    fn parses_durations() {
+        assert_eq!(parse_duration_ms("20ms"), Some(20.0));
+        assert_eq!(parse_duration_ms("1.5s"), Some(1500.0));
+        assert_eq!(parse_duration_ms("1h2m3s"), Some(3_723_000.0));
+        assert_eq!(parse_duration_ms("soon"), None);
+        assert_eq!(parse_duration_ms(""), None);
+    }
+
+    #[tokio::test]
+    async fn retries_transient_failures() {
+        let attempts = RefCell::new(0);
+        let delays = RefCell::new(Vec::new());
+
+        let result = with_retries(
+            &RetryPolicy::new(3),
+            || {
+                *attempts.borrow_mut() += 1;
+                let attempt = *attempts.borrow();
+                async move {
+                    match attempt {
+                        1 => Err(http_error(429, Some(300.0))),
+                        2 => Err(http_error(502, None)),
+                        _ => Ok(attempt),
+                    }
+                }
+            },
+            |ms| {
+                delays.borrow_mut().push(ms);
+                async {}
+            },
+            || 0.5,
+        )
+        .await;
+
+        assert_eq!(result.unwrap(), 3);
+        assert_eq!(*delays.borrow(), vec![300.0, 1000.0]);
+    }
+
+    #[tokio::test]
+    async fn gives_up_after_budget() {
+        let attempts = RefCell::new(0);
+
+        let error = with_retries(
+            &RetryPolicy::new(2),
+            || {
+                *attempts.borrow_mut() += 1;
+                async { Err::<(), _>(http_error(500, None)) }
+            },
+            |_| async {},
+            || 0.0,
+        )
+        .await
+        .unwrap_err();
+
+        assert_eq!(*attempts.borrow(), 3);
+        let exhausted = error.downcast_ref::<RetriesExhausted>().unwrap();
+        assert_eq!(exhausted.attempts, 3);
+        assert_eq!(error.to_string(), "Gave up after 3 attempts: status 500");
+    }
+
+    #[tokio::test]
+    async fn does_not_retry_permanent_failures() {
+        let attempts = RefCell::new(0);
+
+        let error = with_retries(
+            &RetryPolicy::new(5),
+            || {
+                *attempts.borrow_mut() += 1;
+                async { Err::<(), _>(http_error(401, None)) }
+            },
+            |_| async {},
+            || 0.0,
+        )
+        .await
+        .unwrap_err();
+
+        assert_eq!(*attempts.borrow(), 1);
+        assert_eq!(error.to_string(), "status 401");
+    }
+}
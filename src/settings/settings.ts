@@ -9,9 +9,27 @@ export interface semanticSearchSettings {
 	debugMode: boolean;
 	ignoredFolders: string;
 	apiResponseType: string;
+	customRequestTemplate: string;
+	customResponsePath: string;
+	customBatchSize: number;
+	teiTruncate: boolean;
+	teiNormalize: boolean;
+	azureApiVersion: string;
+	embeddingDimensions: number;
+	maxRetries: number;
+	requestsPerMinute: number;
+	tokensPerMinute: number;
+	concurrentRequests: number;
+	embeddingCacheSize: number;
+	documentPrefix: string;
+	queryPrefix: string;
 	sectionDelimeterRegex: string;
-	numBatches: number;
 	maxTokenLength: number;
+	quantization: string;
+	quantizationRescore: boolean;
+	hnswEfSearch: number;
+	liveIndexing: boolean;
+	liveIndexingDelay: number;
 	enableLinkRecommendationSuggestor: boolean;
 }
 
@@ -43,7 +61,7 @@ export class SemanticSearchSettingTab extends PluginSettingTab {
 
 		new Setting(containerEl)
 			.setName('API Key')
-			.setDesc('if your endpoint needs one, this places the key into the Bearer HTTP header')
+			.setDesc('if your endpoint needs one, this places the key into the Bearer HTTP header, or the api-key header for Azure OpenAI')
 			.addText(text => text
 				.setPlaceholder('Enter your secret')
 				.setValue(this.plugin.settings.apiKey)
@@ -67,12 +85,171 @@ export class SemanticSearchSettingTab extends PluginSettingTab {
 		.addDropdown(dropdown => dropdown
 					 .addOption("Ollama", "Ollama")
 					 .addOption("OpenAI", "OpenAI")
+					 .addOption("TEI", "Text Embeddings Inference")
+					 .addOption("Cohere", "Cohere")
+					 .addOption("Voyage", "Voyage")
+					 .addOption("Azure", "Azure OpenAI")
+					 .addOption("Gemini", "Gemini")
+					 .addOption("Custom", "Custom")
 					 .setValue(this.plugin.settings.apiResponseType)
 					 .onChange(async (value) => {
 						 this.plugin.settings.apiResponseType = value;
 						 await this.plugin.saveSettings();
+						 this.display();
 					 }));
 
+		if (this.plugin.settings.apiResponseType === "TEI") {
+			new Setting(containerEl)
+				.setName('Truncate long inputs')
+				.setDesc("Let the server truncate inputs longer than the model's maximum instead of rejecting them.")
+				.addToggle(toggleComponent => toggleComponent
+					.setValue(this.plugin.settings.teiTruncate)
+					.onChange(async (value) => {
+						this.plugin.settings.teiTruncate = value;
+						await this.plugin.saveSettings();
+					}));
+
+			new Setting(containerEl)
+				.setName('Normalize embeddings')
+				.setDesc("Ask the server for unit length embeddings.")
+				.addToggle(toggleComponent => toggleComponent
+					.setValue(this.plugin.settings.teiNormalize)
+					.onChange(async (value) => {
+						this.plugin.settings.teiNormalize = value;
+						await this.plugin.saveSettings();
+					}));
+		}
+
+		if (this.plugin.settings.apiResponseType === "Azure") {
+			new Setting(containerEl)
+				.setName('Azure API version')
+				.setDesc('Sent as the "api-version" query parameter. The API URL is the resource endpoint, e.g. https://my-resource.openai.azure.com, and the model is the deployment name.')
+				.addText(text => text
+					.setValue(this.plugin.settings.azureApiVersion)
+					.onChange(async (value) => {
+						this.plugin.settings.azureApiVersion = value;
+						await this.plugin.saveSettings();
+					}));
+		}
+
+		if (this.plugin.settings.apiResponseType === "Custom") {
+			new Setting(containerEl)
+				.setName('Custom request template')
+				.setDesc('JSON request body. The string "{{input}}" is replaced by the array of texts to embed, {{model}} by the model and {{input_type}} by "document" or "query".')
+				.addTextArea(text => text
+					.setValue(this.plugin.settings.customRequestTemplate)
+					.onChange(async (value) => {
+						this.plugin.settings.customRequestTemplate = value;
+						await this.plugin.saveSettings();
+					}));
+
+			new Setting(containerEl)
+				.setName('Custom response path')
+				.setDesc('Path to the embeddings in the response JSON, e.g. "embeddings" or "data[*].embedding". [n] selects an array element and [*] every element.')
+				.addText(text => text
+					.setValue(this.plugin.settings.customResponsePath)
+					.onChange(async (value) => {
+						this.plugin.settings.customResponsePath = value;
+						await this.plugin.saveSettings();
+					}));
+
+			new Setting(containerEl)
+				.setName('Custom batch size')
+				.setDesc('Most texts sent in one request. Lower it if the API rejects large requests.')
+				.addText(text => text
+					.setValue(String(this.plugin.settings.customBatchSize))
+					.onChange(async (value) => {
+						this.plugin.settings.customBatchSize = Number(value) || 96;
+						await this.plugin.saveSettings();
+					}));
+		}
+
+		new Setting(containerEl)
+			.setName('Retries')
+			.setDesc("How often a request that was rate limited or hit a server error is retried, waiting longer after each attempt or as long as the API asks, up to a minute, before giving up.")
+			.addSlider(slider => slider
+				.setValue(this.plugin.settings.maxRetries)
+				.onChange(async (value) => {
+					this.plugin.settings.maxRetries = value;
+					await this.plugin.saveSettings();
+				})
+				.setLimits(0, 10, 1)
+				.setDynamicTooltip()
+				.showTooltip());
+
+		new Setting(containerEl)
+			.setName('Requests per minute')
+			.setDesc("Send at most this many embedding requests per minute, to stay under your API plan's rate limit. 0 sends them as fast as possible.")
+			.addText(text => text
+				.setValue(String(this.plugin.settings.requestsPerMinute))
+				.onChange(async (value) => {
+					this.plugin.settings.requestsPerMinute = Number(value) || 0;
+					await this.plugin.saveSettings();
+				}));
+
+		new Setting(containerEl)
+			.setName('Tokens per minute')
+			.setDesc("Send at most this many tokens per minute. 0 sends them as fast as possible.")
+			.addText(text => text
+				.setValue(String(this.plugin.settings.tokensPerMinute))
+				.onChange(async (value) => {
+					this.plugin.settings.tokensPerMinute = Number(value) || 0;
+					await this.plugin.saveSettings();
+				}));
+
+		new Setting(containerEl)
+			.setName('Concurrent requests')
+			.setDesc("How many embedding requests are sent at once. Raise it for a local Ollama server or an API with high rate limits.")
+			.addSlider(slider => slider
+				.setValue(this.plugin.settings.concurrentRequests)
+				.onChange(async (value) => {
+					this.plugin.settings.concurrentRequests = value;
+					await this.plugin.saveSettings();
+				})
+				.setLimits(1, 16, 1)
+				.setDynamicTooltip()
+				.showTooltip());
+
+		new Setting(containerEl)
+			.setName('Embedding cache size (MB)')
+			.setDesc("Keep embeddings of previously embedded text so that unchanged text is not sent to the API again, for example after changing the section delimiter. The least recently used embeddings are dropped beyond this size. 0 disables the cache.")
+			.addText(text => text
+				.setValue(String(this.plugin.settings.embeddingCacheSize))
+				.onChange(async (value) => {
+					this.plugin.settings.embeddingCacheSize = Number(value) || 0;
+					await this.plugin.saveSettings();
+				}));
+
+		new Setting(containerEl)
+			.setName('Embedding dimensions')
+			.setDesc("Shorten embeddings to this many dimensions, for models that support it such as OpenAI's text-embedding-3. APIs that cannot shorten them are truncated locally. 0 keeps the model's default. Changing it re-embeds every section.")
+			.addText(text => text
+				.setValue(String(this.plugin.settings.embeddingDimensions))
+				.onChange(async (value) => {
+					this.plugin.settings.embeddingDimensions = Number(value) || 0;
+					await this.plugin.saveSettings();
+				}));
+
+		new Setting(containerEl)
+			.setName('Document prefix')
+			.setDesc('Prepended to every section before embedding it, e.g. "search_document: " for Nomic or "passage: " for E5. Use {{text}} to place the text elsewhere. Changing it re-embeds every section.')
+			.addText(text => text
+				.setValue(this.plugin.settings.documentPrefix)
+				.onChange(async (value) => {
+					this.plugin.settings.documentPrefix = value;
+					await this.plugin.saveSettings();
+				}));
+
+		new Setting(containerEl)
+			.setName('Query prefix')
+			.setDesc('Prepended to search queries before embedding them, e.g. "search_query: " for Nomic, "query: " for E5 or "Represent this sentence for searching relevant passages: " for BGE. Use {{text}} to place the text elsewhere. Changing it re-embeds every section.')
+			.addText(text => text
+				.setValue(this.plugin.settings.queryPrefix)
+				.onChange(async (value) => {
+					this.plugin.settings.queryPrefix = value;
+					await this.plugin.saveSettings();
+				}));
+
     const presetRegexes: Record<string, string> = {
       ".": "Match every line",
       "^#{1,6} ": "Match every heading",
@@ -110,7 +287,7 @@ export class SemanticSearchSettingTab extends PluginSettingTab {
 
 		new Setting(containerEl)
 			.setName('Folders to ignore')
-			.setDesc('Folders to ignore when generating input. Enter folder paths separated by newlines.')
+			.setDesc('Folders to ignore when reading notes to embed. Enter folder paths separated by newlines.')
 			.addTextArea(text => text
 				.setValue(this.plugin.settings.ignoredFolders)
 				.onChange(async (value) => {
@@ -118,19 +295,6 @@ export class SemanticSearchSettingTab extends PluginSettingTab {
 					await this.plugin.saveSettings();
 				}));
 
-		new Setting(containerEl)
-			.setName('Number of batches')
-			.setDesc("Number of batches used to call OpenAI's endpoint. If you have lots of data, and are facing invalid request errors, try increasing this number.")
-			.addSlider(slider => slider
-				.setValue(this.plugin.settings.numBatches)
-				.onChange(async (value) => {
-					this.plugin.settings.numBatches = value;
-					await this.plugin.saveSettings();
-        })
-        .setLimits(1, 100, 1)
-        .setDynamicTooltip()
-        .showTooltip());
-
 		new Setting(containerEl)
 		.setName('Max token length')
 		.setDesc("Used to truncate the text to this length in case of API restrictions.")
@@ -141,6 +305,62 @@ export class SemanticSearchSettingTab extends PluginSettingTab {
 					 await this.plugin.saveSettings();
 				 }));
 
+		new Setting(containerEl)
+		.setName('Quantization')
+		.setDesc("Compress stored embeddings to reduce disk and memory use. Int8 uses 1 byte per dimension and Binary 1 bit, at some cost in accuracy. Takes effect the next time embeddings are generated.")
+		.addDropdown(dropdown => dropdown
+					 .addOption("None", "None")
+					 .addOption("Int8", "Int8")
+					 .addOption("Binary", "Binary")
+					 .setValue(this.plugin.settings.quantization)
+					 .onChange(async (value) => {
+						 this.plugin.settings.quantization = value;
+						 await this.plugin.saveSettings();
+					 }));
+
+    new Setting(containerEl)
+    .setName("Rescore quantized results at full precision")
+    .setDesc("Keeps full precision embeddings alongside quantized ones and uses them to re-rank the best candidates. Improves accuracy of quantized search at the cost of disk and memory.")
+    .addToggle(toggleComponent => toggleComponent
+               .setValue(this.plugin.settings.quantizationRescore)
+               .onChange(async (value) => {
+                 this.plugin.settings.quantizationRescore = value;
+                 await this.plugin.saveSettings();
+               }));
+
+		new Setting(containerEl)
+		.setName('Approximate search candidates')
+		.setDesc("Size of the candidate list used when searching large vaults approximately. Higher values find better matches but are slower. Set to 0 to always search exhaustively.")
+		.addText(text => text
+				 .setValue(String(this.plugin.settings.hnswEfSearch))
+				 .onChange(async (value) => {
+					 const parsed = parseInt(value);
+					 this.plugin.settings.hnswEfSearch = isNaN(parsed) || parsed < 0 ? 0 : parsed;
+					 await this.plugin.saveSettings();
+				 }));
+
+    new Setting(containerEl)
+    .setName("Live indexing")
+    .setDesc("Re-embed notes in the background as they are created, edited, renamed or deleted. Only sections whose content changed are sent to the API. Requires embeddings to have been generated once.")
+    .addToggle(toggleComponent => toggleComponent
+               .setValue(this.plugin.settings.liveIndexing)
+               .onChange(async (value) => {
+                 this.plugin.settings.liveIndexing = value;
+                 await this.plugin.saveSettings();
+                 this.plugin.setLiveIndexing(value);
+               }));
+
+		new Setting(containerEl)
+		.setName('Live indexing delay')
+		.setDesc("Milliseconds without further changes to wait before indexing, so that bursts of edits are indexed once.")
+		.addText(text => text
+				 .setValue(String(this.plugin.settings.liveIndexingDelay))
+				 .onChange(async (value) => {
+					 const parsed = parseInt(value);
+					 this.plugin.settings.liveIndexingDelay = isNaN(parsed) || parsed < 0 ? 0 : parsed;
+					 await this.plugin.saveSettings();
+				 }));
+
     new Setting(containerEl)
     .setName("Enable link recommendation using {{}}")
     .setDesc("Typing '{{}}' will generate link recommendations for the text within the braces (requires reload).")
@@ -0,0 +1,627 @@
+use std::borrow::Cow;
+use std::convert::TryInto;
+
+use anyhow::{anyhow, Context, Result};
+use serde::Deserialize;
+use serde::Serialize;
+
+use crate::byte_reader::ByteReader;
+use crate::error::ErrorKind;
+use crate::error::SemanticSearchError;
+use crate::file_processor::EmbeddingRow;
+use crate::index::normalize;
+use crate::quantization::Quantization;
+use crate::quantization::QuantizedMatrix;
+use crate::quantization::QuantizedVector;
+
+/// Identifies an embedding index file written by this plugin
+const MAGIC: &[u8; 4] = b"SSEI";
+/// Version 2 added the optional quantized matrix
+pub const INDEX_FORMAT_VERSION: u32 = 2;
+
+/// Describes how the vectors in an index were produced. Stored as JSON so
+/// that fields can be added without breaking older index files.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct IndexHeader {
+    #[serde(skip)]
+    pub format_version: u32,
+    pub model: String,
+    pub provider: String,
+    pub dimension: usize,
+    /// Milliseconds since the unix epoch
+    pub created_at: f64,
+    #[serde(default)]
+    pub quantization: Quantization,
+    /// Whether the f32 matrix is stored, which is always the case for
+    /// unquantized indexes and optional for quantized ones
+    #[serde(default = "default_full_precision")]
+    pub full_precision: bool,
+    /// Templates applied to the text of documents and queries before
+    /// embedding them, see `PrefixTemplates`
+    #[serde(default)]
+    pub document_prefix: String,
+    #[serde(default)]
+    pub query_prefix: String,
+    /// Dimensions the embeddings were requested at, `None` for the model's
+    /// default. Queries are embedded at the same dimensions.
+    #[serde(default)]
+    pub output_dimensions: Option<u32>,
+}
+
+fn default_full_precision() -> bool {
+    true
+}
+
+impl IndexHeader {
+    pub fn new(model: &str, provider: &str, dimension: usize, created_at: f64) -> Self {
+        Self {
+            format_version: INDEX_FORMAT_VERSION,
+            model: model.to_string(),
+            provider: provider.to_string(),
+            dimension,
+            created_at,
+            quantization: Quantization::None,
+            full_precision: true,
+            document_prefix: String::new(),
+            query_prefix: String::new(),
+            output_dimensions: None,
+        }
+    }
+
+    /// Describes how the embeddings of this index differ from the ones
+    /// `other` would produce, or `None` if they are comparable. Documents and
+    /// queries are only comparable when embedded by the same model with the
+    /// prefixes they were tuned with, so a change to either prefix counts.
+    pub fn mismatch(&self, other: &IndexHeader) -> Option<String> {
+        if self.model != other.model || self.provider != other.provider {
+            Some(format!(
+                "{} from {} instead of {} from {}",
+                self.model, self.provider, other.model, other.provider
+            ))
+        } else if self.document_prefix != other.document_prefix {
+            Some(format!(
+                "document prefix '{}' instead of '{}'",
+                self.document_prefix, other.document_prefix
+            ))
+        } else if self.query_prefix != other.query_prefix {
+            Some(format!(
+                "query prefix '{}' instead of '{}'",
+                self.query_prefix, other.query_prefix
+            ))
+        } else if self.output_dimensions != other.output_dimensions {
+            let describe = |dimensions: Option<u32>| match dimensions {
+                Some(dimensions) => format!("{} dimensions", dimensions),
+                None => "the model's default dimensions".to_string(),
+            };
+            Some(format!(
+                "{} instead of {}",
+                describe(self.output_dimensions),
+                describe(other.output_dimensions)
+            ))
+        } else {
+            None
+        }
+    }
+
+    /// Describes why the vectors of this index cannot be stored the way
+    /// `other` stores them without embedding them again, or `None` if they
+    /// can. Vectors kept only as quantized codes are reused verbatim, so they
+    /// cannot be stored at full precision or quantized differently.
+    pub fn storage_mismatch(&self, other: &IndexHeader) -> Option<String> {
+        let quantized_only = |header: &IndexHeader| {
+            header.quantization != Quantization::None && !header.full_precision
+        };
+        if !quantized_only(self)
+            || (quantized_only(other) && self.quantization == other.quantization)
+        {
+            return None;
+        }
+        let describe = |header: &IndexHeader| match header.quantization {
+            Quantization::None => "full precision vectors".to_string(),
+            kind if header.full_precision => {
+                format!("{:?} quantized and full precision vectors", kind)
+            }
+            kind => format!("only {:?} quantized vectors", kind),
+        };
+        Some(format!("{} instead of {}", describe(self), describe(other)))
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SectionMetadata {
+    pub name: String,
+    /// Empty for sections migrated from embedding.csv or written before paths
+    /// were recorded
+    #[serde(default)]
+    pub path: String,
+    pub mtime: String,
+    #[serde(default)]
+    pub section_id: String,
+    pub header: String,
+    #[serde(default)]
+    pub hash: String,
+}
+
+impl SectionMetadata {
+    /// Identifies the embedded content of a section, changing whenever the
+    /// section is re-embedded
+    pub fn key(&self) -> String {
+        if self.path.is_empty() {
+            format!("{}\u{1f}{}\u{1f}{}", self.name, self.header, self.mtime)
+        } else {
+            format!("{}\u{1f}{}\u{1f}{}", self.path, self.section_id, self.hash)
+        }
+    }
+}
+
+/// All embeddings of a vault: a header, one metadata entry per section, the
+/// quantized codes if the index is quantized and a row-major
+/// `sections.len() x header.dimension` matrix of f32 if it keeps full
+/// precision vectors. Quantized vectors are L2-normalized before quantizing.
+///
+/// On disk the layout is:
+///
+/// | bytes | content                                          |
+/// |-------|--------------------------------------------------|
+/// | 4     | magic `SSEI`                                     |
+/// | 4     | format version (u32 LE)                          |
+/// | 4 + n | header length (u32 LE), JSON header              |
+/// | 4 + m | metadata length (u32 LE), JSON metadata          |
+/// | q     | scales, offsets (f32 LE) and codes, if quantized |
+/// | 4 * k | f32 LE matrix, if full precision                 |
+#[derive(Debug, Clone)]
+pub struct EmbeddingStore {
+    pub header: IndexHeader,
+    pub sections: Vec<SectionMetadata>,
+    vectors: Vec<f32>,
+    quantized: Option<QuantizedMatrix>,
+}
+
+impl EmbeddingStore {
+    pub fn from_rows(mut header: IndexHeader, rows: Vec<EmbeddingRow>) -> Result<Self> {
+        if let Some(first) = rows.first() {
+            header.dimension = first.embedding.len();
+        }
+        if header.quantization == Quantization::None {
+            header.full_precision = true;
+        }
+        let mut quantized = match header.quantization {
+            Quantization::None => None,
+            kind => Some(QuantizedMatrix::new(kind, header.dimension)),
+        };
+        let mut sections = Vec::with_capacity(rows.len());
+        let mut vectors = Vec::with_capacity(if header.full_precision {
+            rows.len() * header.dimension
+        } else {
+            0
+        });
+        for mut row in rows {
+            if row.embedding.len() != header.dimension {
+                return Err(SemanticSearchError::new(
+                    ErrorKind::DimensionMismatch,
+                    anyhow!(
+                        "Embedding for file: {} and section: {} has {} dimensions, expected {}",
+                        &row.name,
+                        &row.header,
+                        row.embedding.len(),
+                        header.dimension
+                    ),
+                )
+                .into());
+            }
+            if let Some(quantized) = quantized.as_mut() {
+                match row.quantized.take() {
+                    // Quantizing the reconstruction again would add to its error
+                    Some(codes) if codes.kind() == header.quantization && !header.full_precision => {
+                        quantized.push_quantized(codes)
+                    }
+                    _ => {
+                        normalize(&mut row.embedding);
+                        quantized.push(&row.embedding);
+                    }
+                }
+            }
+            if header.full_precision {
+                vectors.extend_from_slice(&row.embedding);
+            }
+            sections.push(SectionMetadata {
+                name: row.name,
+                path: row.path,
+                mtime: row.mtime,
+                section_id: row.section_id,
+                header: row.header,
+                hash: row.hash,
+            });
+        }
+        Ok(Self {
+            header,
+            sections,
+            vectors,
+            quantized,
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.sections.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.sections.is_empty()
+    }
+
+    /// The `i`th vector, reconstructed from its codes if the full precision
+    /// vector was not stored
+    pub fn embedding(&self, i: usize) -> Cow<'_, [f32]> {
+        match &self.quantized {
+            Some(quantized) if !self.header.full_precision => Cow::Owned(quantized.dequantize(i)),
+            _ => {
+                let dimension = self.header.dimension;
+                Cow::Borrowed(&self.vectors[i * dimension..(i + 1) * dimension])
+            }
+        }
+    }
+
+    /// Row-major f32 matrix, if full precision vectors are stored
+    pub fn full_precision_vectors(&self) -> Option<&[f32]> {
+        if self.header.full_precision {
+            Some(&self.vectors)
+        } else {
+            None
+        }
+    }
+
+    /// Every vector L2-normalized, in a single row-major matrix
+    pub fn normalized_vectors(&self) -> Vec<f32> {
+        let mut vectors: Vec<f32> = Vec::with_capacity(self.len() * self.header.dimension);
+        for i in 0..self.len() {
+            let start = vectors.len();
+            vectors.extend_from_slice(&self.embedding(i));
+            normalize(&mut vectors[start..]);
+        }
+        vectors
+    }
+
+    pub fn keys(&self) -> Vec<String> {
+        self.sections.iter().map(SectionMetadata::key).collect()
+    }
+
+    pub fn quantized(&self) -> Option<&QuantizedMatrix> {
+        self.quantized.as_ref()
+    }
+
+    /// The rows of this index. Rows of an index without full precision
+    /// vectors keep their codes, see `EmbeddingRow::quantized`.
+    pub fn into_rows(self) -> Vec<EmbeddingRow> {
+        let embeddings: Vec<(Vec<f32>, Option<QuantizedVector>)> = (0..self.len())
+            .map(|i| {
+                let codes = match &self.quantized {
+                    Some(quantized) if !self.header.full_precision => Some(quantized.vector(i)),
+                    _ => None,
+                };
+                (self.embedding(i).into_owned(), codes)
+            })
+            .collect();
+        self.sections
+            .into_iter()
+            .zip(embeddings)
+            .map(|(section, (embedding, quantized))| EmbeddingRow {
+                name: section.name,
+                path: section.path,
+                mtime: section.mtime,
+                section_id: section.section_id,
+                header: section.header,
+                hash: section.hash,
+                embedding,
+                quantized,
+            })
+            .collect()
+    }
+
+    pub fn to_bytes(&self) -> Result<Vec<u8>> {
+        let header = serde_json::to_vec(&self.header).context("Failed to serialize index header")?;
+        let metadata =
+            serde_json::to_vec(&self.sections).context("Failed to serialize index metadata")?;
+
+        let mut bytes =
+            Vec::with_capacity(16 + header.len() + metadata.len() + self.vectors.len() * 4);
+        bytes.extend_from_slice(MAGIC);
+        bytes.extend_from_slice(&INDEX_FORMAT_VERSION.to_le_bytes());
+        write_block(&mut bytes, &header)?;
+        write_block(&mut bytes, &metadata)?;
+        if let Some(quantized) = &self.quantized {
+            quantized.write_to(&mut bytes);
+        }
+        self.vectors
+            .iter()
+            .for_each(|f| bytes.extend_from_slice(&f.to_le_bytes()));
+        Ok(bytes)
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        let mut reader = ByteReader::new(bytes, "Embedding index");
+        if reader.take(MAGIC.len())? != MAGIC {
+            return Err(anyhow!("Not an embedding index file"));
+        }
+        let format_version = reader.read_u32()?;
+        if format_version == 0 || format_version > INDEX_FORMAT_VERSION {
+            return Err(anyhow!(
+                "Unsupported embedding index format version {}, expected at most {}",
+                format_version,
+                INDEX_FORMAT_VERSION
+            ));
+        }
+
+        let mut header: IndexHeader = serde_json::from_slice(reader.read_block()?)
+            .context("Failed to deserialize index header")?;
+        header.format_version = format_version;
+        let sections: Vec<SectionMetadata> = serde_json::from_slice(reader.read_block()?)
+            .context("Failed to deserialize index metadata")?;
+        if header.quantization == Quantization::None {
+            header.full_precision = true;
+        }
+
+        let count = sections.len();
+        let quantized = match header.quantization {
+            Quantization::None => None,
+            kind => {
+                let len = QuantizedMatrix::byte_len(kind, header.dimension, count)
+                    .ok_or_else(|| anyhow!("Embedding index claims too many embeddings"))?;
+                Some(QuantizedMatrix::read_from(kind, header.dimension, count, reader.take(len)?)?)
+            }
+        };
+        let expected = if header.full_precision {
+            count
+                .checked_mul(header.dimension)
+                .and_then(|floats| floats.checked_mul(4))
+                .ok_or_else(|| anyhow!("Embedding index claims too many embeddings"))?
+        } else {
+            0
+        };
+        let matrix = reader.take(expected)?;
+        reader.finish()?;
+        let vectors = matrix
+            .chunks_exact(4)
+            .map(|b| f32::from_le_bytes(b.try_into().expect("chunk is 4 bytes")))
+            .collect();
+
+        Ok(Self {
+            header,
+            sections,
+            vectors,
+            quantized,
+        })
+    }
+}
+
+fn write_block(bytes: &mut Vec<u8>, block: &[u8]) -> Result<()> {
+    let len: u32 = block.len().try_into().context("Index block is too large")?;
+    bytes.extend_from_slice(&len.to_le_bytes());
+    bytes.extend_from_slice(block);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hash::content_hash;
+
+    fn row(name: &str, embedding: Vec<f32>) -> EmbeddingRow {
+        EmbeddingRow {
+            name: name.to_string(),
+            path: format!("notes/{}.md", name),
+            mtime: "1".to_string(),
+            section_id: format!("{}-header", name),
+            header: format!("{} header", name),
+            hash: content_hash(name),
+            embedding,
+            quantized: None,
+        }
+    }
+
+    fn store() -> EmbeddingStore {
+        let header = IndexHeader::new("nomic-embed-text", "Ollama", 0, 1700000000000.0);
+        let rows = vec![row("a", vec![0.5, -1.25, 3.0]), row("b", vec![0.0, 1.0, -0.1])];
+        EmbeddingStore::from_rows(header, rows).unwrap()
+    }
+
+    #[test]
+    fn roundtrip() {
+        let store = store();
+
+        let decoded = EmbeddingStore::from_bytes(&store.to_bytes().unwrap()).unwrap();
+
+        assert_eq!(decoded.header, store.header);
+        assert_eq!(decoded.header.dimension, 3);
+        assert_eq!(decoded.len(), 2);
+        assert_eq!(decoded.sections[1].name, "b");
+        assert_eq!(*decoded.embedding(0), [0.5, -1.25, 3.0]);
+        assert_eq!(*decoded.embedding(1), [0.0, 1.0, -0.1]);
+    }
+
+    fn quantized_store(quantization: Quantization, full_precision: bool) -> EmbeddingStore {
+        let mut header = IndexHeader::new("model", "OpenAI", 0, 0.0);
+        header.quantization = quantization;
+        header.full_precision = full_precision;
+        let rows = vec![row("a", vec![3.0, 4.0, 0.0]), row("b", vec![0.0, -1.0, 0.0])];
+        EmbeddingStore::from_rows(header, rows).unwrap()
+    }
+
+    #[test]
+    fn roundtrip_prefixes() {
+        let mut store = store();
+        store.header.document_prefix = "search_document: ".to_string();
+        store.header.query_prefix = "search_query: ".to_string();
+
+        let decoded = EmbeddingStore::from_bytes(&store.to_bytes().unwrap()).unwrap();
+
+        assert_eq!(decoded.header, store.header);
+    }
+
+    #[test]
+    fn header_without_prefixes() {
+        let header: IndexHeader = serde_json::from_str(
+            r#"{"model": "m", "provider": "OpenAI", "dimension": 3, "created_at": 0.0}"#,
+        )
+        .unwrap();
+
+        assert_eq!(header.document_prefix, "");
+        assert!(header.mismatch(&IndexHeader::new("m", "OpenAI", 0, 1.0)).is_none());
+    }
+
+    #[test]
+    fn detects_mismatched_headers() {
+        let header = IndexHeader::new("nomic-embed-text", "Ollama", 3, 0.0);
+        let mut other = header.clone();
+        other.query_prefix = "search_query: ".to_string();
+
+        assert_eq!(
+            header.mismatch(&other).unwrap(),
+            "query prefix '' instead of 'search_query: '"
+        );
+        other.query_prefix.clear();
+        other.output_dimensions = Some(256);
+        assert_eq!(
+            header.mismatch(&other).unwrap(),
+            "the model's default dimensions instead of 256 dimensions"
+        );
+        other.model = "mxbai-embed-large".to_string();
+        assert!(header.mismatch(&other).unwrap().contains("mxbai-embed-large"));
+    }
+
+    #[test]
+    fn quantized_roundtrip() {
+        for quantization in [Quantization::Int8, Quantization::Binary] {
+            for full_precision in [true, false] {
+                let store = quantized_store(quantization, full_precision);
+
+                let decoded = EmbeddingStore::from_bytes(&store.to_bytes().unwrap()).unwrap();
+
+                assert_eq!(decoded.header, store.header);
+                assert_eq!(decoded.quantized(), store.quantized());
+                assert_eq!(decoded.full_precision_vectors().is_some(), full_precision);
+            }
+        }
+    }
+
+    #[test]
+    fn reused_quantized_only_rows_keep_their_codes() {
+        for quantization in [Quantization::Int8, Quantization::Binary] {
+            let store = quantized_store(quantization, false);
+            let header = store.header.clone();
+
+            let mut reused = store.clone();
+            for _ in 0..3 {
+                let bytes = EmbeddingStore::from_rows(header.clone(), reused.into_rows())
+                    .unwrap()
+                    .to_bytes()
+                    .unwrap();
+                reused = EmbeddingStore::from_bytes(&bytes).unwrap();
+            }
+
+            assert_eq!(reused.quantized(), store.quantized());
+        }
+    }
+
+    #[test]
+    fn quantized_only_vectors_cannot_change_storage() {
+        let quantized_only = quantized_store(Quantization::Int8, false).header;
+        let mut other = quantized_only.clone();
+
+        assert!(quantized_only.storage_mismatch(&other).is_none());
+        other.full_precision = true;
+        assert_eq!(
+            quantized_only.storage_mismatch(&other).unwrap(),
+            "only Int8 quantized vectors instead of Int8 quantized and full precision vectors"
+        );
+        other.quantization = Quantization::None;
+        assert_eq!(
+            quantized_only.storage_mismatch(&other).unwrap(),
+            "only Int8 quantized vectors instead of full precision vectors"
+        );
+        other.quantization = Quantization::Binary;
+        other.full_precision = false;
+        assert!(quantized_only.storage_mismatch(&other).is_some());
+        assert!(other.storage_mismatch(&IndexHeader::new("model", "OpenAI", 0, 0.0)).is_some());
+        assert!(quantized_store(Quantization::Int8, true)
+            .header
+            .storage_mismatch(&quantized_only)
+            .is_none());
+    }
+
+    #[test]
+    fn quantized_only_is_smaller() {
+        let full = quantized_store(Quantization::Int8, true).to_bytes().unwrap();
+        let quantized = quantized_store(Quantization::Int8, false).to_bytes().unwrap();
+
+        assert!(quantized.len() < full.len());
+    }
+
+    #[test]
+    fn quantized_vectors_are_normalized() {
+        let store = quantized_store(Quantization::Int8, true);
+
+        assert_eq!(*store.embedding(0), [0.6, 0.8, 0.0]);
+        let dequantized = quantized_store(Quantization::Int8, false).embedding(0).into_owned();
+        assert!((dequantized[0] - 0.6).abs() < 0.01);
+        assert!((dequantized[1] - 0.8).abs() < 0.01);
+    }
+
+    #[test]
+    fn unquantized_always_keeps_full_precision() {
+        let store = quantized_store(Quantization::None, false);
+
+        assert!(store.header.full_precision);
+        assert!(store.quantized().is_none());
+    }
+
+    #[test]
+    fn empty_roundtrip() {
+        let header = IndexHeader::new("model", "OpenAI", 0, 0.0);
+        let store = EmbeddingStore::from_rows(header, Vec::new()).unwrap();
+
+        let decoded = EmbeddingStore::from_bytes(&store.to_bytes().unwrap()).unwrap();
+
+        assert!(decoded.is_empty());
+        assert!(decoded.into_rows().is_empty());
+    }
+
+    #[test]
+    fn rejects_mismatched_dimensions() {
+        let header = IndexHeader::new("model", "OpenAI", 0, 0.0);
+        let rows = vec![row("a", vec![1.0, 2.0]), row("b", vec![1.0])];
+
+        assert!(EmbeddingStore::from_rows(header, rows).is_err());
+    }
+
+    #[test]
+    fn rejects_bad_magic() {
+        let mut bytes = store().to_bytes().unwrap();
+        bytes[0] = b'X';
+
+        assert!(EmbeddingStore::from_bytes(&bytes).is_err());
+    }
+
+    #[test]
+    fn rejects_future_version() {
+        let mut bytes = store().to_bytes().unwrap();
+        bytes[4..8].copy_from_slice(&(INDEX_FORMAT_VERSION + 1).to_le_bytes());
+
+        assert!(EmbeddingStore::from_bytes(&bytes).is_err());
+    }
+
+    #[test]
+    fn rejects_truncated_matrix() {
+        let bytes = store().to_bytes().unwrap();
+
+        assert!(EmbeddingStore::from_bytes(&bytes[..bytes.len() - 1]).is_err());
+    }
+
+    #[test]
+    fn rejects_overflowing_dimension() {
+        let mut store = store();
+        store.header.dimension = usize::MAX;
+        let bytes = store.to_bytes().unwrap();
+
+        assert!(EmbeddingStore::from_bytes(&bytes).is_err());
+    }
+}
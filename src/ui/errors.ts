@@ -0,0 +1,18 @@
+// Errors thrown by the plugin's wasm module carry a stable `code` and, for
+// most codes, a `hint` on how to fix them.
+export interface SemanticSearchError extends Error {
+  code: string;
+  hint?: string;
+}
+
+export function isSemanticSearchError(error: unknown): error is SemanticSearchError {
+  return error instanceof Error && typeof (error as SemanticSearchError).code === "string";
+}
+
+// The message of `error` followed by its hint, for showing in a notice.
+export function describeError(error: unknown): string {
+  if (!isSemanticSearchError(error)) {
+    return `${error}`;
+  }
+  return error.hint ? `${error.message}. ${error.hint}.` : error.message;
+}
@@ -1,16 +1,19 @@
 import { App, Modal, Notice } from "obsidian";
 import { semanticSearchSettings } from "src/settings/settings.js";
+import { describeError, isSemanticSearchError } from "./errors";
+import { ProgressNotice } from "./progress";
 
 import * as plugin from "../../pkg/obsidian_rust_plugin.js";
 
 export class GenerateEmbeddingsModal extends Modal {
   wasmGenerateEmbeddingsCommand : plugin.GenerateEmbeddingsCommand;
   settings: semanticSearchSettings;
+  index: plugin.EmbeddingIndex;
 
-  constructor(app: App, settings: semanticSearchSettings) {
+  constructor(app: App, settings: semanticSearchSettings, index: plugin.EmbeddingIndex) {
     super(app);
-    this.wasmGenerateEmbeddingsCommand = new plugin.GenerateEmbeddingsCommand(app, settings);
 	this.settings = settings;
+	this.index = index;
   }
 
   async onOpen() {
@@ -19,40 +22,60 @@ export class GenerateEmbeddingsModal extends Modal {
      const exists_container = contentEl.createDiv();
      const estimate_text = estimate_container.createDiv();
      const nfiles_text = estimate_container.createDiv();
-     estimate_text.setText("Processing estimated cost of query: ...");
+     estimate_text.setText("Reading notes to estimate the cost of query: ...");
 
      try {
+       // throws if the settings select an unsupported API
+       this.wasmGenerateEmbeddingsCommand = new plugin.GenerateEmbeddingsCommand(this.app, this.settings);
        const exists = await this.wasmGenerateEmbeddingsCommand.check_embedding_file_exists();
 
-	   const { nfiles } = await this.wasmGenerateEmbeddingsCommand.get_input_n_modified();
-	   if (nfiles == 0) {
-		   nfiles_text.setText(`Detected 0 files that are new or modified.`)
-		   exists_container.createDiv({text: "Make sure to run 'Generate Input' after modifications.", cls: "ss-exists-text"})
+	   const { nfiles, added, updated, removed, moved, resumed } = await this.wasmGenerateEmbeddingsCommand.get_input_n_modified();
+	   if (resumed > 0) {
+		   exists_container.createDiv({text: `Resuming an unfinished run: ${resumed} section(s) are already embedded.`, cls: "ss-exists-text"})
+	   }
+	   if (nfiles == 0 && removed == 0 && moved == 0 && resumed == 0) {
+		   nfiles_text.setText(`Detected 0 sections that are new or modified.`)
 	   } else {
-		   if (this.settings.costEstimation) {
+		   if (this.settings.costEstimation && nfiles != 0) {
 			   const { cost } = await this.wasmGenerateEmbeddingsCommand.get_input_cost_estimate();
 			   estimate_text.setText(`Estimated cost of query: ${cost}`);
 		   }
-		   nfiles_text.setText(`Detected ${nfiles == -1 ? 'all' : nfiles} file(s) that are new or modified`)
+		   const { seconds } = await this.wasmGenerateEmbeddingsCommand.get_input_time_estimate();
+		   if (seconds >= 1) {
+			   exists_container.createDiv({text: `The rate limits will make this take about ${Math.ceil(seconds / 60)} minute(s).`, cls: "ss-exists-text"})
+		   }
+		   if (nfiles == -1) {
+			   nfiles_text.setText(`Detected ${added} section(s) to embed`)
+		   } else {
+			   nfiles_text.setText(`Detected ${added} new, ${updated} modified, ${removed} deleted and ${moved} moved section(s)`)
+		   }
 		   const confirm_button = contentEl.createEl("button", {text: "Generate Embeddings"})
 		   confirm_button.onclick = async () => {
 			   this.close();
+			   const progress = new ProgressNotice("Generating embeddings", this.settings.costEstimation);
 			   try {
-				   await this.wasmGenerateEmbeddingsCommand.get_embeddings();
-				   new Notice("Successfully generated embeddings in 'embedding.csv'");
+				   await this.wasmGenerateEmbeddingsCommand.get_embeddings(progress.update, progress.signal);
+				   this.index.invalidate();
+				   new Notice("Successfully generated embeddings in 'embedding.bin'");
 			   } catch (error) {
-				   console.error(error);
-				   new Notice(`Failed to create embeddings. Error: ${error}`);
+				   if (isSemanticSearchError(error) && error.code === "aborted") {
+					   new Notice(`Stopped generating embeddings. ${describeError(error)}`);
+				   } else {
+					   console.error(error);
+					   new Notice(`Failed to create embeddings, completed batches will be reused on the next run. Error: ${describeError(error)}`);
+				   }
+			   } finally {
+				   progress.hide();
 			   }
 		   }
 	   }
        if (exists) {
-         exists_container.createDiv({text: "Warning: the file 'embedding.csv' already exists.", cls: "ss-exists-text"})
+         exists_container.createDiv({text: "Warning: an embedding index already exists.", cls: "ss-exists-text"})
        }
      } catch (error) {
        this.close();
        console.error(error)
-	   new Notice(`Failed to create embeddings. Error: ${error}`);
+	   new Notice(`Failed to create embeddings. Error: ${describeError(error)}`);
      }
   }
 
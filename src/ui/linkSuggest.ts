@@ -7,12 +7,14 @@ import * as plugin from "../../pkg/obsidian_rust_plugin.js";
 export class LinkSuggest extends EditorSuggest<Suggestion> {
     app: App;
     settings: semanticSearchSettings;
+    index: plugin.EmbeddingIndex;
     debouncer: Debouncer<[EditorSuggestContext, (suggestions: Suggestion[]) => void], void> | undefined;
 
-    constructor(app: App, settings: semanticSearchSettings) {
+    constructor(app: App, settings: semanticSearchSettings, index: plugin.EmbeddingIndex) {
       super(app);
       this.app = app;
       this.settings = settings;
+      this.index = index;
     }
 
     onTrigger(cursor: EditorPosition, editor: Editor, file: TFile): EditorSuggestTriggerInfo | null {
@@ -55,7 +57,7 @@ export class LinkSuggest extends EditorSuggest<Suggestion> {
           return []
         }
 
-        const wasmSuggestions: WASMSuggestion[] = await plugin.get_suggestions(this.app, this.settings, query);
+        const wasmSuggestions: WASMSuggestion[] = await this.index.get_suggestions(query);
         const suggestions: Suggestion[] = wasmSuggestions.map(wasmSuggestion => new Suggestion(this.app, wasmSuggestion, this.settings.sectionDelimeterRegex));
 
         suggestions.forEach(suggestion => {
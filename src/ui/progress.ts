@@ -0,0 +1,65 @@
+import { Notice } from "obsidian";
+
+// Progress reported by 'Export Input' and 'Generate Embeddings'
+export interface Progress {
+  phase: "reading_notes" | "writing_input" | "embedding" | "saving" | "done";
+  files_processed: number;
+  files_total: number;
+  sections_embedded: number;
+  sections_total: number;
+  batches_remaining: number;
+  estimated_cost: number;
+  rate_limit_wait_seconds: number;
+}
+
+export function describeProgress(progress: Progress, showCost: boolean): string {
+  switch (progress.phase) {
+    case "reading_notes":
+      return `Read ${progress.files_processed} of ${progress.files_total} note(s)`;
+    case "writing_input":
+      return "Writing 'input.csv'";
+    case "embedding": {
+      let embedded = `Embedded ${progress.sections_embedded} of ${progress.sections_total} section(s), ${progress.batches_remaining} batch(es) remaining`;
+      if (showCost) {
+        embedded += `, about $${progress.estimated_cost.toFixed(4)} so far`;
+      }
+      if (progress.rate_limit_wait_seconds > 0) {
+        embedded += `, waiting ${progress.rate_limit_wait_seconds} s for the rate limits`;
+      }
+      return embedded;
+    }
+    case "saving":
+      return "Saving 'embedding.bin'";
+    case "done":
+      return "Done";
+  }
+}
+
+// A notice that stays open while a command runs, showing its progress, and
+// stops the command when clicked.
+export class ProgressNotice {
+  private notice: Notice;
+  private controller = new AbortController();
+
+  constructor(private title: string, private showCost = false) {
+    this.notice = new Notice(`${title}...`, 0);
+    this.notice.noticeEl.addEventListener("click", () => {
+      this.controller.abort();
+      new Notice(`Stopping: ${title.toLowerCase()}`);
+    });
+  }
+
+  get signal(): AbortSignal {
+    return this.controller.signal;
+  }
+
+  update = (progress: Progress) => {
+    if (!this.signal.aborted) {
+      this.notice.setMessage(`${this.title}: ${describeProgress(progress, this.showCost)}. Click to stop.`);
+    }
+  };
+
+  hide() {
+    this.notice.hide();
+  }
+}
@@ -1,18 +1,21 @@
 import { App, Editor, Modal, normalizePath, Notice, OpenViewState, PaneType, renderResults, SearchResult, setIcon, SplitDirection, TFile, WorkspaceLeaf } from "obsidian";
 import { semanticSearchSettings } from "src/settings/settings";
+import { describeError } from "./errors";
 import { Suggestion, WASMSuggestion } from "./suggestion";
 
 import * as plugin from "../../pkg/obsidian_rust_plugin.js";
 
 export class QueryModal extends Modal {
   settings: semanticSearchSettings;
+  index: plugin.EmbeddingIndex;
   estimatedCost = 0;
   timerId: number;
   delay = 200;
 
-  constructor(app: App, settings: semanticSearchSettings) {
+  constructor(app: App, settings: semanticSearchSettings, index: plugin.EmbeddingIndex) {
     super(app);
     this.settings = settings;
+    this.index = index;
   }
 
   onOpen(): void {
@@ -68,7 +71,14 @@ export class QueryModal extends Modal {
 
   // Returns all available suggestions.
   async getSuggestions(query: string): Promise<Suggestion[]> {
-    const wasmSuggestions: WASMSuggestion[] = await plugin.get_suggestions(this.app, this.settings, query);
+    let wasmSuggestions: WASMSuggestion[];
+    try {
+      wasmSuggestions = await this.index.get_suggestions(query);
+    } catch (error) {
+      console.error(error);
+      new Notice(`Search failed. Error: ${describeError(error)}`);
+      return [];
+    }
     const suggestions: Suggestion[] = wasmSuggestions.map(wasmSuggestion => new Suggestion(this.app, wasmSuggestion, this.settings.sectionDelimeterRegex));
 
     suggestions.forEach(async suggestion => {
@@ -194,8 +204,8 @@ export class QueryModal extends Modal {
 export class LinkSuggestQueryModal extends QueryModal {
   editor: Editor;
 
-  constructor(app: App, settings: semanticSearchSettings, editor: Editor) {
-    super(app, settings);
+  constructor(app: App, settings: semanticSearchSettings, index: plugin.EmbeddingIndex, editor: Editor) {
+    super(app, settings, index);
     this.editor = editor;
   }
 
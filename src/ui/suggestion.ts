@@ -3,6 +3,7 @@ import Fuse from 'fuse.js';
 
 export type WASMSuggestion = {
   name: string
+  path: string
   header: string
 }
 
@@ -15,6 +16,7 @@ type Section = {
 export class Suggestion {
   app: App;
   name: string;
+  path: string;
   header: string;
   pos: Pos | undefined;
   file: TFile | undefined;
@@ -24,12 +26,19 @@ export class Suggestion {
   constructor(app: App, wasmSuggestion: WASMSuggestion, sectionDelimeterRegex: string) {
     this.app = app;
     this.name = wasmSuggestion.name;
+    this.path = wasmSuggestion.path;
     this.header = wasmSuggestion.header;
     this.sectionDelimeterRegex = sectionDelimeterRegex;
   }
 
   // Find corresponding suggestion file
   addSuggestionFile() : Suggestion {
+    const file = this.path ? this.app.vault.getAbstractFileByPath(this.path) : null;
+    if (file instanceof TFile) {
+      this.file = file;
+      return this;
+    }
+    // Embeddings migrated from older versions only record the file name
     const files = this.app.vault.getMarkdownFiles();
     const matching_file = files.find(file => file.name === this.name);
     this.file = matching_file;